@@ -0,0 +1,28 @@
+//! Picks the memory layout the firmware is linked with
+//!
+//! Plain builds own all of flash but the two settings sectors. With `OTA` the
+//! firmware runs from one of the two slots of `src/boot.rs`, slot A unless
+//! `SLOT_B` is set, and `BOOTLOADER` links `bin/bootloader` ahead of them.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let feature =
+        |name: &str| env::var_os(format!("CARGO_FEATURE_{name}")).is_some();
+    let layout = if feature("BOOTLOADER") {
+        "memory/bootloader.x"
+    } else if feature("SLOT_B") {
+        "memory/slot_b.x"
+    } else if feature("OTA") {
+        "memory/slot_a.x"
+    } else {
+        "memory/app.x"
+    };
+
+    // `link.x` includes `memory.x` from the linker search path
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy(layout, out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory");
+    println!("cargo:rerun-if-changed=build.rs");
+}
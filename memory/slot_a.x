@@ -1,7 +1,8 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* Slot A of `src/boot.rs`, the 128K sector 5 */
+  FLASH : ORIGIN = 0x08020000, LENGTH = 128K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
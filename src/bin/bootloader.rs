@@ -0,0 +1,56 @@
+//! Starts the firmware slot the boot record names
+//!
+//! Built with the `BOOTLOADER` feature it fits the first two sectors of flash
+//! and goes there once, next to an `OTA` image in slot A. A new image in the
+//! other slot gets one start; unless it confirms itself before the next
+//! reset, the slot in use is started again. The independent watchdog runs
+//! for that start, so a new image that hangs gets reset too. See
+//! `tunnel_firmware::boot`.
+
+#![no_main]
+#![no_std]
+
+use cortex_m_rt::entry;
+use stm32f4xx_hal::{pac, prelude::*, watchdog::IndependentWatchdog};
+use tunnel_firmware::boot::{self, BootRecord};
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
+    let mut flash = dp.FLASH;
+
+    let mut record = BootRecord::load(&flash);
+    let mut slot = record.select();
+    if !slot.is_bootable(&flash) {
+        // Nothing to run there, the other slot is all we have
+        slot = slot.other();
+        if !slot.is_bootable(&flash) {
+            // Neither slot holds an image, so wait for one to be flashed
+            loop {
+                cortex_m::asm::wfi();
+            }
+        }
+        record = BootRecord {
+            active: slot,
+            pending: None,
+            tried: false,
+        };
+    }
+    // Failing to write only means making the same choice on the next reset
+    record.save(&mut flash).ok();
+
+    // Once started the watchdog cannot be stopped, so the image has to feed
+    // it from then on, and a confirmed image starts without it on the next
+    // reset
+    if record.is_trying(slot) {
+        IndependentWatchdog::new(dp.IWDG).start(boot::WATCHDOG_PERIOD.millis());
+    }
+
+    // SAFETY: the slot holds a vector table linked for its address and
+    // nothing set up here outlives the jump
+    unsafe {
+        cp.SCB.vtor.write(slot.address() as u32);
+        cortex_m::asm::bootload(slot.address() as *const u32)
+    }
+}
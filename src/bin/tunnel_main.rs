@@ -12,16 +12,13 @@ mod app {
         pac,
         prelude::*,
         timer::{self, DelayUs},
+        watchdog::IndependentWatchdog,
     };
     use heapless::pool::singleton::Pool;
     use stm32f4xx_hal as hal;
     use usb_device::{bus::UsbBusAllocator, prelude::*};
 
-    #[cfg(feature = "FOLLOWER")]
-    use plc::Follower as PlcDriver;
-    #[cfg(feature = "LEADER")]
-    use plc::Leader as PlcDriver;
-    use tunnel_firmware::{dbg, mem, plc, st7580, usb, util};
+    use tunnel_firmware::{config, dbg, mem, plc, st7580, usb, util};
 
     #[shared]
     struct Shared {}
@@ -34,7 +31,8 @@ mod app {
         usb_manager: usb::UsbManager,
         st7580_interrupt_handler: st7580::InterruptHandler,
         delay: DelayUs<pac::TIM3>,
-        driver: PlcDriver<TWO_WAY>,
+        driver: plc::Node<TWO_WAY>,
+        watchdog: IndependentWatchdog,
     }
 
     #[monotonic(binds = TIM2, default = true)]
@@ -69,8 +67,16 @@ mod app {
         let mono = dp.TIM2.monotonic_us(&clocks);
 
         let gpioa = dp.GPIOA.split();
+        let gpiob = dp.GPIOB.split();
         let gpioc = dp.GPIOC.split();
 
+        // Tying the strapping pin low makes a follower unless the host
+        // stored a role, otherwise the node takes part in the election
+        let store = config::Store::new(dp.FLASH);
+        let strap = gpiob.pb12.into_pull_up_input();
+        let role = plc::role::select(&store, strap.is_low());
+        dbg::println!("role {:?}", role);
+
         mem::POOL::grow(stbuf);
 
         let (st7580_driver, st7580_dsender, st7580_interrupt_handler) =
@@ -86,6 +92,8 @@ mod app {
             }
             .split(&clocks);
         let delay = dp.TIM3.delay(&clocks);
+        // Started by the bootloader for an image on trial only
+        let watchdog = IndependentWatchdog::new(dp.IWDG);
 
         let usb = USB {
             usb_global: dp.OTG_FS_GLOBAL,
@@ -101,28 +109,34 @@ mod app {
 
         let usb::UsbSplit {
             usb_manager,
-            in_producer,
-            out_consumer,
-        } = usb::split(usb_bus);
+            in_producers,
+            out_consumers,
+            ctrl_in_producer,
+            ctrl_out_consumer,
+        } = usb::split(usb_bus, TWO_WAY || role != Some(plc::Role::Follower));
 
         let usb_device =
             UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x0000, 0x6969))
                 .manufacturer("TUNNEL Team")
-                .product(if cfg!(feature = "LEADER") {
-                    "TUNNEL Leader"
-                } else {
-                    "TUNNEL Follower"
-                })
+                .product(plc::role::product(role))
                 .serial_number("deadbeef")
+                // Composite device made of the CDC-ACM and control interfaces
+                .device_class(0xEF)
+                .device_sub_class(0x02)
+                .device_protocol(0x01)
                 .self_powered(true)
                 .build();
 
-        let driver = PlcDriver::new(
+        let parts = plc::role::Parts::new(
             st7580_driver,
             st7580_dsender,
-            in_producer,
-            out_consumer,
+            store,
+            in_producers,
+            out_consumers,
+            ctrl_in_producer,
+            ctrl_out_consumer,
         );
+        let driver = plc::Node::new(role, parts);
 
         plm::spawn().unwrap();
 
@@ -135,6 +149,7 @@ mod app {
                 st7580_interrupt_handler,
                 delay,
                 driver,
+                watchdog,
             },
             init::Monotonics(mono),
         )
@@ -154,7 +169,9 @@ mod app {
             usb_manager,
         } = ctx.local;
 
-        if !usb_device.poll(&mut [usb_manager.class()]) {
+        // Also woken by `plm` when the queues changed without a bus event
+        let event = usb_device.poll(&mut usb_manager.classes());
+        if !event && usb_device.state() != UsbDeviceState::Configured {
             return;
         }
 
@@ -166,6 +183,7 @@ mod app {
         local = [
             delay,
             driver,
+            watchdog,
             should_init: bool = true
         ]
     )]
@@ -173,6 +191,7 @@ mod app {
         let plm::LocalResources {
             delay,
             driver,
+            watchdog,
             should_init,
         } = ctx.local;
 
@@ -185,7 +204,12 @@ mod app {
             dbg::println!("plm init end");
         }
 
+        // Feeding a watchdog that was never started does nothing
+        watchdog.feed();
         driver.process();
+        if usb::take_wake() {
+            rtic::pend(pac::Interrupt::OTG_FS);
+        }
 
         plm::spawn().unwrap();
     }
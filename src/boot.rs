@@ -0,0 +1,245 @@
+//! Flash layout and boot record shared by the bootloader and the firmware
+//!
+//! Builds with the `OTA` feature leave the first two sectors of flash to
+//! `bin/bootloader` and run from one of two slots, each a sector of its own,
+//! while the settings take turns between sector 3 and the last sector. The
+//! boot record, alone in sector 2, names the slot holding the firmware in use
+//! and the slot holding a new image, if any. The bootloader starts a new
+//! image once, on trial, and the image confirms itself once its link is back
+//! up. Should the node reboot before that, the bootloader goes back to the
+//! slot in use. The bootloader starts the independent watchdog for an image
+//! on trial, so one that hangs resets the node as well.
+
+use crate::util;
+use stm32f4xx_hal::{
+    flash::{self, FlashExt},
+    pac,
+};
+
+/// Start of flash in the address space
+const FLASH_BASE: usize = 0x0800_0000;
+/// Sector holding the boot record
+const RECORD_SECTOR: u8 = 2;
+/// Offset of `RECORD_SECTOR` from the start of flash
+const RECORD_OFFSET: usize = 0x0_8000;
+/// RAM an image's initial stack pointer may point into
+const RAM: core::ops::RangeInclusive<u32> = 0x2000_0000..=0x2002_0000;
+
+const MAGIC: [u8; 4] = *b"BOOT";
+const RECORD_LEN: usize = MAGIC.len() + 3 + 4;
+/// Value of an unset slot in the record
+const NO_SLOT: u8 = 0xFF;
+/// Time a new image on trial may go without feeding the independent
+/// watchdog in milliseconds, longer than any stall of a sector erase
+pub const WATCHDOG_PERIOD: u32 = 8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A = 0x00,
+    B = 0x01,
+}
+
+impl From<Slot> for u8 {
+    fn from(val: Slot) -> Self {
+        val as u8
+    }
+}
+
+impl TryFrom<u8> for Slot {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x00 => Ok(Slot::A),
+            0x01 => Ok(Slot::B),
+            v => Err(v),
+        }
+    }
+}
+
+impl Slot {
+    /// Longest image a slot holds
+    pub const SIZE: usize = 128 * 1024;
+
+    /// Slot this firmware was linked to run from
+    pub const RUNNING: Self = if cfg!(feature = "SLOT_B") {
+        Slot::B
+    } else {
+        Slot::A
+    };
+
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn sector(self) -> u8 {
+        match self {
+            Slot::A => 5,
+            Slot::B => 6,
+        }
+    }
+
+    /// Offset of the slot from the start of flash
+    fn offset(self) -> usize {
+        match self {
+            Slot::A => 0x2_0000,
+            Slot::B => 0x4_0000,
+        }
+    }
+
+    /// Address of the vector table of the image in the slot
+    pub fn address(self) -> usize {
+        FLASH_BASE + self.offset()
+    }
+
+    /// Contents of the slot
+    pub fn image(self, flash: &pac::FLASH) -> &[u8] {
+        &flash.read()[self.offset()..][..Self::SIZE]
+    }
+
+    /// Whether the slot starts with a vector table whose stack lies in RAM
+    /// and whose reset handler lies in the slot, which also turns away images
+    /// linked for the other slot
+    pub fn is_bootable(self, flash: &pac::FLASH) -> bool {
+        let image = self.image(flash);
+        let word = |idx: usize| {
+            u32::from_le_bytes(image[idx..idx + 4].try_into().unwrap())
+        };
+        let reset = (word(4) & !1) as usize;
+        RAM.contains(&word(0))
+            && (self.address()..self.address() + Self::SIZE).contains(&reset)
+    }
+
+    /// Erases the slot. This stalls the core for as long as the sector erase
+    /// takes, which can be over a second.
+    pub fn erase(self, flash: &mut pac::FLASH) -> Result<(), flash::Error> {
+        flash.unlocked().erase(self.sector())
+    }
+
+    /// Writes `data` at `offset` into the erased slot
+    pub fn program(
+        self,
+        flash: &mut pac::FLASH,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), flash::Error> {
+        flash
+            .unlocked()
+            .program(self.offset() + offset, data.iter())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootRecord {
+    /// Slot holding the firmware in use
+    pub active: Slot,
+    /// Slot holding a new image to try
+    pub pending: Option<Slot>,
+    /// Whether the new image was already started once
+    pub tried: bool,
+}
+
+impl Default for BootRecord {
+    fn default() -> Self {
+        Self {
+            active: Slot::A,
+            pending: None,
+            tried: false,
+        }
+    }
+}
+
+impl BootRecord {
+    /// Reads the record, falling back to booting slot A when flash holds no
+    /// valid record
+    pub fn load(flash: &pac::FLASH) -> Self {
+        Self::decode(&flash.read()[RECORD_OFFSET..]).unwrap_or_default()
+    }
+
+    /// Replaces the record. This stalls the core for as long as the sector
+    /// erase takes.
+    pub fn save(&self, flash: &mut pac::FLASH) -> Result<(), flash::Error> {
+        if Self::load(flash) == *self {
+            return Ok(());
+        }
+        let mut unlocked = flash.unlocked();
+        unlocked.erase(RECORD_SECTOR)?;
+        unlocked.program(RECORD_OFFSET, self.encode().iter())
+    }
+
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0; RECORD_LEN];
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        buf[4] = self.active.into();
+        buf[5] = self.pending.map_or(NO_SLOT, u8::from);
+        buf[6] = self.tried.into();
+        let crc = util::crc32(&buf[..7]);
+        buf[7..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..RECORD_LEN)?;
+        let (record, crc) = data.split_at(7);
+        if record[..MAGIC.len()] != MAGIC
+            || crc != util::crc32(record).to_le_bytes()
+        {
+            return None;
+        }
+        Some(Self {
+            active: record[4].try_into().ok()?,
+            pending: match record[5] {
+                NO_SLOT => None,
+                slot => Some(slot.try_into().ok()?),
+            },
+            tried: record[6] != 0,
+        })
+    }
+
+    /// Picks the slot to start, marking a new image as tried and giving up
+    /// on one that was tried without confirming itself
+    pub fn select(&mut self) -> Slot {
+        match self.pending {
+            Some(slot) if !self.tried => {
+                self.tried = true;
+                slot
+            }
+            Some(_) => {
+                self.pending = None;
+                self.tried = false;
+                self.active
+            }
+            None => self.active,
+        }
+    }
+
+    /// Whether the running image is a new one on trial
+    pub fn is_trial(&self) -> bool {
+        self.is_trying(Slot::RUNNING)
+    }
+
+    /// Whether the image in `slot` is the new one on trial
+    pub fn is_trying(&self, slot: Slot) -> bool {
+        self.tried && self.pending == Some(slot)
+    }
+
+    /// Keeps the running image for good
+    pub fn confirm(&mut self) {
+        if self.is_trial() {
+            *self = Self {
+                active: Slot::RUNNING,
+                pending: None,
+                tried: false,
+            };
+        }
+    }
+
+    /// Has the next boot try the image in `slot`
+    pub fn install(&mut self, slot: Slot) {
+        self.pending = Some(slot);
+        self.tried = false;
+    }
+}
@@ -0,0 +1,280 @@
+//! Settings kept in flash across reboots
+//!
+//! The settings take turns between two sectors that the layouts in `memory/`
+//! keep out of reach of the linker: the last one and a spare one. Writing the
+//! settings erases the sector not in use, so a write cut short by a power
+//! loss leaves the last record standing. The record is a magic word, the
+//! length of the fields, the fields themselves and an additive checksum over
+//! them. Fields are only ever appended so that older records still load, with
+//! missing fields taking their default.
+//!
+//! Encryption needs an epoch that no boot ever reuses, and erasing a sector
+//! on every boot to count them would stall the core and wear the flash. The
+//! record holds a base epoch instead, and each epoch claimed since clears one
+//! more byte of `MARKS_LEN` behind it, which flash allows without an erase.
+//! Writing the record folds the marks into the base and moves one past it,
+//! so the newer of the two records is always the one with the higher epoch.
+
+use stm32f4xx_hal::{
+    flash::{self, FlashExt},
+    pac,
+};
+
+/// A sector that can hold the settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bank {
+    sector: u8,
+    /// Offset of `sector` from the start of flash
+    offset: usize,
+}
+
+/// The last sector, where the settings have always been
+const LAST: Bank = Bank {
+    sector: 7,
+    offset: 0x6_0000,
+};
+
+/// The 16K sector 3, which the `OTA` layouts leave unused
+const OTA_SPARE: Bank = Bank {
+    sector: 3,
+    offset: 0x0_C000,
+};
+
+/// The 128K sector 6, past the end of the plain layout
+const PLAIN_SPARE: Bank = Bank {
+    sector: 6,
+    offset: 0x4_0000,
+};
+
+/// The sector taking turns with `LAST`
+#[cfg(feature = "OTA")]
+const SPARE: Bank = OTA_SPARE;
+#[cfg(not(feature = "OTA"))]
+const SPARE: Bank = PLAIN_SPARE;
+
+/// Sectors a record is looked for in, including the spare of the other
+/// layout, since its record may hold the highest epoch of a node that was
+/// moved from one layout to the other
+const BANKS: [Bank; 3] = [LAST, OTA_SPARE, PLAIN_SPARE];
+
+const MAGIC: [u8; 4] = *b"TNL1";
+const HEADER_LEN: usize = MAGIC.len() + 1;
+const FIELDS_LEN: usize = 57;
+
+/// Offset of the epoch marks from the start of a bank, past the record
+const MARKS_OFFSET: usize = 0x100;
+/// Epochs claimed before the record has to be written again
+const MARKS_LEN: usize = 4096;
+/// Value of a mark that was not cleared, as left by an erase
+const MARK_FREE: u8 = 0xFF;
+
+/// Length of the pre-shared key for payload encryption
+pub const KEY_LEN: usize = 32;
+/// Length of the modem settings, the PHY configuration followed by the modem
+/// configuration
+pub const MODEM_LEN: usize = 15;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Network the node is paired into, zero when unpaired
+    pub network: u16,
+    /// Address of the paired peer
+    pub peer: u16,
+    /// Last encryption epoch claimed, keeps nonces unique across reboots.
+    /// Only ever advanced by the store, whatever is saved.
+    pub epoch: u32,
+    /// Pre-shared key for payload encryption, all zeros when unset
+    pub key: [u8; KEY_LEN],
+    /// Role picked by the host, `None` leaves it to the strapping pin
+    pub role: Option<u8>,
+    /// Modem settings confirmed over the link, `None` keeps the built-in ones
+    pub modem: Option<[u8; MODEM_LEN]>,
+}
+
+impl Config {
+    fn encode(&self) -> [u8; HEADER_LEN + FIELDS_LEN + 2] {
+        let mut buf = [0; HEADER_LEN + FIELDS_LEN + 2];
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        buf[MAGIC.len()] = FIELDS_LEN as u8;
+        let fields = &mut buf[HEADER_LEN..HEADER_LEN + FIELDS_LEN];
+        fields[0..2].copy_from_slice(&self.network.to_le_bytes());
+        fields[2..4].copy_from_slice(&self.peer.to_le_bytes());
+        fields[4..8].copy_from_slice(&self.epoch.to_le_bytes());
+        fields[8..40].copy_from_slice(&self.key);
+        // Zero is what records from before the field read as
+        fields[40] = self.role.map_or(0, |role| role + 1);
+        if let Some(modem) = &self.modem {
+            fields[41] = 1;
+            fields[42..57].copy_from_slice(modem);
+        }
+        let checksum = checksum(fields);
+        buf[HEADER_LEN + FIELDS_LEN..].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.get(..MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let len = *data.get(MAGIC.len())? as usize;
+        let fields = data.get(HEADER_LEN..HEADER_LEN + len)?;
+        let stored = data.get(HEADER_LEN + len..HEADER_LEN + len + 2)?;
+        if checksum(fields).to_le_bytes() != stored {
+            return None;
+        }
+
+        fn field<const N: usize>(fields: &[u8], at: usize) -> [u8; N] {
+            fields
+                .get(at..at + N)
+                .map_or([0; N], |f| f.try_into().unwrap())
+        }
+        Some(Self {
+            network: u16::from_le_bytes(field(fields, 0)),
+            peer: u16::from_le_bytes(field(fields, 2)),
+            epoch: u32::from_le_bytes(field(fields, 4)),
+            key: field(fields, 8),
+            role: field::<1>(fields, 40)[0].checked_sub(1),
+            modem: (field::<1>(fields, 41)[0] == 1).then(|| field(fields, 42)),
+        })
+    }
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    data.iter()
+        .fold(0, |acc: u16, &val| acc.wrapping_add(val.into()))
+}
+
+pub struct Store {
+    flash: pac::FLASH,
+    /// Settings waiting for `flush` to be written
+    staged: Option<Config>,
+}
+
+impl Store {
+    pub fn new(flash: pac::FLASH) -> Self {
+        Self {
+            flash,
+            staged: None,
+        }
+    }
+
+    /// Flash behind the store, also holding the boot record and the firmware
+    /// slots of `boot`
+    pub fn flash(&mut self) -> &mut pac::FLASH {
+        &mut self.flash
+    }
+
+    /// Reads the settings, staged ones included, falling back to the defaults
+    /// when flash holds no valid record
+    pub fn load(&self) -> Config {
+        self.staged.unwrap_or_else(|| self.stored())
+    }
+
+    fn stored(&self) -> Config {
+        self.current().map(|(_, config)| config).unwrap_or_default()
+    }
+
+    /// The bank holding the newest valid record, along with that record and
+    /// the epochs marked behind it
+    fn current(&self) -> Option<(Bank, Config)> {
+        BANKS
+            .into_iter()
+            .filter_map(|bank| {
+                let data = &self.flash.read()[bank.offset..];
+                let mut config = Config::decode(data)?;
+                config.epoch =
+                    config.epoch.wrapping_add(self.marks(bank) as u32);
+                Some((bank, config))
+            })
+            .max_by_key(|(_, config)| config.epoch)
+    }
+
+    /// Number of epochs claimed since the record in `bank` was written,
+    /// counting a mark whose programming was cut short as claimed
+    fn marks(&self, bank: Bank) -> usize {
+        let at = bank.offset + MARKS_OFFSET;
+        self.flash.read()[at..at + MARKS_LEN]
+            .iter()
+            .take_while(|&&mark| mark != MARK_FREE)
+            .count()
+    }
+
+    /// Replaces the stored settings, leaving their epoch to the store. This
+    /// stalls the core for as long as the sector erase takes, which can be
+    /// over a second.
+    pub fn save(&mut self, config: &Config) -> Result<(), flash::Error> {
+        self.staged = None;
+        let stored = self.stored();
+        let config = Config {
+            epoch: stored.epoch,
+            ..*config
+        };
+        if stored == config {
+            return Ok(());
+        }
+        self.write(&config)?;
+        Ok(())
+    }
+
+    /// Writes `config` to the bank not in use, with an epoch past the stored
+    /// one, and returns that bank
+    fn write(&mut self, config: &Config) -> Result<Bank, flash::Error> {
+        let current = self.current();
+        let bank = match current {
+            Some((LAST, _)) => SPARE,
+            _ => LAST,
+        };
+        let epoch = current.map_or(0, |(_, stored)| stored.epoch);
+        let config = Config {
+            epoch: epoch.wrapping_add(1),
+            ..*config
+        };
+        let mut unlocked = self.flash.unlocked();
+        unlocked.erase(bank.sector)?;
+        unlocked.program(bank.offset, config.encode().iter())?;
+        Ok(bank)
+    }
+
+    /// Claims an epoch that is never handed out again, even across reboots.
+    /// This only clears a byte, save for every `MARKS_LEN` claims when the
+    /// record is written again along with any staged settings.
+    pub fn claim_epoch(&mut self) -> Result<u32, flash::Error> {
+        // The other layout's spare may belong to the firmware now, so a
+        // record found there is moved before any mark goes behind it
+        let bank = match self.current() {
+            Some((bank, _))
+                if [LAST, SPARE].contains(&bank)
+                    && self.marks(bank) < MARKS_LEN =>
+            {
+                bank
+            }
+            _ => {
+                let config = self.load();
+                self.staged = None;
+                self.write(&config)?
+            }
+        };
+        let at = bank.offset + MARKS_OFFSET + self.marks(bank);
+        self.flash.unlocked().program(at, [0].iter())?;
+        Ok(self.stored().epoch)
+    }
+
+    /// Replaces the settings without writing them yet, for callers that
+    /// cannot afford the stall of `save`
+    pub fn stage(&mut self, config: Config) {
+        let stored = self.stored();
+        let config = Config {
+            epoch: stored.epoch,
+            ..config
+        };
+        self.staged = (stored != config).then_some(config);
+    }
+
+    /// Writes the staged settings, if any, with the stall of `save`
+    pub fn flush(&mut self) -> Result<(), flash::Error> {
+        match self.staged {
+            Some(config) => self.save(&config),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 #[cfg(feature = "HALT")]
 pub use panic_halt as _;
@@ -7,10 +7,13 @@ pub use panic_probe as _;
 #[cfg(feature = "QEMU")]
 pub use panic_semihosting as _;
 
+pub mod boot;
+pub mod config;
 pub mod dbg;
 pub mod mem;
 pub mod plc;
 pub mod st7580;
+pub mod stats;
 pub mod usb;
 pub mod util;
 
@@ -18,3 +21,10 @@ pub mod util;
 pub const CLOCK_SPEED: u32 = 144;
 #[cfg(feature = "F411")]
 pub const CLOCK_SPEED: u32 = 96;
+
+/// Firmware version as `[major, minor, patch]`
+pub const FIRMWARE_VERSION: [u8; 3] = [
+    util::parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+    util::parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+    util::parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+];
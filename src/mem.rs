@@ -16,7 +16,11 @@ pub fn grow(memory: &'static mut [u8]) -> usize {
 
 #[inline(always)]
 pub fn alloc_init(buf: VecBuf) -> Option<BufBox> {
-    POOL::alloc().map(|b| b.init(buf))
+    let Some(b) = POOL::alloc() else {
+        crate::stats::STATS.pool_exhausted.inc();
+        return None;
+    };
+    Some(b.init(buf))
 }
 
 pub fn alloc_from_slice(s: &[u8]) -> Option<BufBox> {
@@ -27,3 +31,12 @@ pub fn alloc_from_slice(s: &[u8]) -> Option<BufBox> {
 pub fn alloc() -> Option<BufBox> {
     alloc_init(VecBuf::new())
 }
+
+/// Gives the pool memory for host tests, once however many tests ask
+#[cfg(test)]
+pub fn grow_for_tests() {
+    static GROWN: std::sync::Once = std::sync::Once::new();
+    GROWN.call_once(|| {
+        grow(std::vec![0; 1 << 14].leak());
+    });
+}
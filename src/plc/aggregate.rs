@@ -0,0 +1,69 @@
+//! Packing of several host messages into one frame
+//!
+//! Every frame costs a modem handshake and a powerline preamble however small
+//! it is, so the messages waiting for the peer are sent together, as many as
+//! fit. The payload of a data frame is a run of records, each a length byte
+//! followed by one message:
+//!
+//! `[len: 1][message: len][len: 1][message: len]..`
+//!
+//! The aggregate as a whole then goes through the `pipeline`.
+
+use crate::{mem, usb};
+
+/// Bytes added ahead of every message
+pub const RECORD_OVERHEAD: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateError {
+    /// A record runs past the end of the payload
+    Truncated,
+}
+
+/// Takes the next message waiting for the peer, along with as many of those
+/// queued behind it as fit in `max` bytes of records, up to `limit` messages.
+/// Returns the records and how many messages they hold.
+pub(super) fn collect(
+    queue: &mut usb::UsbConsumer,
+    max: usize,
+    limit: usize,
+) -> Option<(mem::BufBox, usize)> {
+    if limit == 0 {
+        return None;
+    }
+    let mut buf = queue.dequeue()?;
+    let len = buf.len() as u8;
+    if buf.push(len).is_err() {
+        crate::dbg::println!("message too long to aggregate, dropped");
+        return None;
+    }
+    buf.rotate_right(RECORD_OVERHEAD);
+
+    let mut count = 1;
+    while let Some(next) = queue.peek() {
+        if count == limit || buf.len() + RECORD_OVERHEAD + next.len() > max {
+            break;
+        }
+        let next = queue.dequeue().unwrap();
+        buf.push(next.len() as u8).unwrap();
+        buf.extend_from_slice(&next).unwrap();
+        count += 1;
+    }
+    Some((buf, count))
+}
+
+/// Walks the messages of a received aggregate
+pub(super) fn split(
+    payload: &[u8],
+) -> impl Iterator<Item = Result<&[u8], AggregateError>> {
+    let mut rest = payload;
+    core::iter::from_fn(move || {
+        let (&len, tail) = rest.split_first()?;
+        let Some(message) = tail.get(..len as usize) else {
+            rest = &[];
+            return Some(Err(AggregateError::Truncated));
+        };
+        rest = &tail[len as usize..];
+        Some(Ok(message))
+    })
+}
@@ -0,0 +1,536 @@
+//! Synthetic traffic for measuring the link
+//!
+//! Started from the leader's host, a benchmark sends `count` frames with
+//! `size` bytes of a pattern, each stamped with its sequence number and the
+//! time it left, on the reserved `CHANNEL`. In echo mode the follower sends
+//! every frame back and the leader times the round trips. In sink mode the
+//! follower keeps the frames, times their way over in the leader's time base
+//! from `time`, and reports once the leader ends the run. Benchmark frames go
+//! out as fast as the schedule lets data through, with host data held back
+//! meanwhile, and the same settings always make the same frames, so runs
+//! under different modem settings compare. An echo run leaves no more frames
+//! unechoed than the follower can queue echoes for, taking them as lost once
+//! none came back for `DRAIN_TIME`. Echoes cut down to fit the follower's
+//! frames say so, and the leader counts them.
+
+use super::time;
+use crate::{mem, st7580, util};
+
+/// Channel of the link header marking a benchmark frame
+pub const CHANNEL: u8 = 0xFE;
+/// Most frames in one run
+pub const MAX_COUNT: usize = 256;
+/// Time to wait for the last echoes or the report in milliseconds
+const DRAIN_TIME: u32 = 3000;
+/// Bytes of a data or echo frame ahead of the pattern
+const DATA_HEADER: usize = 9;
+/// Length of the sink's report
+const REPORT_LEN: usize = 1 + 2 + 4 + 4 + 16;
+/// Echoes that can wait to go back at once
+const ECHO_QUEUE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The follower sends every frame back
+    Echo = 0x00,
+    /// The follower keeps the frames and reports at the end
+    Sink = 0x01,
+}
+
+impl TryFrom<u8> for Mode {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x00 => Ok(Mode::Echo),
+            0x01 => Ok(Mode::Sink),
+            v => Err(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Zeros = 0x00,
+    /// Bytes counting up from the sequence number
+    Counter = 0x01,
+    /// Bytes that do not compress, seeded by the sequence number
+    Random = 0x02,
+}
+
+impl TryFrom<u8> for Pattern {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x00 => Ok(Pattern::Zeros),
+            0x01 => Ok(Pattern::Counter),
+            0x02 => Ok(Pattern::Random),
+            v => Err(v),
+        }
+    }
+}
+
+impl Pattern {
+    fn fill(self, seq: u16, len: usize, buf: &mut mem::VecBuf) {
+        let mut rng = util::Rng::new(seq.into());
+        for idx in 0..len {
+            let val = match self {
+                Pattern::Zeros => 0,
+                Pattern::Counter => (idx as u8).wrapping_add(seq as u8),
+                Pattern::Random => rng.next_u32() as u8,
+            };
+            buf.push(val).unwrap();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub mode: Mode,
+    /// Pattern bytes per frame, cut down to what a frame holds
+    pub size: u8,
+    pub pattern: Pattern,
+    /// Frames in the run, at most `MAX_COUNT`
+    pub count: u16,
+}
+
+impl Settings {
+    /// Decodes `[mode, size, pattern, count]`
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let &[mode, size, pattern, c0, c1] = data else { return None };
+        let count = u16::from_le_bytes([c0, c1]);
+        if count == 0 || count as usize > MAX_COUNT {
+            return None;
+        }
+        Some(Self {
+            mode: mode.try_into().ok()?,
+            size,
+            pattern: pattern.try_into().ok()?,
+            count,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    /// `[mode, pattern, seq, stamp, pattern bytes]`
+    Data = 0x01,
+    /// The data frame sent back as it came, except that its mode byte is
+    /// `1` when the pattern had to be cut down
+    Echo = 0x02,
+    /// Ends a sink run
+    End = 0x03,
+    /// `[received, bytes, span, latency summary]` of a sink run
+    Report = 0x04,
+}
+
+impl From<Message> for u8 {
+    fn from(val: Message) -> Self {
+        val as u8
+    }
+}
+
+impl TryFrom<u8> for Message {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Message::*;
+        match v {
+            0x01 => Ok(Data),
+            0x02 => Ok(Echo),
+            0x03 => Ok(End),
+            0x04 => Ok(Report),
+            v => Err(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Sending,
+    /// A sink run waits to send its end
+    Ending,
+    /// Everything was sent, waiting for echoes or the report
+    Draining,
+    Done,
+}
+
+/// Frame to send back, its pattern bytes made again from the fields
+#[derive(Debug, Clone, Copy)]
+struct Echo {
+    pattern: Pattern,
+    seq: u16,
+    stamp: u32,
+    len: usize,
+}
+
+/// Latency summary of a run as `[p50, p90, p99, max]` in microseconds
+type Summary = [u32; 4];
+
+fn summarize(latencies: &mut [u32]) -> Summary {
+    let Some(&max) = latencies.iter().max() else {
+        return [0; 4];
+    };
+    latencies.sort_unstable();
+    let at = |pct: usize| latencies[(latencies.len() - 1) * pct / 100];
+    [at(50), at(90), at(99), max]
+}
+
+#[derive(Debug)]
+pub struct Bench {
+    settings: Settings,
+    state: State,
+    /// Frames sent in the run, or received by the follower
+    sent: u16,
+    received: u16,
+    /// Pattern bytes received
+    bytes: u32,
+    /// Local time of the first and last frame of the run
+    first: u32,
+    last: u32,
+    latencies: heapless::Vec<u32, MAX_COUNT>,
+    /// Summary of the run, set once done
+    summary: Summary,
+    drain_timeout: st7580::Timeout,
+    /// Runs from the last frame sent or echo heard of an echo run
+    echo_timeout: st7580::Timeout,
+    /// Frames of an echo run given up on
+    lost: u16,
+    /// Echoes that came back cut down
+    truncated: u16,
+    echoes: heapless::Deque<Echo, ECHO_QUEUE>,
+    report_due: bool,
+    /// Whether the sink's report came in
+    reported: bool,
+}
+
+impl Bench {
+    pub fn new() -> Self {
+        Self {
+            settings: Settings {
+                mode: Mode::Echo,
+                size: 0,
+                pattern: Pattern::Zeros,
+                count: 0,
+            },
+            state: State::Idle,
+            sent: 0,
+            received: 0,
+            bytes: 0,
+            first: 0,
+            last: 0,
+            latencies: heapless::Vec::new(),
+            summary: [0; 4],
+            drain_timeout: Default::default(),
+            echo_timeout: Default::default(),
+            lost: 0,
+            truncated: 0,
+            echoes: heapless::Deque::new(),
+            report_due: false,
+            reported: false,
+        }
+    }
+
+    /// Starts a run from the leader, unless one is going on
+    pub fn start(&mut self, settings: Settings) -> bool {
+        if matches!(
+            self.state,
+            State::Sending | State::Ending | State::Draining
+        ) {
+            return false;
+        }
+        *self = Self {
+            settings,
+            state: State::Sending,
+            first: st7580::now_us(),
+            ..Self::new()
+        };
+        true
+    }
+
+    /// Whether a frame waits to go to the peer
+    pub fn is_pending(&self) -> bool {
+        (self.state == State::Sending && self.window_open())
+            || self.state == State::Ending
+            || self.report_due
+            || !self.echoes.is_empty()
+    }
+
+    /// Whether another frame of the run may go out, which in echo mode
+    /// takes fewer than `ECHO_QUEUE` frames waiting for their echo
+    fn window_open(&self) -> bool {
+        let unechoed = self.sent.saturating_sub(self.received + self.lost);
+        self.settings.mode != Mode::Echo || usize::from(unechoed) < ECHO_QUEUE
+    }
+
+    /// Builds the next frame for the peer, its pattern cut down to `room`
+    pub(super) fn take_frame(&mut self, room: usize) -> Option<mem::BufBox> {
+        if !self.is_pending() {
+            return None;
+        }
+        let mut buf = mem::alloc()?;
+        let len = room.saturating_sub(DATA_HEADER);
+        if self.report_due {
+            self.report_due = false;
+            let span = self.last.wrapping_sub(self.first);
+            buf.push(Message::Report.into()).unwrap();
+            buf.extend_from_slice(&self.received.to_le_bytes()).unwrap();
+            buf.extend_from_slice(&self.bytes.to_le_bytes()).unwrap();
+            buf.extend_from_slice(&span.to_le_bytes()).unwrap();
+            for value in summarize(&mut self.latencies) {
+                buf.extend_from_slice(&value.to_le_bytes()).unwrap();
+            }
+            // The next run starts over even if its first frame is lost
+            self.received = 0;
+        } else if let Some(echo) = self.echoes.pop_front() {
+            buf.push(Message::Echo.into()).unwrap();
+            buf.push((echo.len > len).into()).unwrap();
+            buf.push(echo.pattern as u8).unwrap();
+            buf.extend_from_slice(&echo.seq.to_le_bytes()).unwrap();
+            buf.extend_from_slice(&echo.stamp.to_le_bytes()).unwrap();
+            echo.pattern.fill(echo.seq, echo.len.min(len), &mut buf);
+        } else if self.state == State::Sending && self.window_open() {
+            let Settings {
+                mode,
+                size,
+                pattern,
+                count,
+            } = self.settings;
+            let seq = self.sent;
+            let stamp = st7580::now_us();
+            buf.push(Message::Data.into()).unwrap();
+            buf.push(mode as u8).unwrap();
+            buf.push(pattern as u8).unwrap();
+            buf.extend_from_slice(&seq.to_le_bytes()).unwrap();
+            buf.extend_from_slice(&stamp.to_le_bytes()).unwrap();
+            pattern.fill(seq, (size as usize).min(len), &mut buf);
+            self.sent += 1;
+            if mode == Mode::Echo {
+                self.echo_timeout.set(DRAIN_TIME);
+            }
+            if self.sent == count {
+                self.state = match mode {
+                    Mode::Echo => State::Draining,
+                    Mode::Sink => State::Ending,
+                };
+                self.drain_timeout.set(DRAIN_TIME);
+            }
+        } else if self.state == State::Ending {
+            buf.push(Message::End.into()).unwrap();
+            self.state = State::Draining;
+            self.drain_timeout.set(DRAIN_TIME);
+        } else {
+            return None;
+        }
+        Some(buf)
+    }
+
+    /// Handles a benchmark frame from the peer
+    pub(super) fn received(&mut self, data: &[u8]) {
+        let now = st7580::now_us();
+        let Some((&kind, payload)) = data.split_first() else { return };
+        match Message::try_from(kind) {
+            Ok(Message::Data) => {
+                let &[mode, pattern, s0, s1, t0, t1, t2, t3, ..] = payload
+                else {
+                    return;
+                };
+                let (Ok(mode), Ok(pattern)) =
+                    (Mode::try_from(mode), Pattern::try_from(pattern))
+                else {
+                    return;
+                };
+                let seq = u16::from_le_bytes([s0, s1]);
+                let stamp = u32::from_le_bytes([t0, t1, t2, t3]);
+                let len = payload.len() - 8;
+                match mode {
+                    Mode::Echo => {
+                        let echo = Echo {
+                            pattern,
+                            seq,
+                            stamp,
+                            len,
+                        };
+                        if self.echoes.push_back(echo).is_err() {
+                            crate::dbg::println!("echo {} dropped", seq);
+                        }
+                    }
+                    Mode::Sink => {
+                        if seq == 0 || self.received == 0 {
+                            *self = Self {
+                                first: now,
+                                ..Self::new()
+                            };
+                        }
+                        self.received = self.received.saturating_add(1);
+                        self.bytes += len as u32;
+                        self.last = now;
+                        if let Some(leader_now) = time::now() {
+                            let latency = leader_now.wrapping_sub(stamp);
+                            self.latencies.push(latency).ok();
+                        }
+                    }
+                }
+            }
+            Ok(Message::Echo)
+                if matches!(self.state, State::Sending | State::Draining) =>
+            {
+                let &[cut, _, _, _, t0, t1, t2, t3, ..] = payload else {
+                    return;
+                };
+                let stamp = u32::from_le_bytes([t0, t1, t2, t3]);
+                if cut != 0 {
+                    self.truncated += 1;
+                }
+                self.echo_timeout.set(DRAIN_TIME);
+                self.received += 1;
+                self.bytes += (payload.len() - 8) as u32;
+                self.last = now;
+                self.latencies.push(now.wrapping_sub(stamp)).ok();
+            }
+            Ok(Message::End) => self.report_due = true,
+            Ok(Message::Report) if self.state == State::Draining => {
+                if data.len() != REPORT_LEN {
+                    return;
+                }
+                let mut values = payload[2..]
+                    .chunks_exact(4)
+                    .map(|v| u32::from_le_bytes(v.try_into().unwrap()));
+                self.received = u16::from_le_bytes([payload[0], payload[1]]);
+                self.bytes = values.next().unwrap();
+                // Only the span between the sink's first and last frame
+                // counts for the goodput
+                self.first = 0;
+                self.last = values.next().unwrap();
+                for value in &mut self.summary {
+                    *value = values.next().unwrap();
+                }
+                self.reported = true;
+            }
+            Ok(_) => {}
+            Err(_v) => {
+                crate::dbg::println!("unknown benchmark message {}", _v);
+            }
+        }
+    }
+
+    /// Ends a run once every echo or the report is in, or the wait for them
+    /// ran out, returning whether it just ended
+    pub fn poll(&mut self) -> bool {
+        if self.state == State::Sending && self.echo_timeout.is_expired() {
+            crate::dbg::println!("echoes lost");
+            self.lost = self.sent.saturating_sub(self.received);
+            self.echo_timeout = Default::default();
+        }
+        let echoed = self.settings.mode == Mode::Echo
+            && self.received == self.settings.count;
+        let finished = self.state == State::Draining
+            && (echoed || self.reported || self.drain_timeout.is_expired());
+        if !finished {
+            return false;
+        }
+        if self.settings.mode == Mode::Echo {
+            self.summary = summarize(&mut self.latencies);
+        }
+        self.state = State::Done;
+        true
+    }
+
+    /// Encodes the run for the host as `[state, mode, sent, received,
+    /// goodput, p50, p90, p99, max, truncated]`, the state being `0` idle,
+    /// `1` running or `2` done, with the goodput in pattern bytes per second,
+    /// the latencies in microseconds and the echoes that came back cut down
+    pub fn encode(&self) -> [u8; 28] {
+        let state = match self.state {
+            State::Idle => 0,
+            State::Sending | State::Ending | State::Draining => 1,
+            State::Done => 2,
+        };
+        let span = self.last.wrapping_sub(self.first) as u64;
+        let goodput = match span {
+            0 => 0,
+            span => (self.bytes as u64 * 1_000_000 / span) as u32,
+        };
+        let mut buf = [0; 28];
+        buf[0] = state;
+        buf[1] = self.settings.mode as u8;
+        buf[2..4].copy_from_slice(&self.sent.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.received.to_le_bytes());
+        buf[6..10].copy_from_slice(&goodput.to_le_bytes());
+        for (chunk, value) in buf[10..].chunks_exact_mut(4).zip(self.summary) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        buf[26..].copy_from_slice(&self.truncated.to_le_bytes());
+        buf
+    }
+}
+
+impl Default for Bench {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: Mode, count: u16) -> Settings {
+        Settings {
+            mode,
+            size: 32,
+            pattern: Pattern::Counter,
+            count,
+        }
+    }
+
+    #[test]
+    fn summary_picks_percentiles() {
+        let mut latencies: [u32; 100] =
+            core::array::from_fn(|i| (i as u32 * 37) % 100 + 1);
+        assert_eq!(summarize(&mut latencies), [50, 90, 99, 100]);
+        assert_eq!(summarize(&mut [7]), [7; 4]);
+        assert_eq!(summarize(&mut []), [0; 4]);
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let decoded = Settings::decode(&[0x01, 32, 0x01, 20, 0]);
+        assert_eq!(decoded, Some(settings(Mode::Sink, 20)));
+        assert_eq!(Settings::decode(&[0x00, 32, 0x01, 0, 0]), None);
+        assert_eq!(Settings::decode(&[0x00, 32, 0x01, 1, 1]), None);
+        assert_eq!(Settings::decode(&[0x02, 32, 0x01, 20, 0]), None);
+        assert_eq!(Settings::decode(&[0x00, 32, 0x03, 20, 0]), None);
+        assert_eq!(Settings::decode(&[0x00, 32, 0x01, 20]), None);
+    }
+
+    #[test]
+    fn echo_runs_wait_for_their_echoes() {
+        mem::grow_for_tests();
+        let mut bench = Bench::new();
+        assert!(bench.start(settings(Mode::Echo, 20)));
+        assert!(!bench.start(settings(Mode::Echo, 20)));
+        let mut frames = 0;
+        while bench.take_frame(64).is_some() {
+            frames += 1;
+        }
+        assert_eq!(frames, ECHO_QUEUE);
+        assert!(!bench.is_pending());
+    }
+
+    #[test]
+    fn sink_runs_send_everything_then_end() {
+        mem::grow_for_tests();
+        let mut bench = Bench::new();
+        assert!(bench.start(settings(Mode::Sink, 20)));
+        let mut frames = heapless::Vec::<u8, 32>::new();
+        while let Some(frame) = bench.take_frame(64) {
+            frames.push(frame[0]).unwrap();
+        }
+        assert_eq!(frames.len(), 21);
+        assert_eq!(frames[20], Message::End.into());
+        assert_eq!(bench.encode()[2..4], 20u16.to_le_bytes());
+    }
+}
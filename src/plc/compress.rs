@@ -0,0 +1,317 @@
+//! Compression of tunnel payloads
+//!
+//! Payloads are packed with a small LZ77 scheme before they are sealed, and
+//! the frame header says whether a payload was packed. Payloads that would not
+//! get smaller are sent as they are. Packing is only done when the `COMPRESS`
+//! feature is on, but packed payloads are always understood.
+//!
+//! The packed stream is a sequence of tokens:
+//!
+//! - `0nnnnnnn` followed by `n + 1` literal bytes
+//! - `1nnnnnnn oooooooo` copies `n + MIN_MATCH` bytes from `o + 1` bytes back
+//!
+//! Since payloads fit in one pool buffer, offsets always fit in a byte.
+
+use crate::mem;
+
+/// Whether outgoing payloads are packed
+pub const ENABLED: bool = cfg!(feature = "COMPRESS");
+
+const MATCH_FLAG: u8 = 0x80;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 0x7F;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressError {
+    /// A token runs past the end of the packed payload
+    Truncated,
+    /// A match reaches back before the start of the payload
+    BadOffset,
+    /// The payload unpacks to more than a buffer holds
+    Overflow,
+}
+
+/// Bytes before and after coding, the ratio achieved is `raw / coded`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counters {
+    pub raw: u32,
+    pub coded: u32,
+}
+
+impl Counters {
+    fn add(&mut self, raw: usize, coded: usize) {
+        self.raw = self.raw.wrapping_add(raw as u32);
+        self.coded = self.coded.wrapping_add(coded as u32);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Compression {
+    /// Data payloads sent to the peer
+    pub tx: Counters,
+    /// Data payloads received from the peer
+    pub rx: Counters,
+}
+
+impl Compression {
+    pub const fn new() -> Self {
+        Self {
+            tx: Counters { raw: 0, coded: 0 },
+            rx: Counters { raw: 0, coded: 0 },
+        }
+    }
+
+    /// Packs the payload in `buf` when that makes it smaller, returning
+    /// whether it did
+    pub(super) fn pack(&mut self, buf: &mut mem::VecBuf) -> bool {
+        let raw = buf.len();
+        let mut packed = mem::VecBuf::new();
+        let done = ENABLED && compress(buf, &mut packed);
+        if done {
+            buf.clear();
+            buf.extend_from_slice(&packed).unwrap();
+        }
+        self.tx.add(raw, buf.len());
+        done
+    }
+
+    /// Unpacks the payload in `buf` if the frame says it was packed
+    pub(super) fn unpack(
+        &mut self,
+        buf: &mut mem::VecBuf,
+        packed: bool,
+    ) -> Result<(), CompressError> {
+        let coded = buf.len();
+        if packed {
+            let mut raw = mem::VecBuf::new();
+            decompress(buf, &mut raw)?;
+            *buf = raw;
+        }
+        self.rx.add(buf.len(), coded);
+        Ok(())
+    }
+
+    /// Encodes the counters for the host as `[tx raw, tx coded, rx raw,
+    /// rx coded]`
+    pub fn encode(&self) -> [u8; 16] {
+        let mut buf = [0; 16];
+        let counters = [self.tx.raw, self.tx.coded, self.rx.raw, self.rx.coded];
+        for (chunk, val) in buf.chunks_mut(4).zip(counters) {
+            chunk.copy_from_slice(&val.to_le_bytes());
+        }
+        buf
+    }
+}
+
+/// Packs `input` into `out`, giving up once the result is no smaller
+fn compress(input: &[u8], out: &mut mem::VecBuf) -> bool {
+    pack_tokens(input, out).is_some() && out.len() < input.len()
+}
+
+fn pack_tokens(input: &[u8], out: &mut mem::VecBuf) -> Option<()> {
+    let mut literals = 0;
+    let mut pos = 0;
+    while pos < input.len() {
+        let (len, offset) = longest_match(input, pos);
+        if len < MIN_MATCH {
+            pos += 1;
+            literals += 1;
+            if literals == MAX_LITERALS {
+                push_literals(&input[pos - literals..pos], out)?;
+                literals = 0;
+            }
+        } else {
+            push_literals(&input[pos - literals..pos], out)?;
+            literals = 0;
+            out.push(MATCH_FLAG | (len - MIN_MATCH) as u8).ok()?;
+            out.push((offset - 1) as u8).ok()?;
+            pos += len;
+        }
+        if out.len() >= input.len() {
+            return None;
+        }
+    }
+    push_literals(&input[pos - literals..pos], out)
+}
+
+fn push_literals(literals: &[u8], out: &mut mem::VecBuf) -> Option<()> {
+    if literals.is_empty() {
+        return Some(());
+    }
+    out.push((literals.len() - 1) as u8).ok()?;
+    out.extend_from_slice(literals).ok()
+}
+
+/// Finds the longest earlier copy of the bytes at `pos`, as `(len, offset)`
+fn longest_match(input: &[u8], pos: usize) -> (usize, usize) {
+    let max_len = MAX_MATCH.min(input.len() - pos);
+    let mut best = (0, 0);
+    for start in pos.saturating_sub(MAX_OFFSET)..pos {
+        let len = input[start..]
+            .iter()
+            .zip(&input[pos..pos + max_len])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best.0 {
+            best = (len, pos - start);
+        }
+    }
+    best
+}
+
+fn decompress(
+    input: &[u8],
+    out: &mut mem::VecBuf,
+) -> Result<(), CompressError> {
+    let mut pos = 0;
+    while let Some(&token) = input.get(pos) {
+        pos += 1;
+        if token & MATCH_FLAG == 0 {
+            let len = token as usize + 1;
+            let literals =
+                input.get(pos..pos + len).ok_or(CompressError::Truncated)?;
+            out.extend_from_slice(literals)
+                .map_err(|_| CompressError::Overflow)?;
+            pos += len;
+        } else {
+            let len = (token & !MATCH_FLAG) as usize + MIN_MATCH;
+            let offset =
+                *input.get(pos).ok_or(CompressError::Truncated)? as usize + 1;
+            pos += 1;
+            let start = out
+                .len()
+                .checked_sub(offset)
+                .ok_or(CompressError::BadOffset)?;
+            // Byte by byte since the copy may overlap what it produces
+            for idx in start..start + len {
+                out.push(out[idx]).map_err(|_| CompressError::Overflow)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs `input`, checking it unpacks to the same bytes, and returns the
+    /// packed length if packing paid off
+    fn round_trip(input: &[u8]) -> Option<usize> {
+        let mut packed = mem::VecBuf::new();
+        if !compress(input, &mut packed) {
+            return None;
+        }
+        let mut raw = mem::VecBuf::new();
+        assert_eq!(decompress(&packed, &mut raw), Ok(()));
+        assert_eq!(raw, input);
+        Some(packed.len())
+    }
+
+    /// Bytes with no repeats worth a match
+    fn noise(len: usize) -> std::vec::Vec<u8> {
+        let mut state = 0x1234_5678_u32;
+        (0..len)
+            .map(|_| {
+                state =
+                    state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn text(len: usize) -> std::vec::Vec<u8> {
+        b"{\"level\":\"info\",\"msg\":\"tunnel up\"}\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn empty_payloads_stay_as_they_are() {
+        assert_eq!(round_trip(&[]), None);
+        let mut raw = mem::VecBuf::new();
+        assert_eq!(decompress(&[], &mut raw), Ok(()));
+        assert!(raw.is_empty());
+    }
+
+    #[test]
+    fn incompressible_payloads_are_sent_as_is() {
+        let input = noise(mem::VecBuf::new().capacity());
+        assert_eq!(round_trip(&input), None);
+
+        let mut compression = Compression::new();
+        let mut buf = mem::VecBuf::from_slice(&input).unwrap();
+        assert!(!compression.pack(&mut buf));
+        assert_eq!(buf, input[..]);
+    }
+
+    #[test]
+    fn runs_copy_over_themselves() {
+        let max = mem::VecBuf::new().capacity();
+        let packed = round_trip(&std::vec![0xAA; max]).unwrap();
+        assert!(packed <= 6, "{packed}");
+        let mut run = [7; 200];
+        run[..4].copy_from_slice(b"head");
+        assert!(round_trip(&run).is_some());
+    }
+
+    #[test]
+    fn full_buffers_round_trip() {
+        let max = mem::VecBuf::new().capacity();
+        for len in [60, 100, max] {
+            assert!(round_trip(&text(len)).is_some(), "{len}");
+        }
+        // Literals longer than one token can hold, then a match
+        let mut mixed = noise(max);
+        mixed[200..].copy_from_slice(&noise(max)[..max - 200]);
+        assert!(round_trip(&mixed).is_some());
+    }
+
+    #[test]
+    fn broken_streams_are_errors() {
+        let mut raw = mem::VecBuf::new();
+        assert_eq!(
+            decompress(&[0x05, 1, 2], &mut raw),
+            Err(CompressError::Truncated)
+        );
+        raw.clear();
+        assert_eq!(
+            decompress(&[0x80], &mut raw),
+            Err(CompressError::Truncated)
+        );
+        raw.clear();
+        assert_eq!(
+            decompress(&[0x80, 0x00], &mut raw),
+            Err(CompressError::BadOffset)
+        );
+        raw.clear();
+        assert_eq!(
+            decompress(&[0x00, 1, 0x80, 0x01], &mut raw),
+            Err(CompressError::BadOffset)
+        );
+        raw.clear();
+        assert_eq!(
+            decompress(&[0x00, 1, 0xFF, 0x00, 0xFF, 0x00], &mut raw),
+            Err(CompressError::Overflow)
+        );
+
+        // Cut or garbled anywhere, a packed stream never panics
+        let mut packed = mem::VecBuf::new();
+        assert!(compress(&text(200), &mut packed));
+        for len in 0..packed.len() {
+            raw.clear();
+            let _ = decompress(&packed[..len], &mut raw);
+        }
+        for (idx, byte) in noise(packed.len()).into_iter().enumerate() {
+            let mut garbled = packed.clone();
+            garbled[idx] ^= byte | 1;
+            raw.clear();
+            let _ = decompress(&garbled, &mut raw);
+        }
+    }
+}
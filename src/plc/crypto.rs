@@ -0,0 +1,373 @@
+//! Authenticated encryption of tunnel payloads
+//!
+//! Data payloads are sealed with XChaCha20-Poly1305 under a pre-shared key,
+//! with the link header as associated data. This happens before the frame
+//! reaches the modem, so it works the same over `phy_data` and `dl_data`.
+//! The nonce is built from the sender's 96-bit unique device ID, its boot
+//! epoch and a counter, all three travelling in clear ahead of the
+//! ciphertext:
+//!
+//! `[uid: 12][epoch: 4][counter: 4][ciphertext][tag: 16]`
+//!
+//! The device ID gives every node its own nonce space under a shared key,
+//! which the 16-bit address folded from it cannot promise, and the epoch
+//! claimed from flash keeps nonces from repeating after a reboot. The
+//! receiver tracks a window of counters seen from its peer and drops anything
+//! replayed or older than the window. That window lives in RAM, so frames
+//! from the peer's current epoch can be replayed once after our own reboot.
+//!
+//! Everything is done in place in the frame's pool buffer.
+
+use super::LinkHeader;
+use crate::{config, mem, util};
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    Key, Tag, XChaCha20Poly1305, XNonce,
+};
+
+const UID_LEN: usize = 12;
+const PREFIX_LEN: usize = UID_LEN + 8;
+const TAG_LEN: usize = 16;
+
+/// Bytes added to every sealed payload
+pub const OVERHEAD: usize = PREFIX_LEN + TAG_LEN;
+
+/// Number of counters behind the highest one still accepted
+const WINDOW: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The payload is too short to hold the nonce and tag
+    Truncated,
+    /// The buffer has no room left for the nonce and tag
+    Overflow,
+    /// The counter was already seen or is too old
+    Replayed,
+    /// The tag did not match, the frame was forged or corrupted
+    Auth,
+    /// Every counter of this epoch was used, a reboot is needed
+    Exhausted,
+}
+
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    valid: bool,
+    epoch: u32,
+    highest: u32,
+    /// Bit `n` is set when `highest - n` was seen
+    seen: u32,
+}
+
+impl ReplayWindow {
+    fn check(&self, epoch: u32, counter: u32) -> bool {
+        if !self.valid || epoch > self.epoch {
+            return true;
+        }
+        if epoch < self.epoch {
+            return false;
+        }
+        if counter > self.highest {
+            return true;
+        }
+        let age = self.highest - counter;
+        age < WINDOW && self.seen & (1 << age) == 0
+    }
+
+    fn accept(&mut self, epoch: u32, counter: u32) {
+        if !self.valid || epoch > self.epoch {
+            *self = Self {
+                valid: true,
+                epoch,
+                highest: counter,
+                seen: 1,
+            };
+        } else if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift < WINDOW {
+                self.seen << shift
+            } else {
+                0
+            };
+            self.seen |= 1;
+            self.highest = counter;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+pub struct Crypto {
+    cipher: Option<XChaCha20Poly1305>,
+    /// Unique device ID of this node, sent ahead of every sealed payload
+    uid: [u8; UID_LEN],
+    epoch: u32,
+    counter: u32,
+    window: ReplayWindow,
+    pub auth_failures: u32,
+    pub replays: u32,
+}
+
+impl Crypto {
+    /// Loads the key from flash and, when one is set, claims a new epoch
+    pub fn load(store: &mut config::Store) -> Self {
+        let config = store.load();
+        let mut uid = [0; UID_LEN];
+        for (bytes, word) in uid.chunks_mut(4).zip(util::unique_id()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        let mut crypto = Self {
+            cipher: None,
+            uid,
+            epoch: config.epoch,
+            counter: 0,
+            window: Default::default(),
+            auth_failures: 0,
+            replays: 0,
+        };
+        if config.key == [0; config::KEY_LEN] {
+            return crypto;
+        }
+
+        match store.claim_epoch() {
+            Ok(epoch) => crypto.epoch = epoch,
+            Err(_e) => {
+                // Sealing under a reused epoch would repeat nonces
+                crate::dbg::println!("no epoch, encryption off {:?}", _e);
+                return crypto;
+            }
+        }
+        crypto.cipher =
+            Some(XChaCha20Poly1305::new(Key::from_slice(&config.key)));
+        crypto
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Replaces the pre-shared key, `None` turns encryption off
+    pub fn set_key(
+        &mut self,
+        key: Option<&[u8; config::KEY_LEN]>,
+        store: &mut config::Store,
+    ) {
+        let mut config = store.load();
+        config.key = key.copied().unwrap_or_default();
+        self.cipher = None;
+        self.forget_peer();
+        if let Err(_e) = store.save(&config) {
+            crate::dbg::println!("failed to persist key {:?}", _e);
+            return;
+        }
+        // The same key may come back, so it gets a fresh epoch like on boot
+        match store.claim_epoch() {
+            Ok(epoch) => self.epoch = epoch,
+            Err(_e) => {
+                crate::dbg::println!("no epoch, encryption off {:?}", _e);
+                return;
+            }
+        }
+        self.counter = 0;
+        self.cipher = key.map(|k| XChaCha20Poly1305::new(Key::from_slice(k)));
+    }
+
+    /// Clears the replay window, for when the peer changes
+    pub fn forget_peer(&mut self) {
+        self.window = Default::default();
+    }
+
+    /// Encrypts the payload in `buf` that will be sent under `hdr`
+    pub(super) fn seal(
+        &mut self,
+        hdr: &LinkHeader,
+        buf: &mut mem::VecBuf,
+    ) -> Result<(), CryptoError> {
+        let Some(cipher) = &self.cipher else { return Ok(()) };
+        if buf.len() + OVERHEAD > buf.capacity() {
+            return Err(CryptoError::Overflow);
+        }
+        let counter = self.counter;
+        self.counter = counter.checked_add(1).ok_or(CryptoError::Exhausted)?;
+
+        let nonce = nonce(&self.uid, self.epoch, counter);
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce, &hdr.encode(), buf)
+            .map_err(|_| CryptoError::Overflow)?;
+        buf.extend_from_slice(&tag).unwrap();
+
+        buf.extend_from_slice(&self.uid).unwrap();
+        buf.extend_from_slice(&self.epoch.to_le_bytes()).unwrap();
+        buf.extend_from_slice(&counter.to_le_bytes()).unwrap();
+        buf.rotate_right(PREFIX_LEN);
+        Ok(())
+    }
+
+    /// Authenticates and decrypts the payload in `buf` received under `hdr`
+    pub(super) fn open(
+        &mut self,
+        hdr: &LinkHeader,
+        buf: &mut mem::VecBuf,
+    ) -> Result<(), CryptoError> {
+        let Some(cipher) = &self.cipher else { return Ok(()) };
+        if buf.len() < OVERHEAD {
+            return Err(CryptoError::Truncated);
+        }
+        let uid: [u8; UID_LEN] = buf[..UID_LEN].try_into().unwrap();
+        let word =
+            |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        let (epoch, counter) = (word(UID_LEN), word(UID_LEN + 4));
+        if !self.window.check(epoch, counter) {
+            self.replays = self.replays.wrapping_add(1);
+            return Err(CryptoError::Replayed);
+        }
+
+        let body_end = buf.len() - TAG_LEN;
+        let tag = Tag::clone_from_slice(&buf[body_end..]);
+        let nonce = nonce(&uid, epoch, counter);
+        if cipher
+            .decrypt_in_place_detached(
+                &nonce,
+                &hdr.encode(),
+                &mut buf[PREFIX_LEN..body_end],
+                &tag,
+            )
+            .is_err()
+        {
+            self.auth_failures = self.auth_failures.wrapping_add(1);
+            return Err(CryptoError::Auth);
+        }
+        self.window.accept(epoch, counter);
+
+        buf.copy_within(PREFIX_LEN..body_end, 0);
+        buf.truncate(body_end - PREFIX_LEN);
+        Ok(())
+    }
+}
+
+fn nonce(uid: &[u8; UID_LEN], epoch: u32, counter: u32) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..UID_LEN].copy_from_slice(uid);
+    nonce[UID_LEN..UID_LEN + 4].copy_from_slice(&epoch.to_le_bytes());
+    nonce[UID_LEN + 4..UID_LEN + 8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plc::Header;
+
+    fn crypto(uid: u8) -> Crypto {
+        Crypto {
+            cipher: Some(XChaCha20Poly1305::new(&[7; config::KEY_LEN].into())),
+            uid: [uid; UID_LEN],
+            epoch: 1,
+            counter: 0,
+            window: Default::default(),
+            auth_failures: 0,
+            replays: 0,
+        }
+    }
+
+    fn header() -> LinkHeader {
+        LinkHeader {
+            kind: Header::Data,
+            compressed: false,
+            more: false,
+            seq: false,
+            network: 0x1234,
+            src: 1,
+            dst: 2,
+            channel: 0,
+            credits: [0; crate::usb::CHANNELS],
+        }
+    }
+
+    fn sealed(crypto: &mut Crypto, payload: &[u8]) -> mem::VecBuf {
+        let mut buf = mem::VecBuf::from_slice(payload).unwrap();
+        crypto.seal(&header(), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn sealed_payloads_open() {
+        let (mut tx, mut rx) = (crypto(1), crypto(2));
+        let mut buf = sealed(&mut tx, b"hello");
+        assert_eq!(buf.len(), 5 + OVERHEAD);
+        assert_eq!(buf[..UID_LEN], [1; UID_LEN]);
+        assert_eq!(rx.open(&header(), &mut buf), Ok(()));
+        assert_eq!(buf, b"hello"[..]);
+
+        let mut buf = sealed(&mut tx, b"");
+        assert_eq!(rx.open(&header(), &mut buf), Ok(()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn replays_are_dropped() {
+        let (mut tx, mut rx) = (crypto(1), crypto(2));
+        let frame = sealed(&mut tx, b"once");
+        assert_eq!(rx.open(&header(), &mut frame.clone()), Ok(()));
+        assert_eq!(
+            rx.open(&header(), &mut frame.clone()),
+            Err(CryptoError::Replayed)
+        );
+        assert_eq!(rx.replays, 1);
+        assert_eq!(rx.auth_failures, 0);
+    }
+
+    #[test]
+    fn window_slides_with_the_highest_counter() {
+        let mut window = ReplayWindow::default();
+        window.accept(1, 100);
+        // Late frames within the window still get in, once
+        assert!(window.check(1, 100 - (WINDOW - 1)));
+        window.accept(1, 95);
+        assert!(!window.check(1, 95));
+        assert!(!window.check(1, 100 - WINDOW));
+
+        // Moving up keeps what was seen, until it falls out of the window
+        window.accept(1, 110);
+        assert!(!window.check(1, 95));
+        assert!(!window.check(1, 100));
+        assert!(window.check(1, 101));
+        window.accept(1, 110 + WINDOW);
+        assert!(!window.check(1, 110));
+        assert!(window.check(1, 111));
+
+        // A new epoch starts over, an old one is never taken again
+        assert!(window.check(2, 0));
+        window.accept(2, 0);
+        assert!(!window.check(1, 200));
+        assert!(window.check(2, 1));
+    }
+
+    #[test]
+    fn tampered_frames_fail_auth() {
+        let (mut tx, mut rx) = (crypto(1), crypto(2));
+        let frame = sealed(&mut tx, b"payload");
+
+        let mut tag = frame.clone();
+        *tag.last_mut().unwrap() ^= 1;
+        assert_eq!(rx.open(&header(), &mut tag), Err(CryptoError::Auth));
+
+        let mut uid = frame.clone();
+        uid[0] ^= 1;
+        assert_eq!(rx.open(&header(), &mut uid), Err(CryptoError::Auth));
+
+        let mut hdr = header();
+        hdr.dst = 3;
+        assert_eq!(rx.open(&hdr, &mut frame.clone()), Err(CryptoError::Auth));
+        assert_eq!(rx.auth_failures, 3);
+
+        // Failures do not count as seen, the genuine frame still opens
+        assert_eq!(rx.open(&header(), &mut frame.clone()), Ok(()));
+    }
+
+    #[test]
+    fn short_payloads_are_truncated() {
+        let mut rx = crypto(2);
+        let mut buf = mem::VecBuf::from_slice(&[0; OVERHEAD - 1]).unwrap();
+        assert_eq!(rx.open(&header(), &mut buf), Err(CryptoError::Truncated));
+    }
+}
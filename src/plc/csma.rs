@@ -0,0 +1,82 @@
+//! Carrier sense with random backoff for the contention mode
+//!
+//! Either peer may start a frame, so before every frame a node waits until
+//! the modem has seen the line clear for `CLEAR_TIME`, then for a random
+//! number of slots picked from its contention window. Any activity on the
+//! line freezes the countdown until the line is clear again. Frames that go
+//! unacknowledged are taken as collisions and double the window, up to
+//! `MAX_WINDOW`, which shrinks back once a frame gets through.
+
+use crate::{st7580, util};
+
+/// Length of a backoff slot in milliseconds
+pub const SLOT: u32 = 4;
+/// Time the line must be clear before the countdown resumes in milliseconds
+pub const CLEAR_TIME: u32 = 2;
+/// Contention window after a successful frame, in slots
+pub const MIN_WINDOW: u32 = 4;
+/// Largest contention window, in slots
+pub const MAX_WINDOW: u32 = 128;
+/// Attempts at a frame before it is dropped
+pub const MAX_ATTEMPTS: u8 = 6;
+/// How long to wait for an ack in milliseconds
+pub const ACK_TIMEOUT: u32 = 200;
+
+#[derive(Debug)]
+pub struct Backoff {
+    rng: util::Rng,
+    /// Slots the next backoff is picked from
+    window: u32,
+    /// Slots still to wait out
+    slots_left: u32,
+    slot_timeout: st7580::Timeout,
+}
+
+impl Backoff {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            rng: util::Rng::new(seed),
+            window: MIN_WINDOW,
+            slots_left: 0,
+            slot_timeout: Default::default(),
+        }
+    }
+
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Starts the countdown for a new attempt
+    pub fn start(&mut self) {
+        self.slots_left = self.rng.below(self.window);
+        self.slot_timeout.set(CLEAR_TIME);
+    }
+
+    /// Follows the countdown given whether the line is busy, returning
+    /// whether the frame may go out
+    pub fn poll(&mut self, line_busy: bool) -> bool {
+        if line_busy {
+            self.slot_timeout.set(CLEAR_TIME);
+            return false;
+        }
+        if !self.slot_timeout.is_expired() {
+            return false;
+        }
+        if self.slots_left == 0 {
+            return true;
+        }
+        self.slots_left -= 1;
+        self.slot_timeout.set(SLOT);
+        false
+    }
+
+    /// Widens the window after a frame was lost
+    pub fn collided(&mut self) {
+        self.window = (self.window * 2).min(MAX_WINDOW);
+    }
+
+    /// Narrows the window after a frame got through
+    pub fn succeeded(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+}
@@ -0,0 +1,84 @@
+//! Election of a leader between nodes that were given no role
+//!
+//! A node without a role boots as a follower and listens. Leaders announce
+//! themselves every `ANNOUNCE_PERIOD`, so a follower that hears no leader for
+//! `TAKEOVER_TIME`, plus a random share of `TAKEOVER_JITTER` to keep two
+//! fresh nodes from claiming at once, takes over as leader. Once paired, only
+//! frames from its own leader count, so a follower whose leader disappears
+//! takes over the tunnel. Should two leaders still end up on one network,
+//! the one with the lower address steps down to a follower as soon as it
+//! hears the other.
+
+use super::neighbor::{Address, ANNOUNCE_PERIOD};
+use crate::{st7580, util};
+
+/// Time without hearing a leader before taking over in milliseconds
+pub const TAKEOVER_TIME: u32 = 2 * ANNOUNCE_PERIOD;
+/// Most random time added to `TAKEOVER_TIME` in milliseconds
+pub const TAKEOVER_JITTER: u32 = ANNOUNCE_PERIOD;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Nothing to change
+    Stay,
+    /// No leader was heard, this node takes over
+    TakeOver,
+    /// A leader with a higher address was heard, this node follows it
+    StepDown,
+}
+
+#[derive(Debug)]
+pub struct Election {
+    local: Address,
+    rng: util::Rng,
+    takeover_timeout: st7580::Timeout,
+    outcome: Outcome,
+}
+
+impl Election {
+    pub fn new(local: Address) -> Self {
+        Self {
+            local,
+            rng: util::Rng::new(local.into()),
+            takeover_timeout: Default::default(),
+            outcome: Outcome::Stay,
+        }
+    }
+
+    /// Starts listening for a leader, called whenever the node becomes a
+    /// follower
+    pub fn follow(&mut self) {
+        self.outcome = Outcome::Stay;
+        self.leader_heard();
+    }
+
+    /// Starts leading, called whenever the node becomes a leader
+    pub fn lead(&mut self) {
+        self.outcome = Outcome::Stay;
+        self.takeover_timeout.set(0);
+    }
+
+    /// Puts off the takeover after a follower heard from a leader it follows
+    pub fn leader_heard(&mut self) {
+        let jitter = self.rng.below(TAKEOVER_JITTER);
+        self.takeover_timeout.set(TAKEOVER_TIME + jitter);
+    }
+
+    /// Weighs another leader heard on the network of a leader
+    pub fn rival_heard(&mut self, rival: Address) {
+        if rival > self.local {
+            crate::dbg::println!("stepping down for {:04x}", rival);
+            self.outcome = Outcome::StepDown;
+        }
+    }
+
+    /// What the node should do, checked after every step of its state
+    /// machine
+    pub fn poll(&mut self) -> Outcome {
+        if self.outcome == Outcome::Stay && self.takeover_timeout.is_expired() {
+            crate::dbg::println!("no leader heard, taking over");
+            self.outcome = Outcome::TakeOver;
+        }
+        self.outcome
+    }
+}
@@ -0,0 +1,480 @@
+//! Forward error correction of whole frames
+//!
+//! The modem's PHY mode carries more data than DL mode but has no integrity
+//! checks of its own. Frames sent that way get Reed-Solomon parity over
+//! GF(2^8) appended, covering the link header and payload, so that burst
+//! errors on the wire can be repaired. Bits 5 and 6 of the kind byte say how
+//! much parity follows. Those bits are protected like the rest of the frame,
+//! but are read before decoding, so a frame whose rate bits were hit is
+//! decoded at the wrong rate and dropped as uncorrectable.
+//!
+//! Parity is only added when the `FEC` feature is on, which also moves the
+//! tunnel to PHY mode, but frames carrying parity are always decoded.
+
+use super::HEADER_IDX;
+use crate::{mem, st7580};
+
+/// Whether frames are sent in PHY mode with parity
+pub const ENABLED: bool = cfg!(feature = "FEC");
+
+/// Bits of the kind byte holding the rate
+pub const RATE_MASK: u8 = 0b0110_0000;
+const RATE_SHIFT: u8 = 5;
+
+/// Most parity bytes any rate adds
+const MAX_PARITY: usize = 32;
+/// Codewords are shortened from the full 255 byte code
+const MAX_CODEWORD: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    /// No parity
+    None = 0x00,
+    /// 8 parity bytes, corrects up to 4 bad bytes
+    Light = 0x01,
+    /// 16 parity bytes, corrects up to 8 bad bytes
+    Medium = 0x02,
+    /// 32 parity bytes, corrects up to 16 bad bytes
+    Strong = 0x03,
+}
+
+impl From<Rate> for u8 {
+    fn from(val: Rate) -> Self {
+        val as u8
+    }
+}
+
+impl TryFrom<u8> for Rate {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Rate::*;
+        match v {
+            0x00 => Ok(None),
+            0x01 => Ok(Light),
+            0x02 => Ok(Medium),
+            0x03 => Ok(Strong),
+            v => Err(v),
+        }
+    }
+}
+
+impl Rate {
+    pub const fn parity(self) -> usize {
+        match self {
+            Rate::None => 0,
+            Rate::Light => 8,
+            Rate::Medium => 16,
+            Rate::Strong => MAX_PARITY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FecError {
+    /// The frame and its parity do not fit in a buffer or codeword
+    Overflow,
+    /// The frame is shorter than its parity
+    Truncated,
+    /// More bytes were hit than the parity can repair
+    Uncorrectable,
+}
+
+pub struct Fec {
+    rate: Rate,
+    /// Bytes repaired in received frames
+    pub corrected: u32,
+    /// Received frames dropped because they could not be repaired
+    pub uncorrectable: u32,
+}
+
+impl Default for Fec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fec {
+    pub const fn new() -> Self {
+        Self {
+            rate: if ENABLED { Rate::Medium } else { Rate::None },
+            corrected: 0,
+            uncorrectable: 0,
+        }
+    }
+
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    /// Longest frame that still fits once parity is added
+    pub fn frame_max(&self) -> usize {
+        if ENABLED {
+            st7580::PHY_DATALEN_MAX - self.rate.parity()
+        } else {
+            st7580::DL_DATALEN_MAX
+        }
+    }
+
+    /// Changes the rate of sent frames, parity is never added without the
+    /// `FEC` feature since DL mode already checks frames
+    pub fn set_rate(&mut self, rate: Rate) {
+        if ENABLED {
+            self.rate = rate;
+        }
+    }
+
+    /// Marks the rate in the frame in `buf` and appends its parity
+    pub(super) fn encode(&self, buf: &mut mem::VecBuf) -> Result<(), FecError> {
+        let parity = self.rate.parity();
+        let Some(kind) = buf.first_mut() else { return Ok(()) };
+        *kind = (*kind & !RATE_MASK) | (u8::from(self.rate) << RATE_SHIFT);
+        if parity == 0 {
+            return Ok(());
+        }
+        let len = buf.len();
+        if len + parity > buf.capacity().min(MAX_CODEWORD) {
+            return Err(FecError::Overflow);
+        }
+        buf.resize(len + parity, 0).unwrap();
+        let (msg, rem) = buf.split_at_mut(len);
+        rs::encode(msg, rem);
+        Ok(())
+    }
+
+    /// Repairs a received frame in place and strips its parity
+    pub(super) fn decode(
+        &mut self,
+        frame: &mut st7580::Frame,
+    ) -> Result<(), FecError> {
+        let len = (frame.length as usize).min(frame.data.len());
+        let Some(&kind) = frame.data.get(HEADER_IDX) else { return Ok(()) };
+        let rate = Rate::try_from((kind & RATE_MASK) >> RATE_SHIFT).unwrap();
+        let parity = rate.parity();
+        if parity == 0 {
+            return Ok(());
+        }
+        if len < HEADER_IDX + parity {
+            self.uncorrectable = self.uncorrectable.wrapping_add(1);
+            return Err(FecError::Truncated);
+        }
+        match rs::decode(&mut frame.data[HEADER_IDX..len], parity) {
+            Some(fixed) => {
+                self.corrected = self.corrected.wrapping_add(fixed as u32);
+            }
+            None => {
+                self.uncorrectable = self.uncorrectable.wrapping_add(1);
+                return Err(FecError::Uncorrectable);
+            }
+        }
+        frame.data.truncate(len - parity);
+        frame.length = (len - parity) as u8;
+        Ok(())
+    }
+
+    /// Encodes the rate and counters for the host as `[rate, corrected,
+    /// uncorrectable]`
+    pub fn encode_counters(&self) -> [u8; 9] {
+        let mut buf = [0; 9];
+        buf[0] = self.rate.into();
+        buf[1..5].copy_from_slice(&self.corrected.to_le_bytes());
+        buf[5..9].copy_from_slice(&self.uncorrectable.to_le_bytes());
+        buf
+    }
+}
+
+/// Systematic Reed-Solomon over GF(2^8) with the polynomial 0x11D, whose
+/// generator has the roots `a^0` to `a^(parity - 1)`. Codewords are stored
+/// highest degree first, so the parity ends up after the message.
+mod rs {
+    use super::{MAX_CODEWORD, MAX_PARITY};
+
+    const POLY: u16 = 0x11D;
+
+    struct Tables {
+        exp: [u8; 512],
+        log: [u8; 256],
+    }
+
+    const fn tables() -> Tables {
+        let mut exp = [0; 512];
+        let mut log = [0; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= POLY;
+            }
+            i += 1;
+        }
+        while i < 512 {
+            exp[i] = exp[i - 255];
+            i += 1;
+        }
+        Tables { exp, log }
+    }
+
+    static GF: Tables = tables();
+
+    fn mul(a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        GF.exp[GF.log[a as usize] as usize + GF.log[b as usize] as usize]
+    }
+
+    fn div(a: u8, b: u8) -> u8 {
+        debug_assert_ne!(b, 0);
+        if a == 0 {
+            return 0;
+        }
+        GF.exp[GF.log[a as usize] as usize + 255 - GF.log[b as usize] as usize]
+    }
+
+    /// `a` raised to `power`
+    fn alpha(power: usize) -> u8 {
+        GF.exp[power % 255]
+    }
+
+    /// Evaluates a polynomial stored lowest degree first
+    fn eval(poly: &[u8], x: u8) -> u8 {
+        poly.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+    }
+
+    /// Writes the parity of `msg` into `parity`
+    pub fn encode(msg: &[u8], parity: &mut [u8]) {
+        let nsym = parity.len();
+        // Generator, highest degree first with an implied leading 1
+        let mut gen = [0; MAX_PARITY + 1];
+        gen[0] = 1;
+        for i in 0..nsym {
+            let root = alpha(i);
+            for j in (1..=i + 1).rev() {
+                gen[j] ^= mul(gen[j - 1], root);
+            }
+        }
+
+        parity.fill(0);
+        for &byte in msg {
+            let coef = byte ^ parity[0];
+            parity.copy_within(1.., 0);
+            parity[nsym - 1] = 0;
+            for (p, &g) in parity.iter_mut().zip(&gen[1..=nsym]) {
+                *p ^= mul(g, coef);
+            }
+        }
+    }
+
+    /// Repairs `code`, whose last `nsym` bytes are parity, returning how
+    /// many bytes were fixed or `None` when it is beyond repair
+    pub fn decode(code: &mut [u8], nsym: usize) -> Option<usize> {
+        let n = code.len();
+        if n > MAX_CODEWORD || nsym > MAX_PARITY {
+            return None;
+        }
+
+        let mut synd = [0; MAX_PARITY];
+        for (i, s) in synd[..nsym].iter_mut().enumerate() {
+            let x = alpha(i);
+            *s = code.iter().fold(0, |acc, &c| mul(acc, x) ^ c);
+        }
+        let synd = &synd[..nsym];
+        if synd.iter().all(|&s| s == 0) {
+            return Some(0);
+        }
+
+        // Berlekamp-Massey, locator stored lowest degree first
+        let mut loc = [0; MAX_PARITY + 1];
+        let mut prev = [0; MAX_PARITY + 1];
+        loc[0] = 1;
+        prev[0] = 1;
+        let mut errors = 0;
+        let mut shift = 1;
+        let mut prev_delta = 1;
+        for k in 0..nsym {
+            let delta = (1..=errors)
+                .fold(synd[k], |acc, i| acc ^ mul(loc[i], synd[k - i]));
+            if delta == 0 {
+                shift += 1;
+                continue;
+            }
+            let scale = div(delta, prev_delta);
+            let last = loc;
+            for i in shift..=MAX_PARITY {
+                loc[i] ^= mul(scale, prev[i - shift]);
+            }
+            if 2 * errors <= k {
+                errors = k + 1 - errors;
+                prev = last;
+                prev_delta = delta;
+                shift = 1;
+            } else {
+                shift += 1;
+            }
+        }
+        if 2 * errors > nsym {
+            return None;
+        }
+        let loc = &loc[..=errors];
+
+        // Evaluator, the syndromes times the locator modulo x^nsym
+        let mut omega = [0; MAX_PARITY];
+        for (i, o) in omega[..nsym].iter_mut().enumerate() {
+            *o = (0..=i.min(errors))
+                .fold(0, |acc, j| acc ^ mul(loc[j], synd[i - j]));
+        }
+        let omega = &omega[..nsym];
+
+        // Chien search over the byte positions, with Forney for each root
+        let mut fixed = 0;
+        for (idx, byte) in code.iter_mut().enumerate() {
+            let power = n - 1 - idx;
+            let x = alpha(power);
+            let x_inv = alpha(255 - power % 255);
+            if eval(loc, x_inv) != 0 {
+                continue;
+            }
+            // Formal derivative keeps only the odd terms
+            let deriv = (1..loc.len())
+                .step_by(2)
+                .fold(0, |acc, i| acc ^ mul(loc[i], alpha_pow(x_inv, i - 1)));
+            if deriv == 0 {
+                return None;
+            }
+            *byte ^= mul(x, div(eval(omega, x_inv), deriv));
+            fixed += 1;
+        }
+        if fixed != errors {
+            return None;
+        }
+
+        // Anything that still fails the check was miscorrected
+        for i in 0..nsym {
+            let x = alpha(i);
+            if code.iter().fold(0, |acc, &c| mul(acc, x) ^ c) != 0 {
+                return None;
+            }
+        }
+        Some(fixed)
+    }
+
+    fn alpha_pow(x: u8, power: usize) -> u8 {
+        if x == 0 {
+            return 0;
+        }
+        alpha(GF.log[x as usize] as usize * power)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes of a message followed by room for `nsym` parity bytes
+    fn codeword(len: usize, nsym: usize) -> std::vec::Vec<u8> {
+        let mut code: std::vec::Vec<u8> =
+            (0..len).map(|i| (i * 73 + 41) as u8).collect();
+        code.resize(len + nsym, 0);
+        let (msg, parity) = code.split_at_mut(len);
+        rs::encode(msg, parity);
+        code
+    }
+
+    /// Hits `count` bytes spread over the codeword
+    fn corrupt(code: &mut [u8], count: usize) {
+        let step = code.len() / count;
+        for i in 0..count {
+            code[i * step] ^= 0x5A ^ i as u8;
+        }
+    }
+
+    #[test]
+    fn clean_codewords_decode_untouched() {
+        for rate in [Rate::Light, Rate::Medium, Rate::Strong] {
+            let nsym = rate.parity();
+            let mut code = codeword(100, nsym);
+            let sent = code.clone();
+            assert_eq!(rs::decode(&mut code, nsym), Some(0));
+            assert_eq!(code, sent);
+        }
+    }
+
+    #[test]
+    fn repairs_up_to_half_the_parity() {
+        for rate in [Rate::Light, Rate::Medium, Rate::Strong] {
+            let nsym = rate.parity();
+            for count in 1..=nsym / 2 {
+                let sent = codeword(120, nsym);
+                let mut code = sent.clone();
+                corrupt(&mut code, count);
+                assert_eq!(rs::decode(&mut code, nsym), Some(count));
+                assert_eq!(code, sent);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_more_errors_than_it_can_repair() {
+        for rate in [Rate::Light, Rate::Medium, Rate::Strong] {
+            let nsym = rate.parity();
+            let mut code = codeword(120, nsym);
+            corrupt(&mut code, nsym / 2 + 1);
+            assert_eq!(rs::decode(&mut code, nsym), None);
+        }
+    }
+
+    #[test]
+    fn short_and_full_length_codewords() {
+        let nsym = Rate::Medium.parity();
+        for len in [1, 2, 17, MAX_CODEWORD - nsym] {
+            let sent = codeword(len, nsym);
+            let mut code = sent.clone();
+            corrupt(&mut code, nsym / 2);
+            assert!(rs::decode(&mut code, nsym).is_some());
+            assert_eq!(code, sent);
+        }
+        // A hit in the parity alone is repaired just the same
+        let sent = codeword(1, nsym);
+        let mut code = sent.clone();
+        code[nsym] ^= 0xFF;
+        assert_eq!(rs::decode(&mut code, nsym), Some(1));
+        assert_eq!(code, sent);
+
+        let mut long = [0; MAX_CODEWORD + 1];
+        assert_eq!(rs::decode(&mut long, nsym), None);
+    }
+
+    #[test]
+    fn frames_lose_their_parity_once_repaired() {
+        mem::grow_for_tests();
+        let mut fec = Fec {
+            rate: Rate::Medium,
+            ..Fec::new()
+        };
+        let mut buf = mem::VecBuf::new();
+        buf.resize(60, 0x42).unwrap();
+        fec.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), 60 + Rate::Medium.parity());
+
+        let mut frame = st7580::Frame::default();
+        frame.data.resize(HEADER_IDX, 0).unwrap();
+        frame.data.extend_from_slice(&buf).unwrap();
+        frame.length = frame.data.len() as u8;
+        frame.data[HEADER_IDX + 9] ^= 0x10;
+        frame.data[HEADER_IDX + 50] ^= 0x01;
+        assert_eq!(fec.decode(&mut frame), Ok(()));
+        assert_eq!(frame.data[HEADER_IDX..], buf[..60]);
+        assert_eq!(frame.length as usize, HEADER_IDX + 60);
+        assert_eq!(fec.corrected, 2);
+
+        let mut short = st7580::Frame::default();
+        short.data.resize(HEADER_IDX + 4, 0).unwrap();
+        short.data[HEADER_IDX] = buf[0];
+        short.length = short.data.len() as u8;
+        assert_eq!(fec.decode(&mut short), Err(FecError::Truncated));
+        assert_eq!(fec.uncorrectable, 1);
+    }
+}
@@ -1,5 +1,14 @@
-use super::{Channels, Header, DATA_OPT, DATA_START, HEADER_IDX};
-use crate::{mem, st7580, usb};
+use super::{
+    election::{Election, Outcome},
+    frame::FrameError,
+    host, link,
+    neighbor::{Address, NeighborTable, NodeInfo, Role},
+    role::Parts,
+    session::Session,
+    tunnel::Tunnel,
+    Header, LinkHeader, DATA_OPT, DATA_START,
+};
+use crate::{mem, st7580};
 use stm32f4xx_hal::timer::{self, DelayUs};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -10,101 +19,261 @@ enum State {
 
 pub struct Follower<const TWO_WAY: bool> {
     state: State,
-    driver: st7580::Driver,
-    sender: st7580::DSender,
-    channels: Channels,
+    tunnel: Tunnel,
+    node: NodeInfo,
+    /// Frames the leader's last ping still allows us to send
+    grant: u8,
+    /// Frames of the burst sent since the leader's last ping
+    burst_sent: u8,
+    silence_timeout: st7580::Timeout,
+    election: Option<Election>,
 }
 
 impl<const TWO_WAY: bool> Follower<TWO_WAY> {
-    pub fn new(
-        driver: st7580::Driver,
-        sender: st7580::DSender,
-        in_producer: usb::UsbProducer,
-        out_consumer: usb::UsbConsumer,
-    ) -> Self {
+    pub fn new(parts: Parts) -> Self {
+        let (tunnel, mut election) =
+            Tunnel::new(parts, Role::Follower, TWO_WAY);
+        if let Some(election) = &mut election {
+            election.follow();
+        }
+        let mut silence_timeout = st7580::Timeout::default();
+        silence_timeout.set(link::SILENCE_PERIOD);
         Self {
             state: State::Wait,
-            driver,
-            sender,
-            channels: Channels {
-                in_producer,
-                out_consumer,
-            },
+            tunnel,
+            node: NodeInfo::local(Role::Follower, TWO_WAY),
+            grant: 0,
+            burst_sent: 0,
+            silence_timeout,
+            election,
+        }
+    }
+
+    /// Gives up the hardware and queues for the next role
+    pub fn into_parts(self) -> Parts {
+        self.tunnel.into_parts(self.election)
+    }
+
+    /// Where the election stands, only ever changing between frames
+    pub fn election(&mut self) -> Outcome {
+        match &mut self.election {
+            Some(election) if self.state == State::Wait => election.poll(),
+            _ => Outcome::Stay,
         }
     }
 
     pub fn init<TIM: timer::Instance>(&mut self, delay: &mut DelayUs<TIM>) {
-        super::shared_init(delay, &mut self.driver, &mut self.sender)
+        self.tunnel.init(delay)
+    }
+
+    pub fn neighbors(&self) -> &NeighborTable {
+        &self.tunnel.neighbors
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.tunnel.session
+    }
+
+    pub fn link_state(&self) -> link::LinkState {
+        self.tunnel.link.state()
+    }
+
+    /// Counts a miss for every silence period without hearing the leader
+    fn poll_silence(&mut self) {
+        if !self.silence_timeout.is_expired() {
+            return;
+        }
+        self.silence_timeout.set(link::SILENCE_PERIOD);
+        self.tunnel.link_missed();
+    }
+
+    fn poll_host(&mut self) {
+        let Some((cmd, payload)) = self.tunnel.poll_host() else { return };
+        let tunnel = &mut self.tunnel;
+        match cmd {
+            // Followers are paired by their leader, this only picks which
+            cmd @ host::Command::Pair => match payload[..] {
+                [a0, a1, ..] => {
+                    let leader = Address::from_le_bytes([a0, a1]);
+                    tunnel.session.allow(Some(leader));
+                    tunnel.host.ack(cmd);
+                }
+                _ => tunnel.host.error(cmd as u8),
+            },
+            host::Command::Loopback if payload.is_empty() => {
+                tunnel.host.send_loopback(tunnel.remote.is_looping_back())
+            }
+            // Followers only ever speak when spoken to, and their leader
+            // discovers, polls, offers settings, streams images, runs
+            // benchmarks and switches loopback. Nothing gets back to the
+            // leader in one-way mode or unpaired.
+            cmd => tunnel.host.error(cmd as u8),
+        }
+    }
+
+    /// Takes up the network offered by a join addressed to this node, when
+    /// `Session::welcomes` it
+    fn join(&mut self, hdr: &LinkHeader, payload: &[u8], from_peer: bool) {
+        let Some(network) = self.tunnel.join_offer(hdr, payload, from_peer)
+        else {
+            return;
+        };
+        self.tunnel.pair(network, hdr.src);
+
+        if TWO_WAY {
+            let hdr = self.tunnel.to_peer(Header::Accept);
+            if let Some(send_buf) =
+                super::link_frame(hdr, &network.to_le_bytes())
+            {
+                self.reply(send_buf, self.tunnel.rate.opts());
+            }
+        }
+    }
+
+    /// Sends the next frame of the burst the leader granted, or an idle frame
+    /// if nothing is waiting when the burst starts
+    fn send_burst(&mut self) {
+        let send_buf = match self.tunnel.next_data() {
+            Some((channel, mut send_buf)) => {
+                self.grant -= 1;
+                let mut hdr = self.tunnel.to_peer(Header::Data);
+                hdr.channel = channel;
+                hdr.more = self.grant > 0 && self.tunnel.sendable();
+                if !hdr.more {
+                    self.grant = 0;
+                }
+                if let Err(_e) =
+                    self.tunnel.pipeline.outgoing(&mut hdr, &mut send_buf)
+                {
+                    crate::dbg::println!("packet dropped {:?}", _e);
+                    self.grant = 0;
+                    return;
+                }
+                super::prepend_header(&mut send_buf, hdr);
+                send_buf
+            }
+            None => {
+                self.grant = 0;
+                let hdr = self.tunnel.to_peer(Header::Idle);
+                let Some(send_buf) = super::link_frame(hdr, &[]) else {
+                    return;
+                };
+                send_buf
+            }
+        };
+        self.reply(send_buf, self.tunnel.rate.opts());
+        if self.state == State::Send {
+            self.burst_sent = self.burst_sent.saturating_add(1);
+        }
+    }
+
+    /// Sends a frame with the transmission options `opts`
+    fn reply(&mut self, send_buf: mem::BufBox, opts: u8) {
+        if self.tunnel.transmit(send_buf, opts) {
+            self.state = State::Send;
+        }
     }
 
     pub fn process(&mut self) {
+        self.poll_host();
+        self.tunnel.poll();
+        self.poll_silence();
+        let tunnel = &mut self.tunnel;
+        if tunnel.clock.request_due() && TWO_WAY && tunnel.session.is_paired() {
+            tunnel.remote.time_request();
+        }
+        if self.state == State::Wait {
+            self.tunnel.poll_idle();
+        }
+
         match self.state {
             State::Wait => {
-                let Some(f) = self.driver.receive_frame() else { return };
-                debug_assert!(matches!(f.stx, st7580::STX_03 | st7580::STX_02));
-                if f.length == 0 {
-                    crate::dbg::println!("received zero size packet {:?}", f);
+                let Some((f, hdr, from_peer)) = self.tunnel.receive() else {
                     return;
+                };
+                if from_peer {
+                    self.silence_timeout.set(link::SILENCE_PERIOD);
+                    if let Some(election) = &mut self.election {
+                        election.leader_heard();
+                    }
                 }
-                let header = f.data[HEADER_IDX].try_into().unwrap();
-                match header {
-                    Header::Idle => panic!("Unexpected Idle from leader"),
-                    Header::Data => {
-                        let len = f.length as usize;
-                        let mut data = f.data;
-                        data.copy_within(DATA_START..len, 0);
-                        data.truncate(len - DATA_START);
-                        if let Err(_data) =
-                            self.channels.in_producer.enqueue(data)
+                match hdr.kind {
+                    // Keepalive from a one-way leader
+                    Header::Idle => {}
+                    Header::Data if from_peer => {
+                        self.tunnel.data_received(&hdr, f);
+                    }
+                    Header::Ping if TWO_WAY && from_peer => {
+                        // Leaders that predate grants allow a single frame
+                        let grant = f.data.get(DATA_START).copied();
+                        self.grant = grant.unwrap_or(1).max(1);
+                        // and do not tell how much of the last burst came
+                        if let Some(&heard) = f.data.get(DATA_START + 1) {
+                            self.tunnel.burst_heard(self.burst_sent, heard);
+                        }
+                        self.burst_sent = 0;
+                        self.send_burst();
+                    }
+                    // The leader we unpaired from is told until it stops
+                    Header::Ping
+                        if TWO_WAY
+                            && self.tunnel.session.is_from_left(&hdr) =>
+                    {
+                        if let Some(send_buf) = self.tunnel.leave_frame() {
+                            self.reply(send_buf, DATA_OPT);
+                        }
+                    }
+                    // Pings from anyone but our leader, or any ping in
+                    // one-way mode where we cannot answer
+                    Header::Ping => {
+                        FrameError::Unexpected.record()
+                    }
+                    Header::Discover if TWO_WAY => {
+                        let session = &self.tunnel.session;
+                        let hdr = session.header(Header::Announce, hdr.src);
+                        if let Some(send_buf) =
+                            super::announce_frame(hdr, &self.node)
                         {
-                            crate::dbg::println!(
-                                "IN Producer is full, packet dropped"
-                            );
+                            self.reply(send_buf, DATA_OPT);
                         }
                     }
-                    Header::Ping if TWO_WAY => {
-                        let receive_opt = self.channels.out_consumer.dequeue();
-                        let send_buf = match receive_opt {
-                            Some(mut send_buf) => {
-                                send_buf.push(Header::Data.into()).unwrap();
-                                send_buf.rotate_right(1);
-                                send_buf
-                            }
-                            None => {
-                                mem::alloc_from_slice(&[Header::Idle.into()])
-                                    .unwrap()
-                            }
-                        };
-                        if let Err(e) = self
-                            .driver
-                            // .phy_data(DATA_OPT, send_buf)
-                            .dl_data(DATA_OPT, send_buf)
-                            .and_then(|tag| self.sender.enqueue(tag))
+                    Header::Discover => {}
+                    Header::Announce => {
+                        let info = self.tunnel.neighbors.record(&f);
+                        // Only a leader we could follow puts off a takeover
+                        if let (Some(election), Some(info)) =
+                            (&mut self.election, info)
                         {
-                            crate::dbg::println!("data error {:?}", e);
-                            self.state = State::Wait;
+                            if info.role == Role::Leader
+                                && hdr.network == self.tunnel.session.network()
+                            {
+                                election.leader_heard();
+                            }
                         }
-                        self.state = State::Send;
                     }
-                    Header::Ping => panic!("Recieved ping during one-way mode"),
+                    Header::Join => {
+                        let payload =
+                            f.data.get(DATA_START..).unwrap_or_default();
+                        self.join(&hdr, payload, from_peer);
+                    }
+                    Header::Data | Header::Accept | Header::Ack => {}
                 }
             }
-            State::Send if !TWO_WAY => {
-                panic!("Reached send during one-way mode")
-            }
-            State::Send => match self.sender.process() {
-                Ok(()) => self.state = State::Wait,
-                Err(st7580::NbStErr::WouldBlock) => {}
-                Err(st7580::NbStErr::Other(st7580::StErr::TxErrNoStatus)) => {
-                    crate::dbg::println!("plm did not return status");
+            State::Send => match self.tunnel.poll_sent() {
+                None => {}
+                Some(true) => {
                     self.state = State::Wait;
+                    if self.grant > 0 {
+                        self.send_burst();
+                    }
                 }
-                Err(st7580::NbStErr::Other(st7580::StErr::TxErrAckTmo)) => {
-                    crate::dbg::println!("plm ack timed out");
+                // A frame lost mid burst does not end it, the next one goes
+                // out in its place
+                Some(false) => {
                     self.state = State::Wait;
-                }
-                Err(st7580::NbStErr::Other(e)) => {
-                    panic!("Ping processing error: {:?}", e)
+                    if self.grant > 0 {
+                        self.send_burst();
+                    }
                 }
             },
         }
@@ -0,0 +1,50 @@
+//! Checks of received link frames before any state machine acts on them
+//!
+//! Anything on the wire may reach us, from a neighbour running other firmware
+//! to a frame mangled past what `fec` can tell. Frames are parsed into a
+//! `LinkHeader` only once their length is known to hold it, and frames that
+//! are well formed but make no sense to the receiving state machine are
+//! turned away the same way. Either way the frame is dropped and counted,
+//! and the node carries on.
+
+use super::{LinkHeader, DATA_START};
+use crate::{st7580, stats::STATS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is too short to hold a link header
+    Truncated,
+    /// The frame claims more bytes than were received
+    BadLength,
+    /// The kind byte names no known header
+    UnknownKind(u8),
+    /// The header is valid but not one this node expects in its state
+    Unexpected,
+}
+
+impl FrameError {
+    /// Counts a dropped frame
+    pub fn record(self) {
+        crate::dbg::println!("frame dropped {:?}", self);
+        let errors = &STATS.frame_errors;
+        match self {
+            FrameError::Truncated => errors.truncated.inc(),
+            FrameError::BadLength => errors.bad_length.inc(),
+            FrameError::UnknownKind(_) => errors.unknown_kind.inc(),
+            FrameError::Unexpected => errors.unexpected.inc(),
+        }
+    }
+}
+
+/// Reads the link header of a received frame, making sure the length the
+/// modem reported covers the header and lies within the received bytes
+pub(super) fn parse(frame: &st7580::Frame) -> Result<LinkHeader, FrameError> {
+    let len = frame.length as usize;
+    if len > frame.data.len() {
+        return Err(FrameError::BadLength);
+    }
+    if len < DATA_START {
+        return Err(FrameError::Truncated);
+    }
+    LinkHeader::parse(&frame.data[..len])
+}
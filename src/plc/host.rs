@@ -0,0 +1,369 @@
+//! Management messages exchanged with the USB host
+//!
+//! Each message is one packet on the control interface. The first byte is
+//! the message kind and the rest is its payload. Commands come from the host
+//! and every command is answered by one or more replies. Events are sent
+//! unprompted whenever something the host should know about happens.
+//! Packets of kind `usb::CHANNEL_DATA` carry tunnel channels instead and are
+//! routed by `usb::UsbManager` before they get here.
+
+use super::{
+    bench::Bench,
+    compress::Compression,
+    fec::Fec,
+    integrity::Integrity,
+    link::LinkState,
+    modem::{self, Modem},
+    neighbor::{NeighborTable, Role},
+    qos::Qos,
+    rate::Rate,
+    remote::Report,
+    role,
+    schedule::Scheduler,
+    session::Session,
+    time::Clock,
+};
+use crate::{mem, st7580, stats::STATS, usb};
+
+pub enum Command {
+    /// Dump the neighbor table
+    Neighbors = 0x01,
+    /// Ask the nodes on the wire to announce themselves
+    Discover = 0x02,
+    /// Report the current link state
+    LinkState = 0x03,
+    /// Pair with the follower whose address is the payload, leader only
+    Pair = 0x04,
+    /// Forget the current pairing
+    Unpair = 0x05,
+    /// Report the current pairing
+    Session = 0x06,
+    /// Set the pre-shared key to the 32 byte payload, an empty payload turns
+    /// encryption off
+    SetKey = 0x07,
+    /// Report the compression counters
+    Compression = 0x08,
+    /// Report the error correction counters, a one byte payload first sets
+    /// the rate of sent frames
+    Fec = 0x09,
+    /// Report the end-to-end message check counters
+    Integrity = 0x0A,
+    /// Report the leader's polling schedule, a payload of new settings sets
+    /// it first, two-way leader only
+    Schedule = 0x0B,
+    /// Report the priority class of each channel, a payload of new settings
+    /// sets them first
+    Qos = 0x0C,
+    /// Report the role of the node, a one byte payload saves a new role and
+    /// reboots into it
+    Role = 0x0D,
+    /// Report the counters of received frames dropped as malformed or
+    /// unexpected
+    Frames = 0x0E,
+    /// Report the link statistics
+    Stats = 0x0F,
+    /// Ask the peer for its statistics and health, answered by the peer's
+    /// report once it arrives, two-way only
+    Remote = 0x10,
+    /// Report the modem settings, a payload of new settings offers them to
+    /// the follower first, paired two-way leader only
+    Modem = 0x11,
+    /// Pass an update message on to the follower, answered by the follower's
+    /// state once it arrives, paired two-way leader only
+    Update = 0x12,
+    /// Report the time of the leader as known on this node
+    Time = 0x13,
+    /// Report the last benchmark run, a payload of benchmark settings starts
+    /// a new one, answered again once it is over, paired two-way leader only
+    Bench = 0x14,
+    /// Report whether the follower sends data back instead of handing it to
+    /// its host, a `[on]` payload turns that on or off. The leader asks its
+    /// follower and answers once it does, paired two-way only.
+    Loopback = 0x15,
+    /// Report the modulation of frames to the peer and the modulations each
+    /// side receives, a `[modes]` payload restricts ours first and tells the
+    /// peer in two-way mode, leader or follower only
+    Rate = 0x16,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Command::*;
+        match v {
+            0x01 => Ok(Neighbors),
+            0x02 => Ok(Discover),
+            0x03 => Ok(LinkState),
+            0x04 => Ok(Pair),
+            0x05 => Ok(Unpair),
+            0x06 => Ok(Session),
+            0x07 => Ok(SetKey),
+            0x08 => Ok(Compression),
+            0x09 => Ok(Fec),
+            0x0A => Ok(Integrity),
+            0x0B => Ok(Schedule),
+            0x0C => Ok(Qos),
+            0x0D => Ok(Role),
+            0x0E => Ok(Frames),
+            0x0F => Ok(Stats),
+            0x10 => Ok(Remote),
+            0x11 => Ok(Modem),
+            0x12 => Ok(Update),
+            0x13 => Ok(Time),
+            0x14 => Ok(Bench),
+            0x15 => Ok(Loopback),
+            0x16 => Ok(Rate),
+            v => Err(v),
+        }
+    }
+}
+
+pub enum Reply {
+    /// Command was accepted, payload is the command kind
+    Ack = 0x80,
+    /// One neighbor table entry, payload is `[index, count, entry..]`
+    Neighbor = 0x81,
+    /// Current link state, payload is `[state]`
+    LinkState = 0x82,
+    /// Current pairing, payload is `[network, peer]` with a zero network
+    /// when unpaired
+    Session = 0x83,
+    /// Compression counters, payload is `[tx raw, tx coded, rx raw,
+    /// rx coded]` as 32 bit byte counts
+    Compression = 0x84,
+    /// Error correction state, payload is `[rate, corrected, uncorrectable]`
+    /// with 32 bit counts
+    Fec = 0x85,
+    /// End-to-end check counters, payload is `[passed, failures]` as 32 bit
+    /// message counts
+    Integrity = 0x86,
+    /// Polling schedule, payload is the settings followed by the current
+    /// poll interval
+    Schedule = 0x87,
+    /// Priority settings, payload is the class of each channel followed by
+    /// the interactive weight
+    Qos = 0x88,
+    /// Role of the node, payload is `[current, stored]` with `0xFF` stored
+    /// when the strapping pin and the election decide
+    Role = 0x89,
+    /// Dropped frame counters, payload is `[truncated, bad length, unknown
+    /// kind, unexpected]` as 32 bit frame counts
+    Frames = 0x8A,
+    /// Link statistics, payload is as laid out by `stats::Stats::encode`
+    Stats = 0x8B,
+    /// Statistics of the peer, laid out as for `Reply::Stats`
+    RemoteStats = 0x8C,
+    /// Health of the peer, payload is `[opts, pga, snr, modem status, uptime,
+    /// version]` with the reception of the last frame the peer heard from us
+    /// and its uptime in milliseconds, always following `RemoteStats`
+    RemoteHealth = 0x8D,
+    /// Modem settings, payload is `[phase, settings]` with the settings in
+    /// use as laid out by `modem::Settings::encode`
+    Modem = 0x8E,
+    /// Firmware update state of the follower, payload is `[status, offset]`
+    /// with the next byte of the image it expects
+    Update = 0x8F,
+    /// Leader's time, payload is `[synced, now, drift, round trip]` as laid
+    /// out by `time::Clock::encode`
+    Time = 0x90,
+    /// Benchmark run, payload is `[state, mode, sent, received, goodput,
+    /// latencies, truncated]` as laid out by `bench::Bench::encode`
+    Bench = 0x91,
+    /// Loopback of the follower, payload is `[on]`
+    Loopback = 0x92,
+    /// Modulation, payload is `[modulation, snr, modes, peer modes, peer
+    /// snr]` as laid out by `rate::Rate::encode`
+    Rate = 0x93,
+    /// Command was rejected, payload is the command kind
+    Error = 0xFF,
+}
+
+impl From<Reply> for u8 {
+    fn from(val: Reply) -> Self {
+        val as u8
+    }
+}
+
+pub enum Event {
+    /// The link to the peer changed state, payload is `[state]`
+    LinkChanged = 0xC0,
+    /// The pairing changed, payload is as for `Reply::Session`
+    SessionChanged = 0xC1,
+    /// A change of modem settings went a step further, payload is
+    /// `[outcome, settings]` with the settings now in use
+    ModemChanged = 0xC2,
+}
+
+impl From<Event> for u8 {
+    fn from(val: Event) -> Self {
+        val as u8
+    }
+}
+
+/// Time left for the answer to go out before a reboot in milliseconds
+const REBOOT_DELAY: u32 = 100;
+
+pub(super) struct Host {
+    ctrl_in_producer: usb::ControlProducer,
+    ctrl_out_consumer: usb::ControlConsumer,
+    reboot_timeout: st7580::Timeout,
+}
+
+impl Host {
+    pub(super) fn new(
+        ctrl_in_producer: usb::ControlProducer,
+        ctrl_out_consumer: usb::ControlConsumer,
+    ) -> Self {
+        Self {
+            ctrl_in_producer,
+            ctrl_out_consumer,
+            reboot_timeout: Default::default(),
+        }
+    }
+
+    /// Takes the next command from the host along with its payload
+    pub(super) fn next_command(
+        &mut self,
+    ) -> Option<(Result<Command, u8>, mem::BufBox)> {
+        if self.reboot_timeout.is_expired() {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        let mut msg = self.ctrl_out_consumer.dequeue()?;
+        usb::wake();
+        let &kind = msg.first()?;
+        msg.remove(0);
+        Some((kind.try_into(), msg))
+    }
+
+    pub(super) fn send(&mut self, kind: u8, payload: &[u8]) {
+        debug_assert!(payload.len() < usb::CONTROL_PACKET_SIZE);
+        let Some(mut msg) = mem::alloc() else {
+            crate::dbg::println!("no buffer for host message");
+            return;
+        };
+        msg.push(kind).unwrap();
+        msg.extend_from_slice(payload).unwrap();
+        if let Err(_msg) = self.ctrl_in_producer.enqueue(msg) {
+            crate::dbg::println!("The control reply queue is full");
+            STATS.queue_drops.inc();
+        }
+        usb::wake();
+    }
+
+    pub(super) fn ack(&mut self, cmd: Command) {
+        self.send(Reply::Ack.into(), &[cmd as u8]);
+    }
+
+    pub(super) fn error(&mut self, cmd: u8) {
+        self.send(Reply::Error.into(), &[cmd]);
+    }
+
+    pub(super) fn send_link_state(&mut self, state: LinkState) {
+        self.send(Reply::LinkState.into(), &[state.into()]);
+    }
+
+    pub(super) fn link_changed(&mut self, state: LinkState) {
+        self.send(Event::LinkChanged.into(), &[state.into()]);
+    }
+
+    pub(super) fn send_session(&mut self, session: &Session) {
+        self.send(Reply::Session.into(), &session.encode());
+    }
+
+    pub(super) fn session_changed(&mut self, session: &Session) {
+        self.send(Event::SessionChanged.into(), &session.encode());
+    }
+
+    pub(super) fn send_compression(&mut self, compression: &Compression) {
+        self.send(Reply::Compression.into(), &compression.encode());
+    }
+
+    pub(super) fn send_fec(&mut self, fec: &Fec) {
+        self.send(Reply::Fec.into(), &fec.encode_counters());
+    }
+
+    pub(super) fn send_integrity(&mut self, integrity: &Integrity) {
+        self.send(Reply::Integrity.into(), &integrity.encode());
+    }
+
+    pub(super) fn send_frame_errors(&mut self) {
+        self.send(Reply::Frames.into(), &STATS.encode_frames());
+    }
+
+    pub(super) fn send_stats(&mut self) {
+        self.send(Reply::Stats.into(), &STATS.encode());
+    }
+
+    pub(super) fn send_remote(&mut self, report: &Report) {
+        self.send(Reply::RemoteStats.into(), &report.stats);
+        self.send(Reply::RemoteHealth.into(), &report.encode_health());
+    }
+
+    pub(super) fn send_modem(&mut self, modem: &Modem) {
+        self.send(Reply::Modem.into(), &modem.encode());
+    }
+
+    pub(super) fn modem_changed(
+        &mut self,
+        outcome: modem::Outcome,
+        modem: &Modem,
+    ) {
+        let mut payload = modem.encode();
+        payload[0] = outcome.into();
+        self.send(Event::ModemChanged.into(), &payload);
+    }
+
+    pub(super) fn send_update(&mut self, state: &[u8]) {
+        self.send(Reply::Update.into(), state);
+    }
+
+    pub(super) fn send_time(&mut self, clock: &Clock) {
+        self.send(Reply::Time.into(), &clock.encode());
+    }
+
+    pub(super) fn send_bench(&mut self, bench: &Bench) {
+        self.send(Reply::Bench.into(), &bench.encode());
+    }
+
+    pub(super) fn send_loopback(&mut self, on: bool) {
+        self.send(Reply::Loopback.into(), &[on.into()]);
+    }
+
+    pub(super) fn send_rate(&mut self, rate: &Rate) {
+        self.send(Reply::Rate.into(), &rate.encode());
+    }
+
+    pub(super) fn send_schedule(&mut self, scheduler: &Scheduler) {
+        self.send(Reply::Schedule.into(), &scheduler.encode());
+    }
+
+    pub(super) fn send_qos(&mut self, qos: &Qos) {
+        self.send(Reply::Qos.into(), &qos.settings().encode());
+    }
+
+    pub(super) fn send_role(&mut self, current: Role, stored: Option<u8>) {
+        let stored = stored.unwrap_or(role::ROLE_STRAPPED);
+        self.send(Reply::Role.into(), &[current.into(), stored]);
+    }
+
+    /// Reboots the node once the replies queued so far had time to go out
+    pub(super) fn schedule_reboot(&mut self) {
+        self.reboot_timeout.set(REBOOT_DELAY);
+    }
+
+    /// Replies with one message per entry, or a lone count when empty
+    pub(super) fn send_neighbors(&mut self, table: &NeighborTable) {
+        let now = st7580::now_us();
+        let count = table.len() as u8;
+        if table.is_empty() {
+            self.send(Reply::Neighbor.into(), &[0, count]);
+        }
+        for (idx, n) in table.iter().enumerate() {
+            let mut payload = mem::VecBuf::new();
+            payload.extend_from_slice(&[idx as u8, count]).unwrap();
+            n.encode(now, &mut payload);
+            self.send(Reply::Neighbor.into(), &payload);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! End-to-end check of tunnel messages
+//!
+//! The modem only checksums each hop over its UART, so nothing catches a
+//! message corrupted in our own queues and pool buffers or miscorrected by
+//! `fec`. Every message from the host gets a CRC-32 appended before any other
+//! layer touches it, and the far side checks it after every other layer is
+//! undone, right before the message is handed to its host.
+
+use crate::{mem, util};
+
+/// Bytes added to every message
+pub const CRC_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The buffer has no room left for the CRC
+    Overflow,
+    /// The message is too short to hold a CRC
+    Truncated,
+    /// The CRC does not match the message
+    Mismatch,
+}
+
+#[derive(Debug, Default)]
+pub struct Integrity {
+    /// Received messages whose CRC matched
+    pub passed: u32,
+    /// Received messages dropped because their CRC did not match
+    pub failures: u32,
+}
+
+impl Integrity {
+    pub const fn new() -> Self {
+        Self {
+            passed: 0,
+            failures: 0,
+        }
+    }
+
+    /// Appends the CRC of the message in `buf`
+    pub(super) fn append(
+        &self,
+        buf: &mut mem::VecBuf,
+    ) -> Result<(), IntegrityError> {
+        let crc = util::crc32(buf);
+        buf.extend_from_slice(&crc.to_le_bytes())
+            .map_err(|_| IntegrityError::Overflow)
+    }
+
+    /// Checks and strips the CRC ending the message in `buf`
+    pub(super) fn check(
+        &mut self,
+        buf: &mut mem::VecBuf,
+    ) -> Result<(), IntegrityError> {
+        let Some(len) = buf.len().checked_sub(CRC_LEN) else {
+            self.failures = self.failures.wrapping_add(1);
+            return Err(IntegrityError::Truncated);
+        };
+        let stored = u32::from_le_bytes(buf[len..].try_into().unwrap());
+        if util::crc32(&buf[..len]) != stored {
+            self.failures = self.failures.wrapping_add(1);
+            return Err(IntegrityError::Mismatch);
+        }
+        self.passed = self.passed.wrapping_add(1);
+        buf.truncate(len);
+        Ok(())
+    }
+
+    /// Encodes the counters for the host as `[passed, failures]`
+    pub fn encode(&self) -> [u8; 8] {
+        let mut buf = [0; 8];
+        buf[..4].copy_from_slice(&self.passed.to_le_bytes());
+        buf[4..].copy_from_slice(&self.failures.to_le_bytes());
+        buf
+    }
+}
@@ -1,5 +1,16 @@
-use super::{Channels, Header, DATA_OPT, DATA_START, HEADER_IDX};
-use crate::{mem, st7580, usb};
+use super::{
+    bench,
+    election::{Election, Outcome},
+    frame::FrameError,
+    host, link, modem,
+    neighbor::{self, Address, NeighborTable, NodeInfo, Role},
+    role::Parts,
+    schedule::{self, Scheduler},
+    session::{self, Session},
+    tunnel::Tunnel,
+    Header, LinkHeader, DATA_OPT, DATA_START,
+};
+use crate::{mem, st7580, stats::STATS};
 use stm32f4xx_hal::timer::{self, DelayUs};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -12,117 +23,377 @@ enum State {
 
 pub struct Leader<const TWO_WAY: bool> {
     state: State,
-    driver: st7580::Driver,
-    sender: st7580::DSender,
-    channels: Channels,
+    tunnel: Tunnel,
+    node: NodeInfo,
+    discover_pending: bool,
+    join_target: Option<Address>,
+    scheduler: Scheduler,
     ping_timeout: st7580::Timeout,
+    /// When the ping being answered went out in microseconds, until its
+    /// first answer
+    ping_sent: Option<u32>,
     fail_timeout: st7580::Timeout,
+    announce_timeout: st7580::Timeout,
+    keepalive_timeout: st7580::Timeout,
+    election: Option<Election>,
 }
 
 impl<const TWO_WAY: bool> Leader<TWO_WAY> {
-    pub fn new(
-        driver: st7580::Driver,
-        sender: st7580::DSender,
-        in_producer: usb::UsbProducer,
-        out_consumer: usb::UsbConsumer,
-    ) -> Self {
+    pub fn new(parts: Parts) -> Self {
+        let (tunnel, mut election) = Tunnel::new(parts, Role::Leader, TWO_WAY);
+        if let Some(election) = &mut election {
+            election.lead();
+        }
         let mut fail_timeout = st7580::Timeout::default();
         fail_timeout.set(1);
+        let mut announce_timeout = st7580::Timeout::default();
+        announce_timeout.set(1);
+        let mut keepalive_timeout = st7580::Timeout::default();
+        keepalive_timeout.set(link::KEEPALIVE_PERIOD);
         Self {
             state: State::Dispatch,
-            driver,
-            sender,
-            channels: Channels {
-                in_producer,
-                out_consumer,
-            },
+            tunnel,
+            node: NodeInfo::local(Role::Leader, TWO_WAY),
+            discover_pending: false,
+            join_target: None,
+            scheduler: Scheduler::new(),
             ping_timeout: Default::default(),
+            ping_sent: None,
             fail_timeout,
+            announce_timeout,
+            keepalive_timeout,
+            election,
+        }
+    }
+
+    /// Gives up the hardware and queues for the next role
+    pub fn into_parts(self) -> Parts {
+        self.tunnel.into_parts(self.election)
+    }
+
+    /// Where the election stands, only ever changing between frames
+    pub fn election(&mut self) -> Outcome {
+        match &mut self.election {
+            Some(election) if self.state == State::Dispatch => election.poll(),
+            _ => Outcome::Stay,
         }
     }
 
     pub fn init<TIM: timer::Instance>(&mut self, delay: &mut DelayUs<TIM>) {
-        super::shared_init(delay, &mut self.driver, &mut self.sender)
+        self.tunnel.init(delay)
+    }
+
+    pub fn neighbors(&self) -> &NeighborTable {
+        &self.tunnel.neighbors
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.tunnel.session
+    }
+
+    /// State of the link, only ever leaves `Down` in two-way mode since a
+    /// one-way leader never hears back from its follower
+    pub fn link_state(&self) -> link::LinkState {
+        self.tunnel.link.state()
+    }
+
+    fn poll_host(&mut self) {
+        let Some((cmd, payload)) = self.tunnel.poll_host() else { return };
+        let tunnel = &mut self.tunnel;
+        let paired = TWO_WAY && tunnel.session.is_paired();
+        match cmd {
+            cmd @ host::Command::Discover if TWO_WAY => {
+                self.discover_pending = true;
+                tunnel.host.ack(cmd);
+            }
+            cmd @ host::Command::Pair => match payload[..] {
+                [a0, a1, ..] => {
+                    self.join_target = Some(Address::from_le_bytes([a0, a1]));
+                    tunnel.host.ack(cmd);
+                }
+                _ => tunnel.host.error(cmd as u8),
+            },
+            cmd @ host::Command::Modem => {
+                match modem::Settings::decode(&payload) {
+                    Some(settings)
+                        if paired && tunnel.modem.offer(settings) =>
+                    {
+                        tunnel.remote.configure(settings, modem::SWITCH_DELAY)
+                    }
+                    _ => return tunnel.host.error(cmd as u8),
+                }
+                tunnel.host.send_modem(&tunnel.modem);
+            }
+            cmd @ host::Command::Update if paired && !payload.is_empty() => {
+                if !tunnel.remote.update(&payload) {
+                    return tunnel.host.error(cmd as u8);
+                }
+                tunnel.host.ack(cmd);
+            }
+            cmd @ host::Command::Bench => {
+                match bench::Settings::decode(&payload) {
+                    Some(settings)
+                        if paired && tunnel.bench.start(settings) => {}
+                    None if payload.is_empty() => {}
+                    _ => return tunnel.host.error(cmd as u8),
+                }
+                tunnel.host.send_bench(&tunnel.bench);
+            }
+            cmd @ host::Command::Loopback if paired => {
+                let queued = match payload[..] {
+                    [] => tunnel.remote.loopback(None),
+                    [on @ (0 | 1)] => tunnel.remote.loopback(Some(on != 0)),
+                    _ => false,
+                };
+                if !queued {
+                    return tunnel.host.error(cmd as u8);
+                }
+                tunnel.host.ack(cmd);
+            }
+            cmd @ host::Command::Schedule if TWO_WAY => {
+                match schedule::Settings::decode(&payload) {
+                    Some(settings) => self.scheduler.configure(settings),
+                    None if payload.is_empty() => {}
+                    None => return tunnel.host.error(cmd as u8),
+                }
+                tunnel.host.send_schedule(&self.scheduler);
+            }
+            cmd => tunnel.host.error(cmd as u8),
+        }
+    }
+
+    fn management_due(&self) -> bool {
+        self.discover_pending
+            || self.join_target.is_some()
+            || self.tunnel.session.has_left()
+            || self.announce_timeout.is_expired()
+    }
+
+    /// Builds the pending leave notice, join, discovery or announcement
+    fn management_frame(&mut self) -> Option<mem::BufBox> {
+        if self.tunnel.session.has_left() {
+            let notice = self.tunnel.leave_frame();
+            self.tunnel.session.forget_left();
+            self.state = State::SendData;
+            return notice;
+        }
+        let session = &self.tunnel.session;
+        if let Some(target) = self.join_target {
+            let network = session.own_network();
+            let mut hdr = session.header(Header::Join, target);
+            hdr.network = network;
+            if TWO_WAY {
+                self.state = State::SendPing;
+            } else {
+                // Nothing will come back so the pairing is taken as made
+                self.join_target = None;
+                self.tunnel.pair(network, target);
+                self.state = State::SendData;
+            }
+            return super::link_frame(hdr, &network.to_le_bytes());
+        }
+        if self.discover_pending {
+            self.discover_pending = false;
+            self.state = State::SendPing;
+            let hdr = session.header(Header::Discover, session::BROADCAST);
+            return super::link_frame(hdr, &[]);
+        }
+        if self.announce_timeout.is_expired() {
+            self.announce_timeout.set(neighbor::ANNOUNCE_PERIOD);
+            // An elected leader looks for a follower until it has one
+            self.discover_pending =
+                TWO_WAY && self.election.is_some() && !session.is_paired();
+            self.state = State::SendData;
+            let hdr = session.header(Header::Announce, session::BROADCAST);
+            return super::announce_frame(hdr, &self.node);
+        }
+        None
+    }
+
+    /// Weighs an announcement heard by an elected leader. Rivals count when
+    /// they lead our network, or when neither side is paired, and an unpaired
+    /// leader pairs with the first unpaired follower that answers.
+    fn announce_heard(&mut self, hdr: &LinkHeader, info: NodeInfo) {
+        let Some(election) = &mut self.election else { return };
+        let session = &self.tunnel.session;
+        if hdr.network != session.network() {
+            return;
+        }
+        match info.role {
+            Role::Leader => election.rival_heard(info.addr),
+            Role::Follower
+                if !session.is_paired() && self.join_target.is_none() =>
+            {
+                self.join_target = Some(info.addr);
+            }
+            Role::Follower | Role::Peer => {}
+        }
+    }
+
+    /// Sends a frame with the transmission options `opts`, leaving the modem
+    /// alone for a while when it does not take it
+    fn transmit(&mut self, send_buf: mem::BufBox, opts: u8) {
+        if !self.tunnel.transmit(send_buf, opts) {
+            self.fail_timeout.set(self.scheduler.retry_delay());
+            self.state = State::Dispatch;
+        }
     }
 
     pub fn process(&mut self) {
+        self.poll_host();
+        self.tunnel.poll();
+        if self.state == State::Dispatch {
+            self.tunnel.poll_idle();
+        }
+
         match self.state {
             State::Dispatch if !self.fail_timeout.is_expired() => {
                 // Wait for the plm to get back
             }
+            State::Dispatch if self.management_due() => {
+                let Some(send_buf) = self.management_frame() else { return };
+                self.transmit(send_buf, DATA_OPT);
+            }
+            State::Dispatch if !self.tunnel.session.is_paired() => {
+                // No data flows before pairing
+            }
             State::Dispatch => {
-                let receive_opt = self.channels.out_consumer.dequeue();
+                // Keepalives go out even while data is waiting
+                let keepalive_due = self.keepalive_timeout.is_expired();
+                // Data the follower has no room for waits for a regular poll
+                let sendable = self.tunnel.sendable();
+                let poll = TWO_WAY
+                    && (keepalive_due || self.scheduler.poll_due(sendable));
+                let receive_opt =
+                    if poll { None } else { self.tunnel.next_data() };
 
                 let send_buf = match receive_opt {
-                    Some(mut send_buf) => {
+                    Some((channel, mut send_buf)) => {
+                        let mut hdr = self.tunnel.to_peer(Header::Data);
+                        hdr.channel = channel;
+                        if let Err(_e) = self
+                            .tunnel
+                            .pipeline
+                            .outgoing(&mut hdr, &mut send_buf)
+                        {
+                            crate::dbg::println!("packet dropped {:?}", _e);
+                            return;
+                        }
                         self.state = State::SendData;
-                        send_buf.push(Header::Data.into()).unwrap();
-                        send_buf.rotate_right(1);
+                        self.scheduler.sent_data();
+                        super::prepend_header(&mut send_buf, hdr);
                         send_buf
                     }
-                    None if TWO_WAY => {
+                    None if poll => {
+                        let payload = self.scheduler.start_poll();
+                        let hdr = self.tunnel.to_peer(Header::Ping);
+                        let Some(send_buf) = super::link_frame(hdr, &payload)
+                        else {
+                            self.scheduler.end_poll();
+                            return;
+                        };
                         self.state = State::SendPing;
-                        mem::alloc_from_slice(&[Header::Ping.into()]).unwrap()
+                        self.ping_sent = Some(st7580::now_us());
+                        send_buf
+                    }
+                    None if keepalive_due => {
+                        let hdr = self.tunnel.to_peer(Header::Idle);
+                        let Some(send_buf) = super::link_frame(hdr, &[]) else {
+                            return;
+                        };
+                        self.state = State::SendData;
+                        send_buf
                     }
                     None => return,
                 };
 
-                if let Err(e) = self
-                    .driver
-                    // .phy_data(DATA_OPT, send_buf)
-                    .dl_data(DATA_OPT, send_buf)
-                    .and_then(|tag| self.sender.enqueue(tag))
-                {
-                    crate::dbg::println!("data error {:?}", e);
-                    self.fail_timeout.set(100);
-                    self.state = State::Dispatch;
+                // Only a ping proves the follower is there in two-way mode
+                if !TWO_WAY || self.state == State::SendPing {
+                    self.keepalive_timeout.set(link::KEEPALIVE_PERIOD);
                 }
+                self.transmit(send_buf, self.tunnel.rate.opts());
             }
-            State::SendPing | State::SendData => match self.sender.process() {
-                Ok(()) if self.state == State::SendPing => {
-                    self.ping_timeout.set(500);
-                    self.state = State::WaitPing;
-                }
-                Ok(()) => self.state = State::Dispatch,
-                Err(st7580::NbStErr::WouldBlock) => {}
-                Err(st7580::NbStErr::Other(st7580::StErr::TxErrNoStatus)) => {
-                    crate::dbg::println!("plm did not return status");
-                    self.state = State::Dispatch;
-                }
-                Err(st7580::NbStErr::Other(st7580::StErr::TxErrAckTmo)) => {
-                    crate::dbg::println!("plm ack timed out");
-                    self.state = State::Dispatch;
-                }
-                Err(st7580::NbStErr::Other(st7580::StErr::TxErrBusy)) => {
-                    crate::dbg::println!("plm tx busy");
-                    self.state = State::Dispatch;
-                }
-                Err(st7580::NbStErr::Other(st7580::StErr::TxErrNak)) => {
-                    crate::dbg::println!("plm tx NAK");
-                    self.state = State::Dispatch;
-                }
-                Err(st7580::NbStErr::Other(e)) => {
-                    panic!("{:?} processing error: {:?}", self.state, e)
+            State::SendPing | State::SendData => {
+                match self.tunnel.poll_sent() {
+                    None => {}
+                    Some(true) if self.state == State::SendPing => {
+                        self.ping_timeout.set(self.scheduler.reply_timeout());
+                        self.state = State::WaitPing;
+                    }
+                    Some(_) => self.state = State::Dispatch,
                 }
-            },
+            }
             State::WaitPing if self.ping_timeout.is_expired() => {
+                if self.join_target.take().is_some() {
+                    crate::dbg::println!("join was not accepted");
+                } else {
+                    // The follower is polled again
+                    STATS.retries.inc();
+                    self.tunnel.rate.lost();
+                    self.tunnel.link_missed();
+                }
+                self.ping_sent = None;
+                self.scheduler.end_poll();
                 self.state = State::Dispatch;
             }
             State::WaitPing => {
-                let Some(f) = self.driver.receive_frame() else { return };
-                debug_assert!(matches!(f.stx, st7580::STX_03 | st7580::STX_02));
-                let header = f.data[HEADER_IDX].try_into().unwrap();
-                match header {
-                    Header::Ping => panic!("Unexpected Ping from follower"),
-                    Header::Data => {
-                        let len = f.length as usize;
-                        let mut data = f.data;
-                        data.copy_within(DATA_START..len, 0);
-                        data.truncate(len - DATA_START);
-                        self.channels.in_producer.enqueue(data).unwrap();
+                let Some((f, hdr, from_peer)) = self.tunnel.receive() else {
+                    return;
+                };
+                if from_peer {
+                    if let Some(sent) = self.ping_sent.take() {
+                        let rtt = st7580::now_us().wrapping_sub(sent);
+                        STATS.ping_rtt.record(rtt / 1000);
+                    }
+                    self.tunnel.rate.answered();
+                }
+                match hdr.kind {
+                    // Another leader took over our follower
+                    Header::Ping => match &mut self.election {
+                        Some(election) => election.rival_heard(hdr.src),
+                        None => FrameError::Unexpected.record(),
+                    },
+                    Header::Data if from_peer => {
+                        self.tunnel.data_received(&hdr, f);
+                    }
+                    Header::Accept if self.join_target == Some(hdr.src) => {
+                        self.join_target = None;
+                        let network = self.tunnel.session.own_network();
+                        if f.data.get(DATA_START..DATA_START + 2)
+                            == Some(&network.to_le_bytes())
+                        {
+                            self.tunnel.pair(network, hdr.src);
+                        }
+                    }
+                    Header::Announce => {
+                        if let Some(info) = self.tunnel.neighbors.record(&f) {
+                            self.announce_heard(&hdr, info);
+                        }
                     }
-                    Header::Idle => {}
+                    // Followers only ever join us to tell they unpaired
+                    Header::Join if from_peer => {
+                        let payload =
+                            f.data.get(DATA_START..).unwrap_or_default();
+                        if session::is_leave(payload) {
+                            self.tunnel.peer_left();
+                        }
+                    }
+                    Header::Discover | Header::Join => {
+                        FrameError::Unexpected.record()
+                    }
+                    Header::Data
+                    | Header::Accept
+                    | Header::Idle
+                    | Header::Ack => {}
+                }
+
+                // The follower keeps the line until its burst is over, each
+                // frame of it getting a full reply timeout
+                if from_peer && self.scheduler.heard(&hdr) {
+                    self.ping_timeout.set(self.scheduler.reply_timeout());
+                    return;
                 }
+                self.scheduler.end_poll();
                 self.state = State::Dispatch;
             }
         }
@@ -0,0 +1,116 @@
+//! Tracking of whether the far end of the tunnel is reachable
+//!
+//! The state is driven by keepalives: every exchange heard from the peer is a
+//! success and every expected exchange that never came is a miss. Moving
+//! between states needs several events in a row so that a single lost frame
+//! does not flap the link.
+
+/// Longest the leader goes without a keepalive in milliseconds. In two-way
+/// mode this is a ping, in one-way mode any frame including an idle one.
+pub const KEEPALIVE_PERIOD: u32 = 1000;
+
+/// Silence after which a follower counts a missed keepalive
+pub const SILENCE_PERIOD: u32 = 2 * KEEPALIVE_PERIOD;
+
+/// Successes in a row while `Joining` needed to go `Up`
+pub const JOIN_THRESHOLD: u8 = 3;
+/// Successes in a row while `Degraded` needed to go back `Up`
+pub const RECOVER_THRESHOLD: u8 = 3;
+/// Misses in a row needed to go from `Up` to `Degraded`
+pub const DEGRADE_THRESHOLD: u8 = 2;
+/// Misses in a row needed to go from `Degraded` to `Down`
+pub const DOWN_THRESHOLD: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    /// Nothing has been heard from the peer
+    Down = 0x00,
+    /// The peer was heard but not for long enough to trust it
+    Joining = 0x01,
+    /// The peer answers reliably
+    Up = 0x02,
+    /// The peer was up but has started missing keepalives
+    Degraded = 0x03,
+}
+
+impl From<LinkState> for u8 {
+    fn from(val: LinkState) -> Self {
+        val as u8
+    }
+}
+
+#[derive(Debug)]
+pub struct Link {
+    state: LinkState,
+    successes: u8,
+    misses: u8,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Self {
+            state: LinkState::Down,
+            successes: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Records that the peer was heard, returning the new state on change
+    pub fn heard(&mut self) -> Option<LinkState> {
+        self.misses = 0;
+        self.successes = self.successes.saturating_add(1);
+        let next = match self.state {
+            LinkState::Down => LinkState::Joining,
+            LinkState::Joining if self.successes >= JOIN_THRESHOLD => {
+                LinkState::Up
+            }
+            LinkState::Degraded if self.successes >= RECOVER_THRESHOLD => {
+                LinkState::Up
+            }
+            state => state,
+        };
+        self.transition(next)
+    }
+
+    /// Records that the peer missed a keepalive, returning the new state on
+    /// change
+    pub fn missed(&mut self) -> Option<LinkState> {
+        self.successes = 0;
+        self.misses = self.misses.saturating_add(1);
+        let next = match self.state {
+            LinkState::Joining => LinkState::Down,
+            LinkState::Up if self.misses >= DEGRADE_THRESHOLD => {
+                LinkState::Degraded
+            }
+            LinkState::Degraded if self.misses >= DOWN_THRESHOLD => {
+                LinkState::Down
+            }
+            state => state,
+        };
+        self.transition(next)
+    }
+
+    fn transition(&mut self, next: LinkState) -> Option<LinkState> {
+        if next == self.state {
+            return None;
+        }
+        crate::dbg::println!("link {:?} -> {:?}", self.state, next);
+        self.state = next;
+        self.successes = 0;
+        // Keep counting misses so a degraded link can still go down
+        if next != LinkState::Degraded {
+            self.misses = 0;
+        }
+        Some(next)
+    }
+}
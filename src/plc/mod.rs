@@ -1,11 +1,53 @@
-use crate::{st7580, usb};
+use crate::{mem, st7580, stats::STATS, usb};
 use stm32f4xx_hal::timer::{self, DelayUs};
 
+pub mod aggregate;
+pub mod bench;
+pub mod compress;
+pub mod crypto;
+pub mod csma;
+pub mod election;
+pub mod fec;
 pub mod follower;
+pub mod frame;
+pub mod host;
+pub mod integrity;
 pub mod leader;
+pub mod link;
+pub mod modem;
+pub mod neighbor;
+pub mod peer;
+pub mod pipeline;
+pub mod qos;
+pub mod rate;
+pub mod remote;
+pub mod role;
+pub mod schedule;
+pub mod session;
+pub mod time;
+pub mod tunnel;
+pub mod update;
 
+pub use bench::Bench;
+pub use compress::Compression;
+pub use crypto::Crypto;
+pub use fec::Fec;
 pub use follower::Follower;
+pub use frame::FrameError;
+pub use integrity::Integrity;
 pub use leader::Leader;
+pub use link::LinkState;
+pub use modem::Modem;
+pub use neighbor::{NeighborTable, NodeInfo, Role};
+pub use peer::Peer;
+pub use pipeline::Pipeline;
+pub use qos::Qos;
+pub use rate::Rate;
+pub use role::Node;
+pub use schedule::Scheduler;
+pub use session::Session;
+pub use time::Clock;
+pub use update::Update;
 
 const PLM_SPACE_USED: usize = 4 + if cfg!(feature = "GAIN_SELECTOR") {
     0 // This may need to be 1
@@ -13,15 +55,36 @@ const PLM_SPACE_USED: usize = 4 + if cfg!(feature = "GAIN_SELECTOR") {
     0
 };
 const HEADER_IDX: usize = PLM_SPACE_USED;
-const DATA_START: usize = HEADER_IDX + 1;
+const DATA_START: usize = HEADER_IDX + LinkHeader::LEN;
+
+/// Bit of the kind byte set when the payload is compressed
+const FLAG_COMPRESSED: u8 = 0x80;
+/// Bit of the kind byte set when another frame of a burst follows
+const FLAG_MORE: u8 = 0x10;
+/// Alternating bit of the kind byte telling acknowledged frames apart
+const FLAG_SEQ: u8 = 0x08;
+/// Bits of the kind byte that are not the kind itself
+const KIND_FLAGS: u8 = FLAG_COMPRESSED | FLAG_MORE | FLAG_SEQ | fec::RATE_MASK;
+
+/// Most messages a channel takes from the peer at once. The queues have more
+/// room, but the three channels share a pool of about 60 buffers with
+/// everything else, and credits the pool cannot back would only end up
+/// dropped.
+const MAX_CREDITS: u8 = 8;
 
 /// 0 -
 const DATA_OPT: u8 = 0b0_010_0_1_0_0;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Header {
     Idle = 0x00,
     Data = 0x01,
     Ping = 0x02,
+    Discover = 0x03,
+    Announce = 0x04,
+    Join = 0x05,
+    Accept = 0x06,
+    Ack = 0x07,
 }
 
 impl From<Header> for u8 {
@@ -39,33 +102,250 @@ impl TryFrom<u8> for Header {
             0x00 => Ok(Idle),
             0x01 => Ok(Data),
             0x02 => Ok(Ping),
+            0x03 => Ok(Discover),
+            0x04 => Ok(Announce),
+            0x05 => Ok(Join),
+            0x06 => Ok(Accept),
+            0x07 => Ok(Ack),
             v => Err(v),
         }
     }
 }
 
+/// Fields at the start of every frame sent over the powerline
+#[derive(Debug, Clone, Copy)]
+struct LinkHeader {
+    kind: Header,
+    /// Whether the payload was packed by `compress`
+    compressed: bool,
+    /// Whether the sender has another frame of its burst to follow
+    more: bool,
+    /// Sequence bit of a data frame and of the ack for it, contention mode
+    /// only
+    seq: bool,
+    network: session::NetworkId,
+    src: neighbor::Address,
+    dst: neighbor::Address,
+    /// Channel the messages of a data frame belong to
+    channel: u8,
+    /// Messages the sender has room to receive on each channel, for flow
+    /// control
+    credits: [u8; usb::CHANNELS],
+}
+
+impl LinkHeader {
+    const LEN: usize = 8 + usb::CHANNELS;
+
+    fn encode(&self) -> [u8; Self::LEN] {
+        let [n0, n1] = self.network.to_le_bytes();
+        let [s0, s1] = self.src.to_le_bytes();
+        let [d0, d1] = self.dst.to_le_bytes();
+        let mut flags = 0;
+        if self.compressed {
+            flags |= FLAG_COMPRESSED;
+        }
+        if self.more {
+            flags |= FLAG_MORE;
+        }
+        if self.seq {
+            flags |= FLAG_SEQ;
+        }
+        let kind = u8::from(self.kind) | flags;
+        let mut buf = [0; Self::LEN];
+        buf[..8].copy_from_slice(&[kind, n0, n1, s0, s1, d0, d1, self.channel]);
+        buf[8..].copy_from_slice(&self.credits);
+        buf
+    }
+
+    /// Reads the header of a received frame, past the indication
+    fn parse(data: &[u8]) -> Result<Self, FrameError> {
+        let hdr = data
+            .get(HEADER_IDX..DATA_START)
+            .ok_or(FrameError::Truncated)?;
+        let field = |at: usize| u16::from_le_bytes([hdr[at], hdr[at + 1]]);
+        Ok(Self {
+            kind: Header::try_from(hdr[0] & !KIND_FLAGS)
+                .map_err(FrameError::UnknownKind)?,
+            compressed: hdr[0] & FLAG_COMPRESSED != 0,
+            more: hdr[0] & FLAG_MORE != 0,
+            seq: hdr[0] & FLAG_SEQ != 0,
+            network: field(1),
+            src: field(3),
+            dst: field(5),
+            channel: hdr[7],
+            credits: hdr[8..].try_into().unwrap(),
+        })
+    }
+}
+
+/// Receive metadata the ST7580 places ahead of the payload of an indication
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Indication {
+    /// Modulation and channel the frame was received with
+    pub opts: u8,
+    /// Receive gain picked by the modem's PGA
+    pub pga: u8,
+    /// Estimated signal to noise ratio of the frame
+    pub snr: u8,
+    /// Delay of the frame start from the mains zero crossing
+    pub zc_delay: u8,
+}
+
+impl Indication {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let &[opts, pga, snr, zc_delay] = data.get(..4)? else { return None };
+        Some(Self {
+            opts,
+            pga,
+            snr,
+            zc_delay,
+        })
+    }
+}
+
+/// Builds a frame made of a header and a payload
+fn link_frame(hdr: LinkHeader, payload: &[u8]) -> Option<mem::BufBox> {
+    let mut buf = mem::alloc()?;
+    buf.extend_from_slice(&hdr.encode()).unwrap();
+    buf.extend_from_slice(payload).ok()?;
+    Some(buf)
+}
+
+/// Puts a header in front of a payload already in a buffer
+fn prepend_header(buf: &mut mem::BufBox, hdr: LinkHeader) {
+    buf.extend_from_slice(&hdr.encode()).unwrap();
+    buf.rotate_right(LinkHeader::LEN);
+}
+
+/// Builds an announcement of `node`
+fn announce_frame(hdr: LinkHeader, node: &NodeInfo) -> Option<mem::BufBox> {
+    let mut buf = link_frame(hdr, &[])?;
+    node.encode(&mut buf);
+    Some(buf)
+}
+
+/// Queues of the logical channels multiplexed over the tunnel
 struct Channels {
-    in_producer: usb::UsbProducer,
-    out_consumer: usb::UsbConsumer,
+    in_producers: [usb::UsbProducer; usb::CHANNELS],
+    out_consumers: [usb::UsbConsumer; usb::CHANNELS],
+    /// Messages the peer last said it has room for on each channel, less
+    /// those sent since. `None` in one-way mode where the peer never answers.
+    peer_credits: Option<[u8; usb::CHANNELS]>,
+    /// Channel sent from next, channels of a class take turns
+    next: usize,
+    qos: Qos,
+}
+
+impl Channels {
+    fn new(
+        in_producers: [usb::UsbProducer; usb::CHANNELS],
+        out_consumers: [usb::UsbConsumer; usb::CHANNELS],
+        two_way: bool,
+    ) -> Self {
+        Self {
+            in_producers,
+            out_consumers,
+            // Nothing is sent until the peer says it has room
+            peer_credits: two_way.then_some([0; usb::CHANNELS]),
+            next: 0,
+            qos: Qos::new(),
+        }
+    }
+
+    /// Room left for messages from the peer on each channel, advertised in
+    /// our frames
+    fn credits(&self) -> [u8; usb::CHANNELS] {
+        let mut credits = [0; usb::CHANNELS];
+        for (credit, producer) in credits.iter_mut().zip(&self.in_producers) {
+            let free = producer.capacity() - producer.len();
+            *credit = free.min(MAX_CREDITS.into()) as u8;
+        }
+        credits
+    }
+
+    /// Takes up the credits advertised in a frame from the peer
+    fn heard(&mut self, hdr: &LinkHeader) {
+        if let Some(credits) = &mut self.peer_credits {
+            *credits = hdr.credits;
+        }
+    }
+
+    /// Whether `channel` has a message waiting that the peer has room for
+    fn channel_sendable(&self, channel: usize) -> bool {
+        self.out_consumers[channel].ready()
+            && self.peer_credits.map(|c| c[channel]) != Some(0)
+    }
+
+    /// Whether a message is waiting on any channel that the peer has room for
+    fn sendable(&self) -> bool {
+        (0..usb::CHANNELS).any(|channel| self.channel_sendable(channel))
+    }
+
+    /// Whether a message of `class` is waiting that the peer has room for
+    fn class_sendable(&self, class: qos::Class) -> bool {
+        (0..usb::CHANNELS).any(|channel| {
+            self.qos.class(channel) == class && self.channel_sendable(channel)
+        })
+    }
+
+    /// Takes as many messages waiting on the channel whose turn it is as the
+    /// peer has room for and as fit in `room` bytes, returning the channel
+    /// along with them
+    fn next_aggregate(&mut self, room: usize) -> Option<(u8, mem::BufBox)> {
+        let class = self.qos.pick(|class| self.class_sendable(class))?;
+        let channel = (0..usb::CHANNELS)
+            .map(|n| (self.next + n) % usb::CHANNELS)
+            .find(|&channel| {
+                self.qos.class(channel) == class
+                    && self.channel_sendable(channel)
+            })?;
+        self.next = (channel + 1) % usb::CHANNELS;
+        self.qos.sent(class);
+
+        let limit = self.peer_credits.map_or(usize::MAX, |c| c[channel].into());
+        let (buf, count) =
+            aggregate::collect(&mut self.out_consumers[channel], room, limit)?;
+        if let Some(credits) = &mut self.peer_credits {
+            credits[channel] -= count as u8;
+        }
+        usb::wake();
+        Some((channel as u8, buf))
+    }
+
+    /// Hands every message of a received aggregate to the host on `channel`
+    fn deliver(&mut self, channel: u8, payload: &[u8]) {
+        let Some(in_producer) = self.in_producers.get_mut(channel as usize)
+        else {
+            crate::dbg::println!("no channel {}, packet dropped", channel);
+            return;
+        };
+        for message in aggregate::split(payload) {
+            let Ok(message) = message else {
+                crate::dbg::println!("malformed aggregate");
+                return;
+            };
+            let Some(buf) = mem::alloc_from_slice(message) else {
+                crate::dbg::println!("no buffer for message, dropped");
+                return;
+            };
+            // Only happens when the peer ignores our credits
+            if let Err(_buf) = in_producer.enqueue(buf) {
+                crate::dbg::println!("IN Producer is full, packet dropped");
+                STATS.queue_drops.inc();
+            }
+        }
+        usb::wake();
+    }
 }
 
 fn shared_init<TIM: timer::Instance>(
     delay: &mut DelayUs<TIM>,
     driver: &mut st7580::Driver,
     sender: &mut st7580::DSender,
+    settings: &modem::Settings,
 ) {
     driver.init(delay);
 
-    driver
-        .mib_write(st7580::MIB_MODEM_CONF, &st7580::MODEM_CONFIG)
-        .and_then(|tag| sender.enqueue(tag))
-        .and_then(|d| nb::block!(d.process()))
-        .unwrap();
-
-    driver
-        .mib_write(st7580::MIB_PHY_CONF, &st7580::PHY_CONFIG)
-        .and_then(|tag| sender.enqueue(tag))
-        .and_then(|d| nb::block!(d.process()))
-        .unwrap();
+    settings.apply(driver, sender).unwrap();
     driver.set_ready_to_receive();
 }
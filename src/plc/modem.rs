@@ -0,0 +1,340 @@
+//! Modem settings changed from the far end of the tunnel
+//!
+//! The leader offers its follower new PHY and modem configuration in a link
+//! control message carrying a switch delay. The follower answers and both
+//! sides write the settings to their modem once the delay runs out, counted
+//! from when each side sent or got the offer, so they switch within the
+//! latency of one frame of each other. A leader that gets no answer before
+//! then keeps its settings. After switching, each side has
+//! `FALLBACK_TIMEOUT` to confirm the settings by an exchange both ways. The
+//! leader has one as soon as its follower answers, since the follower only
+//! speaks when spoken to, and tells the follower so with each frame it hears
+//! for another `FALLBACK_TIMEOUT`. The follower takes that as its own
+//! confirmation, hearing the leader alone proving nothing about the way
+//! back. Confirmed settings are saved to flash and used from then on.
+//! Otherwise a side goes back to the settings it had, and so does the other
+//! side, which either cannot hear it or was never told.
+
+use crate::{config, st7580};
+
+/// Delay the leader gives before both sides switch in milliseconds
+pub const SWITCH_DELAY: u32 = 2000;
+/// Longest switch delay a follower takes in milliseconds
+pub const MAX_SWITCH_DELAY: u32 = 60_000;
+/// Time to hear the peer under new settings before going back, in
+/// milliseconds
+pub const FALLBACK_TIMEOUT: u32 = 10_000;
+
+const PHY_LEN: usize = st7580::PHY_CONFIG.len();
+const MODEM_LEN: usize = st7580::MODEM_CONFIG.len();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Written to `st7580::MIB_PHY_CONF`
+    pub phy: [u8; PHY_LEN],
+    /// Written to `st7580::MIB_MODEM_CONF`
+    pub modem: [u8; MODEM_LEN],
+}
+
+impl Settings {
+    pub const ENCODED_LEN: usize = config::MODEM_LEN;
+
+    /// Settings the firmware was built with
+    pub const BUILT_IN: Self = Self {
+        phy: st7580::PHY_CONFIG,
+        modem: st7580::MODEM_CONFIG,
+    };
+
+    /// Settings last confirmed over the link, or the built-in ones
+    pub fn load(store: &config::Store) -> Self {
+        store
+            .load()
+            .modem
+            .and_then(|data| Self::decode(&data))
+            .unwrap_or(Self::BUILT_IN)
+    }
+
+    /// Encodes the settings as the PHY configuration followed by the modem
+    /// configuration
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut buf = [0; Self::ENCODED_LEN];
+        buf[..PHY_LEN].copy_from_slice(&self.phy);
+        buf[PHY_LEN..].copy_from_slice(&self.modem);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::ENCODED_LEN)?;
+        let (phy, modem) = data.split_at(PHY_LEN);
+        Some(Self {
+            phy: phy.try_into().ok()?,
+            modem: modem.try_into().ok()?,
+        })
+    }
+
+    /// Writes the settings to the modem, waiting for it to confirm them
+    pub(super) fn apply(
+        &self,
+        driver: &mut st7580::Driver,
+        sender: &mut st7580::DSender,
+    ) -> Result<(), st7580::StErr> {
+        driver
+            .mib_write(st7580::MIB_MODEM_CONF, &self.modem)
+            .and_then(|tag| sender.enqueue(tag))
+            .and_then(|d| nb::block!(d.process()))?;
+        driver
+            .mib_write(st7580::MIB_PHY_CONF, &self.phy)
+            .and_then(|tag| sender.enqueue(tag))
+            .and_then(|d| nb::block!(d.process()))
+    }
+}
+
+/// How far a change of settings got, as reported to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The new settings were written and wait to be confirmed
+    Switched = 0x00,
+    /// The peer was heard under the new settings, which were saved
+    Confirmed = 0x01,
+    /// The peer was not heard in time and the old settings are back
+    FellBack = 0x02,
+    /// The follower never answered the offer so nothing changed
+    Cancelled = 0x03,
+}
+
+impl From<Outcome> for u8 {
+    fn from(val: Outcome) -> Self {
+        val as u8
+    }
+}
+
+#[derive(Debug)]
+enum Phase {
+    Idle,
+    /// The leader waits for its follower to take the settings
+    Offered {
+        settings: Settings,
+        switch_timeout: st7580::Timeout,
+    },
+    /// Both sides agreed and switch once the timeout expires
+    Scheduled {
+        settings: Settings,
+        switch_timeout: st7580::Timeout,
+    },
+    /// The settings were switched and wait for an exchange both ways
+    Trial {
+        previous: Settings,
+        fallback_timeout: st7580::Timeout,
+    },
+    /// The leader confirmed the settings and tells its follower until the
+    /// timeout expires
+    Telling {
+        tell_timeout: st7580::Timeout,
+    },
+}
+
+impl Phase {
+    fn code(&self) -> u8 {
+        match self {
+            Phase::Idle | Phase::Telling { .. } => 0x00,
+            Phase::Offered { .. } => 0x01,
+            Phase::Scheduled { .. } => 0x02,
+            Phase::Trial { .. } => 0x03,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Modem {
+    current: Settings,
+    phase: Phase,
+}
+
+impl Modem {
+    pub fn load(store: &config::Store) -> Self {
+        Self {
+            current: Settings::load(store),
+            phase: Phase::Idle,
+        }
+    }
+
+    pub fn current(&self) -> &Settings {
+        &self.current
+    }
+
+    /// Starts offering `settings` to the follower, unless a change is
+    /// already under way
+    pub fn offer(&mut self, settings: Settings) -> bool {
+        if !matches!(self.phase, Phase::Idle) {
+            return false;
+        }
+        let mut switch_timeout = st7580::Timeout::default();
+        switch_timeout.set(SWITCH_DELAY);
+        self.phase = Phase::Offered {
+            settings,
+            switch_timeout,
+        };
+        true
+    }
+
+    /// Takes settings the peer says it took: on the leader the follower's
+    /// answer to our offer, on a follower the leader telling it heard us
+    /// under the settings on trial, which confirms them
+    pub fn accepted(
+        &mut self,
+        accepted: Settings,
+        store: &mut config::Store,
+    ) -> Option<Outcome> {
+        match self.phase {
+            Phase::Offered {
+                settings,
+                switch_timeout,
+            } if settings == accepted => {
+                self.phase = Phase::Scheduled {
+                    settings,
+                    switch_timeout,
+                };
+                None
+            }
+            Phase::Trial { .. } if self.current == accepted => {
+                self.phase = Phase::Idle;
+                Some(self.confirm(store))
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes settings offered by the leader, to be switched to after `delay`,
+    /// returning whether they were taken
+    pub fn schedule(&mut self, delay: u32, settings: Settings) -> bool {
+        let busy = matches!(self.phase, Phase::Trial { .. });
+        if busy || delay > MAX_SWITCH_DELAY {
+            return false;
+        }
+        let mut switch_timeout = st7580::Timeout::default();
+        // A zero timeout never expires
+        switch_timeout.set(delay.max(1));
+        self.phase = Phase::Scheduled {
+            settings,
+            switch_timeout,
+        };
+        true
+    }
+
+    /// Takes a frame from the follower, which answers one of ours and so
+    /// confirms settings on trial on the leader
+    pub fn answered(&mut self, store: &mut config::Store) -> Option<Outcome> {
+        let Phase::Trial { .. } = self.phase else { return None };
+        let mut tell_timeout = st7580::Timeout::default();
+        tell_timeout.set(FALLBACK_TIMEOUT);
+        self.phase = Phase::Telling { tell_timeout };
+        Some(self.confirm(store))
+    }
+
+    /// Whether the follower is still to be told the settings were confirmed
+    pub fn is_telling(&self) -> bool {
+        matches!(self.phase, Phase::Telling { .. })
+    }
+
+    /// Stages the settings in use to be saved
+    fn confirm(&mut self, store: &mut config::Store) -> Outcome {
+        let mut config = store.load();
+        config.modem = Some(self.current.encode());
+        store.stage(config);
+        Outcome::Confirmed
+    }
+
+    /// Switches and falls back when due. Writing to the modem waits for it,
+    /// so this is only called while nothing else is being sent.
+    pub(super) fn poll(
+        &mut self,
+        driver: &mut st7580::Driver,
+        sender: &mut st7580::DSender,
+    ) -> Option<Outcome> {
+        match self.phase {
+            Phase::Offered { switch_timeout, .. }
+                if switch_timeout.is_expired() =>
+            {
+                self.phase = Phase::Idle;
+                Some(Outcome::Cancelled)
+            }
+            Phase::Scheduled {
+                settings,
+                switch_timeout,
+            } if switch_timeout.is_expired() => {
+                let previous = self.current;
+                if let Err(_e) = settings.apply(driver, sender) {
+                    crate::dbg::println!("modem settings refused {:?}", _e);
+                    self.phase = Phase::Idle;
+                    self.restore(previous, driver, sender);
+                    return Some(Outcome::FellBack);
+                }
+                self.current = settings;
+                let mut fallback_timeout = st7580::Timeout::default();
+                fallback_timeout.set(FALLBACK_TIMEOUT);
+                self.phase = Phase::Trial {
+                    previous,
+                    fallback_timeout,
+                };
+                Some(Outcome::Switched)
+            }
+            Phase::Trial {
+                previous,
+                fallback_timeout,
+            } if fallback_timeout.is_expired() => {
+                self.phase = Phase::Idle;
+                self.restore(previous, driver, sender);
+                Some(Outcome::FellBack)
+            }
+            Phase::Telling { tell_timeout } if tell_timeout.is_expired() => {
+                self.phase = Phase::Idle;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn restore(
+        &mut self,
+        previous: Settings,
+        driver: &mut st7580::Driver,
+        sender: &mut st7580::DSender,
+    ) {
+        if let Err(_e) = previous.apply(driver, sender) {
+            crate::dbg::println!("modem settings not restored {:?}", _e);
+        }
+        self.current = previous;
+    }
+
+    /// Encodes the state for the host as `[phase, settings]`, the phase
+    /// being `0` idle, `1` offered, `2` scheduled or `3` on trial
+    pub fn encode(&self) -> [u8; 1 + Settings::ENCODED_LEN] {
+        let mut buf = [0; 1 + Settings::ENCODED_LEN];
+        buf[0] = self.phase.code();
+        buf[1..].copy_from_slice(&self.current.encode());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip() {
+        let mut settings = Settings::BUILT_IN;
+        settings.phy[0] ^= 0xFF;
+        settings.modem[MODEM_LEN - 1] ^= 0xFF;
+        let encoded = settings.encode();
+        assert_eq!(encoded[..PHY_LEN], settings.phy);
+        assert_eq!(Settings::decode(&encoded), Some(settings));
+    }
+
+    #[test]
+    fn short_settings_are_rejected() {
+        let encoded = Settings::BUILT_IN.encode();
+        let short = &encoded[..Settings::ENCODED_LEN - 1];
+        assert_eq!(Settings::decode(short), None);
+        assert_eq!(Settings::decode(&[]), None);
+    }
+}
@@ -0,0 +1,219 @@
+//! Neighbor discovery and the table of nodes heard on the wire
+
+use super::{Indication, DATA_START};
+use crate::{mem, st7580, util};
+use heapless::Vec;
+
+/// Short address of a node, folded from the chip's unique ID
+pub type Address = u16;
+
+/// How often a leader announces itself in milliseconds
+pub const ANNOUNCE_PERIOD: u32 = 5000;
+
+/// Most nodes remembered at once, the stalest entry is evicted past this
+pub const MAX_NEIGHBORS: usize = 8;
+
+/// Capability bits advertised in an announcement
+pub const CAP_TWO_WAY: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Leader = 0x00,
+    Follower = 0x01,
+    /// Either side of a tunnel in contention mode
+    Peer = 0x02,
+}
+
+impl From<Role> for u8 {
+    fn from(val: Role) -> Self {
+        val as u8
+    }
+}
+
+impl TryFrom<u8> for Role {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Role::*;
+        match v {
+            0x00 => Ok(Leader),
+            0x01 => Ok(Follower),
+            0x02 => Ok(Peer),
+            v => Err(v),
+        }
+    }
+}
+
+/// Identity of a node as carried in an announcement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub addr: Address,
+    pub role: Role,
+    pub version: [u8; 3],
+    pub caps: u8,
+}
+
+impl NodeInfo {
+    pub const ENCODED_LEN: usize = 7;
+
+    pub fn local(role: Role, two_way: bool) -> Self {
+        Self {
+            addr: local_address(),
+            role,
+            version: crate::FIRMWARE_VERSION,
+            caps: if two_way { CAP_TWO_WAY } else { 0 },
+        }
+    }
+
+    pub fn encode(&self, buf: &mut mem::VecBuf) {
+        buf.extend_from_slice(&self.addr.to_le_bytes()).unwrap();
+        buf.push(self.role.into()).unwrap();
+        buf.extend_from_slice(&self.version).unwrap();
+        buf.push(self.caps).unwrap();
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::ENCODED_LEN)?;
+        Some(Self {
+            addr: Address::from_le_bytes([data[0], data[1]]),
+            role: data[2].try_into().ok()?,
+            version: [data[3], data[4], data[5]],
+            caps: data[6],
+        })
+    }
+}
+
+/// Folds the 96-bit unique device ID down to a node address
+pub fn local_address() -> Address {
+    util::unique_id()
+        .iter()
+        .fold(0, |acc, &w| acc ^ (w as Address) ^ ((w >> 16) as Address))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Neighbor {
+    pub info: NodeInfo,
+    /// Time of the last frame heard from the node in microseconds, as
+    /// `st7580::now_us` wraps where `wrapping_sub` expects
+    pub last_heard: u32,
+    /// Smoothed SNR over the frames heard from the node
+    pub snr: u8,
+    /// Receive gain of the last frame heard from the node
+    pub pga: u8,
+    pub frames: u32,
+}
+
+impl Neighbor {
+    pub const ENCODED_LEN: usize = NodeInfo::ENCODED_LEN + 10;
+
+    /// Encodes the entry for the host with the age in milliseconds relative
+    /// to `now` in microseconds
+    pub fn encode(&self, now: u32, buf: &mut mem::VecBuf) {
+        self.info.encode(buf);
+        let age = now.wrapping_sub(self.last_heard) / 1000;
+        buf.extend_from_slice(&age.to_le_bytes()).unwrap();
+        buf.push(self.snr).unwrap();
+        buf.push(self.pga).unwrap();
+        buf.extend_from_slice(&self.frames.to_le_bytes()).unwrap();
+    }
+
+    /// Takes a frame heard from the node at `now`
+    fn refresh(&mut self, now: u32, ind: Indication) {
+        self.last_heard = now;
+        self.snr = ((3 * self.snr as u16 + ind.snr as u16) / 4) as u8;
+        self.pga = ind.pga;
+        self.frames = self.frames.wrapping_add(1);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NeighborTable {
+    entries: Vec<Neighbor, MAX_NEIGHBORS>,
+}
+
+impl NeighborTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Records an announcement heard from `info` with the given link quality
+    pub fn update(&mut self, info: NodeInfo, ind: Indication) {
+        let now = st7580::now_us();
+        if let Some(n) = self.entries.iter_mut().find(|n| n.info == info) {
+            n.refresh(now, ind);
+            return;
+        }
+
+        let neighbor = Neighbor {
+            info,
+            last_heard: now,
+            snr: ind.snr,
+            pga: ind.pga,
+            frames: 1,
+        };
+        // Entries for a node whose identity changed are replaced
+        let existing = self
+            .entries
+            .iter()
+            .position(|n| n.info.addr == info.addr)
+            .or_else(|| self.entries.is_full().then(|| self.stalest(now)));
+        match existing {
+            Some(idx) => self.entries[idx] = neighbor,
+            None => self.entries.push(neighbor).unwrap(),
+        }
+    }
+
+    /// Records the announcement carried by a received frame, returning the
+    /// node it announced
+    pub(super) fn record(&mut self, frame: &st7580::Frame) -> Option<NodeInfo> {
+        let ind = Indication::parse(&frame.data).unwrap_or_default();
+        let payload = frame.data.get(DATA_START..).unwrap_or_default();
+        let info = NodeInfo::decode(payload);
+        match info {
+            Some(info) => self.update(info, ind),
+            None => {
+                crate::dbg::println!("malformed announcement");
+            }
+        }
+        info
+    }
+
+    /// Refreshes the entry of a node already known from its announcements
+    /// with any other frame heard from it, so that a node busy sending data
+    /// or pings does not look gone between announcements
+    pub(super) fn heard(&mut self, src: Address, frame: &st7580::Frame) {
+        let Some(n) = self.entries.iter_mut().find(|n| n.info.addr == src)
+        else {
+            return;
+        };
+        let ind = Indication::parse(&frame.data).unwrap_or_default();
+        n.refresh(st7580::now_us(), ind);
+    }
+
+    pub fn get(&self, addr: Address) -> Option<&Neighbor> {
+        self.entries.iter().find(|n| n.info.addr == addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Neighbor> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn stalest(&self, now: u32) -> usize {
+        self.entries
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, n)| now.wrapping_sub(n.last_heard))
+            .map(|(idx, _)| idx)
+            .unwrap()
+    }
+}
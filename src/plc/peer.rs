@@ -0,0 +1,450 @@
+//! Either side of a tunnel in contention mode
+//!
+//! Instead of a leader polling its follower, both peers send whenever they
+//! have something for the other and take turns on the line through `csma`.
+//! Data frames are acknowledged by the peer and sent again after a longer
+//! backoff when the ack does not come back. The sequence bit of the header
+//! lets the peer tell a frame sent again from a new one.
+
+use super::{
+    csma::{self, Backoff},
+    frame::FrameError,
+    host, link,
+    neighbor::{self, Address, NeighborTable, NodeInfo, Role},
+    role::Parts,
+    session::{self, Session},
+    tunnel::Tunnel,
+    Header, LinkHeader, DATA_OPT, DATA_START,
+};
+use crate::{mem, st7580, stats::STATS, usb};
+use stm32f4xx_hal::timer::{self, DelayUs};
+
+/// How long to wait for a join to be accepted in milliseconds
+const JOIN_TIMEOUT: u32 = 2000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State {
+    /// Listening with nothing to send
+    Listen,
+    /// Waiting for the line to send the pending frame
+    Backoff,
+    /// The modem is sending the pending frame
+    Send,
+    /// The modem is sending an ack, after which the previous state resumes
+    SendAck,
+    /// Waiting for the peer to acknowledge the pending frame
+    WaitAck,
+}
+
+/// Frame waiting for its turn on the line
+struct Pending {
+    frame: mem::BufBox,
+    /// Whether the peer acknowledges it
+    acked: bool,
+    attempts: u8,
+}
+
+impl Pending {
+    fn new(frame: mem::BufBox, acked: bool) -> Self {
+        Self {
+            frame,
+            acked,
+            attempts: 0,
+        }
+    }
+}
+
+pub struct Peer {
+    state: State,
+    /// State to go back to once an ack is sent
+    resume: State,
+    tunnel: Tunnel,
+    node: NodeInfo,
+    discover_pending: bool,
+    join_target: Option<Address>,
+    /// Whether the join for `join_target` is still to be sent
+    join_pending: bool,
+    backoff: Backoff,
+    pending: Option<Pending>,
+    /// Answer to another node, sent ahead of anything else
+    reply: Option<mem::BufBox>,
+    /// Sequence bit of the next data frame
+    tx_seq: bool,
+    /// Sequence bit of the last data frame taken from the peer
+    rx_seq: Option<bool>,
+    /// Sequence bit of a data frame the peer is owed an ack for
+    ack_due: Option<bool>,
+    /// Credits last advertised to the peer
+    advertised: [u8; usb::CHANNELS],
+    ack_timeout: st7580::Timeout,
+    join_timeout: st7580::Timeout,
+    announce_timeout: st7580::Timeout,
+    keepalive_timeout: st7580::Timeout,
+    silence_timeout: st7580::Timeout,
+}
+
+impl Peer {
+    /// Builds a peer, which never takes part in an election
+    pub fn new(parts: Parts) -> Self {
+        let (tunnel, _) = Tunnel::new(parts, Role::Peer, true);
+        let node = NodeInfo::local(Role::Peer, true);
+        let mut announce_timeout = st7580::Timeout::default();
+        announce_timeout.set(1);
+        let mut keepalive_timeout = st7580::Timeout::default();
+        keepalive_timeout.set(link::KEEPALIVE_PERIOD);
+        let mut silence_timeout = st7580::Timeout::default();
+        silence_timeout.set(link::SILENCE_PERIOD);
+        Self {
+            state: State::Listen,
+            resume: State::Listen,
+            tunnel,
+            node,
+            discover_pending: false,
+            join_target: None,
+            join_pending: false,
+            // Peers pick different backoffs since their addresses differ
+            backoff: Backoff::new(node.addr.into()),
+            pending: None,
+            reply: None,
+            tx_seq: false,
+            rx_seq: None,
+            ack_due: None,
+            advertised: [0; usb::CHANNELS],
+            ack_timeout: Default::default(),
+            join_timeout: Default::default(),
+            announce_timeout,
+            keepalive_timeout,
+            silence_timeout,
+        }
+    }
+
+    pub fn init<TIM: timer::Instance>(&mut self, delay: &mut DelayUs<TIM>) {
+        self.tunnel.init(delay)
+    }
+
+    pub fn neighbors(&self) -> &NeighborTable {
+        &self.tunnel.neighbors
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.tunnel.session
+    }
+
+    pub fn link_state(&self) -> link::LinkState {
+        self.tunnel.link.state()
+    }
+
+    /// Counts a miss for every silence period without hearing the peer
+    fn poll_silence(&mut self) {
+        if !self.silence_timeout.is_expired() {
+            return;
+        }
+        self.silence_timeout.set(link::SILENCE_PERIOD);
+        self.tunnel.link_missed();
+    }
+
+    /// Header of a frame to the peer, advertising our room to receive. Any
+    /// frame to the peer also stands in for a keepalive.
+    fn advertise(&mut self, kind: Header) -> LinkHeader {
+        let hdr = self.tunnel.to_peer(kind);
+        self.advertised = hdr.credits;
+        self.keepalive_timeout.set(link::KEEPALIVE_PERIOD);
+        hdr
+    }
+
+    fn poll_host(&mut self) {
+        let Some((cmd, payload)) = self.tunnel.poll_host() else { return };
+        let tunnel = &mut self.tunnel;
+        match cmd {
+            cmd @ host::Command::Discover => {
+                self.discover_pending = true;
+                tunnel.host.ack(cmd);
+            }
+            cmd @ host::Command::Pair => match payload[..] {
+                [a0, a1, ..] => {
+                    let target = Address::from_le_bytes([a0, a1]);
+                    // A join from the target crossing ours is as good
+                    tunnel.session.allow(Some(target));
+                    self.join_target = Some(target);
+                    self.join_pending = true;
+                    tunnel.host.ack(cmd);
+                }
+                _ => tunnel.host.error(cmd as u8),
+            },
+            // Nobody polls in contention mode, and reports, modem settings,
+            // images, time, benchmarks, loopback and modulations are only
+            // exchanged between a leader and its follower
+            cmd => tunnel.host.error(cmd as u8),
+        }
+    }
+
+    fn pair(&mut self, network: session::NetworkId, peer: Address) {
+        self.tunnel.pair(network, peer);
+        self.tx_seq = false;
+        self.rx_seq = None;
+        // Whatever was pending was meant for the old peer
+        if matches!(self.state, State::Backoff | State::WaitAck) {
+            self.pending = None;
+            self.state = State::Listen;
+        }
+    }
+
+    /// Takes up the network offered by a join addressed to this node, when
+    /// `Session::welcomes` it
+    fn join(&mut self, hdr: &LinkHeader, payload: &[u8], from_peer: bool) {
+        let Some(network) = self.tunnel.join_offer(hdr, payload, from_peer)
+        else {
+            return;
+        };
+        self.pair(network, hdr.src);
+        let hdr = self.advertise(Header::Accept);
+        self.reply = super::link_frame(hdr, &network.to_le_bytes());
+    }
+
+    /// Builds the pending leave notice, join, discovery or announcement
+    fn management_frame(&mut self) -> Option<mem::BufBox> {
+        if self.tunnel.session.has_left() {
+            let notice = self.tunnel.leave_frame();
+            self.tunnel.session.forget_left();
+            return notice;
+        }
+        let session = &self.tunnel.session;
+        if let Some(target) = self.join_target.filter(|_| self.join_pending) {
+            self.join_pending = false;
+            self.join_timeout.set(JOIN_TIMEOUT);
+            let network = session.own_network();
+            let mut hdr = session.header(Header::Join, target);
+            hdr.network = network;
+            return super::link_frame(hdr, &network.to_le_bytes());
+        }
+        if self.discover_pending {
+            self.discover_pending = false;
+            let hdr = session.header(Header::Discover, session::BROADCAST);
+            return super::link_frame(hdr, &[]);
+        }
+        if self.announce_timeout.is_expired() {
+            self.announce_timeout.set(neighbor::ANNOUNCE_PERIOD);
+            let hdr = session.header(Header::Announce, session::BROADCAST);
+            return super::announce_frame(hdr, &self.node);
+        }
+        None
+    }
+
+    /// Picks the next frame to contend for the line with, control frames
+    /// first
+    fn next_pending(&mut self) -> Option<Pending> {
+        if let Some(frame) = self.reply.take() {
+            return Some(Pending::new(frame, false));
+        }
+        if let Some(frame) = self.management_frame() {
+            return Some(Pending::new(frame, false));
+        }
+        if !self.tunnel.session.is_paired() {
+            return None;
+        }
+        if self.keepalive_timeout.is_expired() {
+            let hdr = self.advertise(Header::Idle);
+            return super::link_frame(hdr, &[]).map(|f| Pending::new(f, false));
+        }
+
+        if let Some((channel, mut frame)) = self.tunnel.next_data() {
+            let mut hdr = self.advertise(Header::Data);
+            hdr.channel = channel;
+            hdr.seq = self.tx_seq;
+            let pipeline = &mut self.tunnel.pipeline;
+            if let Err(_e) = pipeline.outgoing(&mut hdr, &mut frame) {
+                crate::dbg::println!("packet dropped {:?}", _e);
+                return None;
+            }
+            super::prepend_header(&mut frame, hdr);
+            return Some(Pending::new(frame, true));
+        }
+
+        // A peer that was told we are full waits to hear we have room again
+        let freed = self
+            .tunnel
+            .channels
+            .credits()
+            .iter()
+            .zip(&self.advertised)
+            .any(|(&now, &then)| then == 0 && now > 0);
+        if freed {
+            let hdr = self.advertise(Header::Idle);
+            return super::link_frame(hdr, &[]).map(|f| Pending::new(f, false));
+        }
+        None
+    }
+
+    /// Sends the ack the peer is owed, ahead of our own frames
+    fn send_ack(&mut self) {
+        let Some(seq) = self.ack_due.take() else { return };
+        let mut hdr = self.advertise(Header::Ack);
+        hdr.seq = seq;
+        let Some(send_buf) = super::link_frame(hdr, &[]) else { return };
+        if self.tunnel.transmit(send_buf, DATA_OPT) {
+            self.resume = self.state;
+            self.state = State::SendAck;
+        }
+    }
+
+    /// Sends a copy of the pending frame, keeping it for another attempt
+    fn send_pending(&mut self) {
+        let Some(pending) = &mut self.pending else {
+            self.state = State::Listen;
+            return;
+        };
+        let Some(send_buf) = mem::alloc_from_slice(&pending.frame) else {
+            return;
+        };
+        if pending.attempts > 0 {
+            STATS.retries.inc();
+        }
+        pending.attempts += 1;
+        if self.tunnel.transmit(send_buf, DATA_OPT) {
+            self.state = State::Send;
+        } else {
+            self.retry();
+        }
+    }
+
+    /// Backs off further before the next attempt at the pending frame, or
+    /// gives up on it
+    fn retry(&mut self) {
+        let Some(pending) = &self.pending else {
+            self.state = State::Listen;
+            return;
+        };
+        self.backoff.collided();
+        if pending.attempts < csma::MAX_ATTEMPTS {
+            self.backoff.start();
+            self.state = State::Backoff;
+            return;
+        }
+
+        crate::dbg::println!(
+            "frame dropped after {} attempts",
+            pending.attempts
+        );
+        if pending.acked {
+            // The peer may have taken the frame and only its acks were lost,
+            // in which case the next frame must not look like it
+            self.tx_seq = !self.tx_seq;
+            self.tunnel.link_missed();
+        }
+        self.pending = None;
+        self.state = State::Listen;
+    }
+
+    fn poll_send(&mut self) {
+        let Some(sent) = self.tunnel.poll_sent() else { return };
+
+        if self.state == State::SendAck {
+            // A lost ack is made up for when the peer sends again
+            self.state = self.resume;
+            return;
+        }
+        match &self.pending {
+            Some(pending) if sent && pending.acked => {
+                self.ack_timeout.set(csma::ACK_TIMEOUT);
+                self.state = State::WaitAck;
+            }
+            _ if sent => {
+                self.pending = None;
+                self.state = State::Listen;
+            }
+            _ => self.retry(),
+        }
+    }
+
+    fn poll_receive(&mut self) {
+        let Some((f, hdr, from_peer)) = self.tunnel.receive() else { return };
+        if from_peer {
+            self.silence_timeout.set(link::SILENCE_PERIOD);
+        }
+        match hdr.kind {
+            Header::Data if from_peer => {
+                // Frames sent again are acked again but only delivered once
+                if self.rx_seq == Some(hdr.seq) {
+                    crate::dbg::println!("duplicate frame dropped");
+                    self.ack_due = Some(hdr.seq);
+                    return;
+                }
+                // A frame the pipeline rejects is left for the peer to send
+                // again
+                if self.tunnel.data_received(&hdr, f) {
+                    self.rx_seq = Some(hdr.seq);
+                    self.ack_due = Some(hdr.seq);
+                }
+            }
+            Header::Ack if from_peer => {
+                if self.state == State::WaitAck && hdr.seq == self.tx_seq {
+                    self.pending = None;
+                    self.tx_seq = !self.tx_seq;
+                    self.backoff.succeeded();
+                    self.state = State::Listen;
+                }
+            }
+            Header::Discover => {
+                let hdr = self.tunnel.session.header(Header::Announce, hdr.src);
+                self.reply = super::announce_frame(hdr, &self.node);
+            }
+            Header::Announce => {
+                self.tunnel.neighbors.record(&f);
+            }
+            Header::Join => {
+                let payload = f.data.get(DATA_START..).unwrap_or_default();
+                self.join(&hdr, payload, from_peer);
+            }
+            Header::Accept if self.join_target == Some(hdr.src) => {
+                self.join_target = None;
+                self.join_timeout.clear();
+                let network = self.tunnel.session.own_network();
+                if f.data.get(DATA_START..DATA_START + 2)
+                    == Some(&network.to_le_bytes())
+                {
+                    self.pair(network, hdr.src);
+                }
+            }
+            // Nobody polls in contention mode
+            Header::Ping => {
+                FrameError::Unexpected.record()
+            }
+            Header::Data | Header::Ack | Header::Accept | Header::Idle => {}
+        }
+    }
+
+    pub fn process(&mut self) {
+        self.poll_host();
+        self.tunnel.poll();
+        self.poll_silence();
+        if self.state == State::Listen {
+            self.tunnel.poll_idle();
+        }
+        if !matches!(self.state, State::Send | State::SendAck) {
+            self.poll_receive();
+        }
+        if self.join_target.is_some() && self.join_timeout.is_expired() {
+            crate::dbg::println!("join was not accepted");
+            self.join_target = None;
+            self.join_timeout.clear();
+        }
+
+        match self.state {
+            State::Send | State::SendAck => self.poll_send(),
+            _ if self.ack_due.is_some() => self.send_ack(),
+            State::Listen => {
+                let Some(pending) = self.next_pending() else { return };
+                self.pending = Some(pending);
+                self.backoff.start();
+                self.state = State::Backoff;
+            }
+            State::Backoff => {
+                if self.backoff.poll(self.tunnel.driver.line_busy()) {
+                    self.send_pending();
+                }
+            }
+            State::WaitAck if self.ack_timeout.is_expired() => self.retry(),
+            State::WaitAck => {}
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Layers a tunnel message goes through between the host queues and a frame
+//!
+//! Going out a message gets its CRC, is compressed and is then sealed. Coming
+//! in the same steps are undone in reverse order.
+
+use super::{
+    compress::{CompressError, Compression},
+    crypto::{self, Crypto, CryptoError},
+    integrity::{self, Integrity, IntegrityError},
+    LinkHeader,
+};
+use crate::{config, mem};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineError {
+    Integrity(IntegrityError),
+    Compress(CompressError),
+    Crypto(CryptoError),
+}
+
+impl From<IntegrityError> for PipelineError {
+    fn from(val: IntegrityError) -> Self {
+        Self::Integrity(val)
+    }
+}
+
+impl From<CompressError> for PipelineError {
+    fn from(val: CompressError) -> Self {
+        Self::Compress(val)
+    }
+}
+
+impl From<CryptoError> for PipelineError {
+    fn from(val: CryptoError) -> Self {
+        Self::Crypto(val)
+    }
+}
+
+pub struct Pipeline {
+    pub integrity: Integrity,
+    pub compression: Compression,
+    pub crypto: Crypto,
+}
+
+impl Pipeline {
+    pub fn load(store: &mut config::Store) -> Self {
+        Self {
+            integrity: Integrity::new(),
+            compression: Compression::new(),
+            crypto: Crypto::load(store),
+        }
+    }
+
+    /// Room left for the message in a frame of `frame_max` bytes. Compression
+    /// is left out since it never makes a message longer.
+    pub fn room(&self, frame_max: usize) -> usize {
+        let sealed = if self.crypto.is_enabled() {
+            crypto::OVERHEAD
+        } else {
+            0
+        };
+        frame_max - LinkHeader::LEN - integrity::CRC_LEN - sealed
+    }
+
+    /// Turns a message from the host into the payload of a frame sent under
+    /// `hdr`, flagging in it how the payload was coded
+    pub(super) fn outgoing(
+        &mut self,
+        hdr: &mut LinkHeader,
+        buf: &mut mem::VecBuf,
+    ) -> Result<(), PipelineError> {
+        self.integrity.append(buf)?;
+        hdr.compressed = self.compression.pack(buf);
+        self.crypto.seal(hdr, buf)?;
+        Ok(())
+    }
+
+    /// Turns the payload of a frame received under `hdr` back into the
+    /// message for the host
+    pub(super) fn incoming(
+        &mut self,
+        hdr: &LinkHeader,
+        buf: &mut mem::VecBuf,
+    ) -> Result<(), PipelineError> {
+        self.crypto.open(hdr, buf)?;
+        self.compression.unpack(buf, hdr.compressed)?;
+        self.integrity.check(buf)?;
+        Ok(())
+    }
+}
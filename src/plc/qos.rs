@@ -0,0 +1,190 @@
+//! Priority classes of tunnel traffic
+//!
+//! Frames the firmware makes itself, such as keepalives, polls and link
+//! management, always go out ahead of any channel data. Each channel then
+//! belongs to a class. Control channels are sent from strictly first.
+//! Interactive and bulk channels share what is left by weight: after
+//! `weight` interactive frames in a row, a waiting bulk frame gets a turn, so
+//! keystrokes never wait behind a whole file transfer and the transfer still
+//! moves while the console is busy.
+
+use crate::usb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Always sent first
+    Control = 0x00,
+    /// Sent ahead of bulk by weight
+    Interactive = 0x01,
+    /// Sent when nothing else waits, or when its turn comes
+    Bulk = 0x02,
+}
+
+impl From<Class> for u8 {
+    fn from(val: Class) -> Self {
+        val as u8
+    }
+}
+
+impl TryFrom<u8> for Class {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Class::*;
+        match v {
+            0x00 => Ok(Control),
+            0x01 => Ok(Interactive),
+            0x02 => Ok(Bulk),
+            v => Err(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Class of each channel
+    pub classes: [Class; usb::CHANNELS],
+    /// Interactive frames sent in a row before bulk gets a turn
+    pub weight: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            // Console, telemetry and file transfer
+            classes: [Class::Interactive, Class::Interactive, Class::Bulk],
+            weight: 4,
+        }
+    }
+}
+
+impl Settings {
+    pub const ENCODED_LEN: usize = usb::CHANNELS + 1;
+
+    /// Encodes the settings as the class of each channel followed by the
+    /// weight
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut buf = [0; Self::ENCODED_LEN];
+        for (b, &class) in buf.iter_mut().zip(&self.classes) {
+            *b = class.into();
+        }
+        buf[usb::CHANNELS] = self.weight;
+        buf
+    }
+
+    /// Reads settings sent by the host, rejecting a weight that would starve
+    /// interactive channels
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::ENCODED_LEN)?;
+        let mut classes = [Class::Bulk; usb::CHANNELS];
+        for (class, &b) in classes.iter_mut().zip(data) {
+            *class = Class::try_from(b).ok()?;
+        }
+        let weight = data[usb::CHANNELS];
+        (weight > 0).then_some(Self { classes, weight })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Qos {
+    settings: Settings,
+    /// Interactive frames sent since bulk last had a turn
+    streak: u8,
+}
+
+impl Qos {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn configure(&mut self, settings: Settings) {
+        self.settings = settings;
+        self.streak = 0;
+    }
+
+    pub fn class(&self, channel: usize) -> Class {
+        self.settings.classes[channel]
+    }
+
+    /// Picks the class to send from next among those `waiting` says have
+    /// data ready
+    pub(super) fn pick(
+        &self,
+        waiting: impl Fn(Class) -> bool,
+    ) -> Option<Class> {
+        if waiting(Class::Control) {
+            return Some(Class::Control);
+        }
+        match (waiting(Class::Interactive), waiting(Class::Bulk)) {
+            (true, true) if self.streak >= self.settings.weight => {
+                Some(Class::Bulk)
+            }
+            (true, _) => Some(Class::Interactive),
+            (false, true) => Some(Class::Bulk),
+            (false, false) => None,
+        }
+    }
+
+    /// Records a frame sent from `class`
+    pub(super) fn sent(&mut self, class: Class) {
+        match class {
+            Class::Control => {}
+            Class::Interactive => self.streak = self.streak.saturating_add(1),
+            Class::Bulk => self.streak = 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_goes_first() {
+        let qos = Qos::new();
+        assert_eq!(qos.pick(|_| true), Some(Class::Control));
+        assert_eq!(qos.pick(|_| false), None);
+    }
+
+    #[test]
+    fn bulk_gets_a_turn_after_weight_interactive_frames() {
+        let mut qos = Qos::new();
+        let weight = qos.settings().weight;
+        let busy = |class| class != Class::Control;
+        for _ in 0..weight {
+            assert_eq!(qos.pick(busy), Some(Class::Interactive));
+            qos.sent(Class::Interactive);
+        }
+        assert_eq!(qos.pick(busy), Some(Class::Bulk));
+        qos.sent(Class::Bulk);
+        assert_eq!(qos.pick(busy), Some(Class::Interactive));
+    }
+
+    #[test]
+    fn a_class_alone_is_always_picked() {
+        let mut qos = Qos::new();
+        for _ in 0..2 * qos.settings().weight {
+            qos.sent(Class::Interactive);
+        }
+        let bulk = |class| class == Class::Bulk;
+        let interactive = |class| class == Class::Interactive;
+        assert_eq!(qos.pick(bulk), Some(Class::Bulk));
+        assert_eq!(qos.pick(interactive), Some(Class::Interactive));
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = Settings {
+            classes: [Class::Control, Class::Bulk, Class::Interactive],
+            weight: 7,
+        };
+        assert_eq!(Settings::decode(&settings.encode()), Some(settings));
+        assert_eq!(Settings::decode(&[0, 1, 2, 0]), None);
+        assert_eq!(Settings::decode(&[0, 1, 3, 1]), None);
+        assert_eq!(Settings::decode(&[0, 1, 2]), None);
+    }
+}
@@ -0,0 +1,403 @@
+//! Modulation picked per frame from how well the peer comes through
+//!
+//! `DATA_OPT` fixes the modulation of every frame, which wastes airtime on a
+//! clean line and loses frames on a noisy one. Frames to the peer instead
+//! climb a ladder from the most robust modulation of the ST7580 to the
+//! fastest. Each side smooths the signal to noise ratio of the frames it
+//! hears from the peer and reports it every `REPORT_FRAMES` of them as a
+//! link control message, since noise near the receiver need not be heard at
+//! the sender. A step up needs a reported ratio `HYSTERESIS` above what the
+//! next step needs and `HOLD_REPORTS` reports on the current step, while a
+//! ratio below what the current step needs or `LOSS_LIMIT` frames lost in a
+//! row step down at once. A frame is lost when a ping goes unanswered or
+//! the leader's next ping says it missed part of the follower's burst. Each
+//! side tells the other which modulations it receives as a link control
+//! message, every `MODES_PERIOD` until the other confirms, and frames only
+//! use those, so until the peer's answer arrives, frames keep the
+//! modulation of `DATA_OPT`. Frames to no peer in particular always do.
+
+use super::{Indication, DATA_OPT};
+use crate::st7580;
+
+/// Bits of the transmission options and of an indication's options holding
+/// the modulation
+const MODULATION_MASK: u8 = 0b0111_0000;
+const MODULATION_SHIFT: u32 = 4;
+
+/// SNR in dB above what the next step needs before stepping up to it
+pub const HYSTERESIS: u8 = 3;
+/// Frames heard from the peer between reports of their SNR
+pub const REPORT_FRAMES: u8 = 8;
+/// Reports from the peer on a step before stepping up from it
+pub const HOLD_REPORTS: u8 = 2;
+/// Frames lost in a row before stepping down
+pub const LOSS_LIMIT: u8 = 2;
+/// Time between tellings of our modulations until the peer confirms them,
+/// in milliseconds
+pub const MODES_PERIOD: u32 = 2000;
+/// Share of each new SNR reading taken into the smoothed one
+const SNR_GAIN: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modulation {
+    Bpsk = 0x00,
+    Qpsk = 0x01,
+    Psk8 = 0x02,
+    BpskCoded = 0x04,
+    QpskCoded = 0x05,
+    /// Coded B-PSK with peak noise avoidance
+    BpskCodedPna = 0x07,
+}
+
+impl TryFrom<u8> for Modulation {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Modulation::*;
+        match v {
+            0x00 => Ok(Bpsk),
+            0x01 => Ok(Qpsk),
+            0x02 => Ok(Psk8),
+            0x04 => Ok(BpskCoded),
+            0x05 => Ok(QpskCoded),
+            0x07 => Ok(BpskCodedPna),
+            v => Err(v),
+        }
+    }
+}
+
+impl Modulation {
+    /// Modulation of the transmission or indication options `opts`
+    pub fn of(opts: u8) -> Result<Self, u8> {
+        ((opts & MODULATION_MASK) >> MODULATION_SHIFT).try_into()
+    }
+
+    /// Bit of the modulation in a set of modes
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Modulations from the most robust to the fastest, with the SNR in dB each
+/// needs
+const LADDER: [(Modulation, u8); 6] = [
+    (Modulation::BpskCodedPna, 0),
+    (Modulation::BpskCoded, 3),
+    (Modulation::QpskCoded, 6),
+    (Modulation::Bpsk, 8),
+    (Modulation::Qpsk, 11),
+    (Modulation::Psk8, 15),
+];
+
+/// Every modulation of the ladder as a set of modes
+pub const ALL_MODES: u8 = {
+    let mut modes = 0;
+    let mut idx = 0;
+    while idx < LADDER.len() {
+        modes |= 1 << LADDER[idx].0 as u8;
+        idx += 1;
+    }
+    modes
+};
+
+/// Step of the ladder `DATA_OPT` was built with
+fn base_step() -> usize {
+    LADDER
+        .iter()
+        .position(|&(m, _)| Ok(m) == Modulation::of(DATA_OPT))
+        .unwrap_or(0)
+}
+
+#[derive(Debug)]
+pub struct Rate {
+    /// Modulations we receive, told to the peer
+    modes: u8,
+    /// Modulations the peer receives, once it said so
+    peer_modes: Option<u8>,
+    /// Whether the peer confirmed it knows our modulations
+    peer_knows: bool,
+    modes_timeout: st7580::Timeout,
+    step: usize,
+    /// Smoothed SNR of frames from the peer in dB, until the first one
+    snr: Option<f32>,
+    /// Frames heard from the peer since our last report
+    unreported: u8,
+    /// SNR of our frames the peer last reported in dB
+    peer_snr: Option<u8>,
+    /// Reports from the peer since the last step
+    held: u8,
+    /// Frames lost in a row
+    losses: u8,
+}
+
+impl Rate {
+    pub fn new() -> Self {
+        let mut modes_timeout = st7580::Timeout::default();
+        modes_timeout.set(1);
+        Self {
+            modes: ALL_MODES,
+            peer_modes: None,
+            peer_knows: false,
+            modes_timeout,
+            step: base_step(),
+            snr: None,
+            unreported: 0,
+            peer_snr: None,
+            held: 0,
+            losses: 0,
+        }
+    }
+
+    /// Modulations we receive
+    pub fn modes(&self) -> u8 {
+        self.modes
+    }
+
+    /// Restricts the modulations we receive to `modes`, unless that leaves
+    /// none of the ladder
+    pub fn set_modes(&mut self, modes: u8) -> bool {
+        if modes & ALL_MODES == 0 || modes & !ALL_MODES != 0 {
+            return false;
+        }
+        self.modes = modes;
+        self.retell();
+        true
+    }
+
+    /// Takes the modulations the peer receives, along with whether it knows
+    /// ours
+    pub fn peer_modes(&mut self, modes: u8, knows_ours: bool) {
+        self.peer_modes = Some(modes & ALL_MODES);
+        self.peer_knows = knows_ours;
+    }
+
+    /// Whether the peer told us which modulations it receives
+    pub fn knows_peer(&self) -> bool {
+        self.peer_modes.is_some()
+    }
+
+    /// Whether the peer has yet to confirm it knows our modulations
+    pub fn is_untold(&self) -> bool {
+        !self.peer_knows
+    }
+
+    /// Has our modulations told to the peer again, as when it may have
+    /// forgotten them
+    pub fn retell(&mut self) {
+        self.peer_knows = false;
+        self.modes_timeout.set(1);
+    }
+
+    /// Whether to tell the peer our modulations, as long as either side
+    /// does not know the other's
+    pub fn modes_due(&self) -> bool {
+        let known = self.knows_peer() && self.peer_knows;
+        !known && self.modes_timeout.is_expired()
+    }
+
+    /// Records that our modulations went out to the peer
+    pub fn modes_told(&mut self) {
+        self.modes_timeout.set(MODES_PERIOD);
+    }
+
+    /// Forgets the peer, as when pairing with another
+    pub fn forget_peer(&mut self) {
+        *self = Self {
+            modes: self.modes,
+            ..Self::new()
+        };
+    }
+
+    /// Takes the reception of a frame from the peer, returning the SNR to
+    /// report to it when a report is due
+    pub(super) fn heard(&mut self, frame: &st7580::Frame) -> Option<u8> {
+        let ind = Indication::parse(&frame.data)?;
+        let reading = ind.snr as f32;
+        let snr = match self.snr {
+            Some(snr) => snr + (reading - snr) * SNR_GAIN,
+            None => reading,
+        };
+        self.snr = Some(snr);
+        self.unreported = self.unreported.saturating_add(1);
+        if self.unreported < REPORT_FRAMES {
+            return None;
+        }
+        self.unreported = 0;
+        Some(snr as u8)
+    }
+
+    /// Takes the SNR the peer reports for our frames, stepping on it
+    pub fn reported(&mut self, snr: u8) {
+        self.peer_snr = Some(snr);
+        self.losses = 0;
+        self.held = self.held.saturating_add(1);
+
+        let needs = |step: usize| LADDER[step].1;
+        if snr < needs(self.step) && self.step > 0 {
+            self.step_to(self.step - 1);
+        } else if self.step + 1 < LADDER.len()
+            && self.held >= HOLD_REPORTS
+            && snr >= needs(self.step + 1).saturating_add(HYSTERESIS)
+        {
+            self.step_to(self.step + 1);
+        }
+    }
+
+    /// Takes a frame to the peer known to have come through
+    pub fn answered(&mut self) {
+        self.losses = 0;
+    }
+
+    /// Takes a frame to the peer that went unanswered
+    pub fn lost(&mut self) {
+        self.losses = self.losses.saturating_add(1);
+        if self.losses >= LOSS_LIMIT && self.step > 0 {
+            self.step_to(self.step - 1);
+        }
+    }
+
+    fn step_to(&mut self, step: usize) {
+        crate::dbg::println!("modulation step {} -> {}", self.step, step);
+        self.step = step;
+        self.held = 0;
+        self.losses = 0;
+    }
+
+    /// Modulation for the next frame to the peer: the fastest the peer
+    /// receives at or below the current step, else the most robust it does
+    pub fn modulation(&self) -> Option<Modulation> {
+        let peer_modes = self.peer_modes?;
+        let takes = |&(m, _): &(Modulation, u8)| peer_modes & m.bit() != 0;
+        LADDER[..=self.step]
+            .iter()
+            .rev()
+            .find(|step| takes(step))
+            .or_else(|| LADDER.iter().find(|step| takes(step)))
+            .map(|&(m, _)| m)
+    }
+
+    /// Transmission options for the next frame to the peer
+    pub fn opts(&self) -> u8 {
+        match self.modulation() {
+            Some(m) => {
+                (DATA_OPT & !MODULATION_MASK) | (m as u8) << MODULATION_SHIFT
+            }
+            None => DATA_OPT,
+        }
+    }
+
+    /// Encodes the state for the host as `[modulation, snr, modes, peer
+    /// modes, peer snr]`, with the modulation of frames to the peer, the
+    /// smoothed SNR of frames from the peer in dB, the sets of modulations as
+    /// bits by modulation code and the SNR the peer last reported for ours
+    pub fn encode(&self) -> [u8; 5] {
+        let modulation = (self.opts() & MODULATION_MASK) >> MODULATION_SHIFT;
+        let snr = self.snr.map_or(0, |snr| snr as u8);
+        [
+            modulation,
+            snr,
+            self.modes,
+            self.peer_modes.unwrap_or(0),
+            self.peer_snr.unwrap_or(0),
+        ]
+    }
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem;
+
+    fn frame(snr: u8) -> st7580::Frame {
+        st7580::Frame {
+            data: mem::alloc_from_slice(&[0, 0, snr, 0]).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn known_peer() -> Rate {
+        let mut rate = Rate::new();
+        rate.peer_modes(ALL_MODES, true);
+        rate
+    }
+
+    #[test]
+    fn frames_keep_data_opt_until_the_peer_tells_its_modes() {
+        let mut rate = Rate::new();
+        assert_eq!(rate.modulation(), None);
+        assert_eq!(rate.opts(), DATA_OPT);
+
+        let slow = Modulation::Bpsk.bit() | Modulation::BpskCoded.bit();
+        rate.peer_modes(slow, true);
+        assert_eq!(rate.modulation(), Some(Modulation::Bpsk));
+        assert_eq!(Modulation::of(rate.opts()), Ok(Modulation::Bpsk));
+    }
+
+    #[test]
+    fn reports_step_down_at_once_and_up_with_hysteresis() {
+        let mut rate = known_peer();
+        assert_eq!(rate.modulation(), Some(Modulation::Psk8));
+        rate.reported(10);
+        assert_eq!(rate.modulation(), Some(Modulation::Qpsk));
+        rate.reported(10);
+        assert_eq!(rate.modulation(), Some(Modulation::Bpsk));
+
+        // Enough for Q-PSK but short of the hysteresis
+        rate.reported(13);
+        rate.reported(13);
+        assert_eq!(rate.modulation(), Some(Modulation::Bpsk));
+        rate.reported(14);
+        assert_eq!(rate.modulation(), Some(Modulation::Qpsk));
+
+        // A single good report is not enough
+        rate.reported(30);
+        assert_eq!(rate.modulation(), Some(Modulation::Qpsk));
+        rate.reported(30);
+        assert_eq!(rate.modulation(), Some(Modulation::Psk8));
+        assert_eq!(rate.encode()[4], 30);
+    }
+
+    #[test]
+    fn losses_in_a_row_step_down() {
+        let mut rate = known_peer();
+        rate.lost();
+        rate.answered();
+        rate.lost();
+        assert_eq!(rate.modulation(), Some(Modulation::Psk8));
+        rate.lost();
+        assert_eq!(rate.modulation(), Some(Modulation::Qpsk));
+    }
+
+    #[test]
+    fn snr_is_reported_every_report_frames() {
+        mem::grow_for_tests();
+        let mut rate = Rate::new();
+        for _ in 1..REPORT_FRAMES {
+            assert_eq!(rate.heard(&frame(12)), None);
+        }
+        assert_eq!(rate.heard(&frame(12)), Some(12));
+        assert_eq!(rate.heard(&frame(12)), None);
+        // Our own reception alone never steps
+        assert_eq!(rate.step, base_step());
+    }
+
+    #[test]
+    fn modes_are_told_until_the_peer_confirms() {
+        let mut rate = Rate::new();
+        assert!(rate.is_untold());
+        rate.peer_modes(ALL_MODES, true);
+        assert!(!rate.is_untold());
+
+        assert!(!rate.set_modes(0));
+        assert!(!rate.set_modes(0x08));
+        assert!(!rate.is_untold());
+        assert!(rate.set_modes(Modulation::BpskCoded.bit()));
+        assert!(rate.is_untold());
+    }
+}
@@ -0,0 +1,616 @@
+//! Health of the far end of the tunnel, fetched over the link
+//!
+//! Each side only sees the link from its own end, so a node can ask its peer
+//! for a report of the peer's link statistics, how the peer last heard us,
+//! the status of its modem, its uptime and its firmware version. Requests and
+//! reports are link control messages, as are offers of new modem settings
+//! made through `modem`, firmware images streamed by `update`, the time
+//! exchange of `time`, the switch of the follower's loopback mode and the
+//! modulations each side receives for `rate`. The kind byte has no room left
+//! for another kind, so they travel as data on `CONTROL_CHANNEL`, which no
+//! host channel uses, and are sealed and checked like any tunnel data.
+//! Loopback ends along with the link or the pairing it was turned on for.
+
+use super::{
+    host::Host,
+    modem::{Modem, Settings},
+    time::{self, Clock},
+    update::{self, Update},
+    Indication,
+};
+use crate::{
+    config, mem, st7580,
+    stats::{Stats, STATS},
+    usb,
+};
+
+/// Channel of the link header marking a link control message
+pub const CONTROL_CHANNEL: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    /// Asks the peer for a report
+    Request = 0x01,
+    /// Answers a request, payload is a `Report`
+    Report = 0x02,
+    /// Offers modem settings, payload is `[delay, settings]` with the time
+    /// left until the switch in milliseconds
+    Configure = 0x03,
+    /// Takes offered settings, payload is the settings
+    Configured = 0x04,
+    /// Carries a piece of a firmware image, payload is as laid out in
+    /// `update`
+    Update = 0x05,
+    /// Answers an update message, payload is `[status, offset]`
+    UpdateState = 0x06,
+    /// Asks for the leader's time, payload is `[t1]` and padding
+    TimeRequest = 0x07,
+    /// Answers a time request, payload is `[t1, t2, t3]`
+    TimeAnswer = 0x08,
+    /// Turns loopback on or off with a payload of `[on]`, or only asks
+    /// whether it is on with none
+    Loopback = 0x09,
+    /// Answers a loopback message, payload is `[on]`
+    LoopbackState = 0x0A,
+    /// Tells the modulations the sender receives, payload is `[modes,
+    /// flags]` with `MODES_KNOWN` when it already knows those of the
+    /// receiver, which answers if not or if `MODES_ASK` is set
+    Modes = 0x0B,
+    /// Reports the smoothed SNR of frames from the receiver, payload is
+    /// `[snr]` in dB
+    Snr = 0x0C,
+}
+
+/// Modes flag telling the sender knows the receiver's modulations
+pub const MODES_KNOWN: u8 = 0x01;
+/// Modes flag asking the receiver to confirm it took the modulations
+pub const MODES_ASK: u8 = 0x02;
+
+impl From<Message> for u8 {
+    fn from(val: Message) -> Self {
+        val as u8
+    }
+}
+
+impl TryFrom<u8> for Message {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Message::*;
+        match v {
+            0x01 => Ok(Request),
+            0x02 => Ok(Report),
+            0x03 => Ok(Configure),
+            0x04 => Ok(Configured),
+            0x05 => Ok(Update),
+            0x06 => Ok(UpdateState),
+            0x07 => Ok(TimeRequest),
+            0x08 => Ok(TimeAnswer),
+            0x09 => Ok(Loopback),
+            0x0A => Ok(LoopbackState),
+            0x0B => Ok(Modes),
+            0x0C => Ok(Snr),
+            v => Err(v),
+        }
+    }
+}
+
+/// A node's view of the link as sent to its peer
+#[derive(Debug, Clone, Copy)]
+pub struct Report {
+    /// Link statistics as laid out by `Stats::encode`
+    pub stats: [u8; Stats::ENCODED_LEN],
+    /// Reception of the last frame heard from its peer
+    pub last_rx: Indication,
+    /// Last status byte of its modem
+    pub modem_status: u8,
+    /// Milliseconds since it booted
+    pub uptime: u32,
+    pub version: [u8; 3],
+}
+
+impl Report {
+    pub const HEALTH_LEN: usize = 11;
+    pub const ENCODED_LEN: usize = Stats::ENCODED_LEN + Self::HEALTH_LEN;
+
+    fn local(last_rx: Indication) -> Self {
+        Self {
+            stats: STATS.encode(),
+            last_rx,
+            modem_status: STATS.modem_status(),
+            uptime: st7580::now(),
+            version: crate::FIRMWARE_VERSION,
+        }
+    }
+
+    /// Encodes everything but the statistics as `[opts, pga, snr, modem
+    /// status, uptime, version]`
+    pub fn encode_health(&self) -> [u8; Self::HEALTH_LEN] {
+        let Indication { opts, pga, snr, .. } = self.last_rx;
+        let mut buf = [0; Self::HEALTH_LEN];
+        buf[..4].copy_from_slice(&[opts, pga, snr, self.modem_status]);
+        buf[4..8].copy_from_slice(&self.uptime.to_le_bytes());
+        buf[8..].copy_from_slice(&self.version);
+        buf
+    }
+
+    fn encode(&self, buf: &mut mem::VecBuf) {
+        buf.extend_from_slice(&self.stats).unwrap();
+        buf.extend_from_slice(&self.encode_health()).unwrap();
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::ENCODED_LEN)?;
+        let (stats, health) = data.split_at(Stats::ENCODED_LEN);
+        let &[opts, pga, snr, modem_status, u0, u1, u2, u3, v0, v1, v2] =
+            health
+        else {
+            return None;
+        };
+        Some(Self {
+            stats: stats.try_into().ok()?,
+            last_rx: Indication {
+                opts,
+                pga,
+                snr,
+                zc_delay: 0,
+            },
+            modem_status,
+            uptime: u32::from_le_bytes([u0, u1, u2, u3]),
+            version: [v0, v1, v2],
+        })
+    }
+}
+
+/// Message waiting to go to the peer
+#[derive(Debug, Clone)]
+enum Outgoing {
+    Request,
+    Report,
+    /// Settings to switch to when `st7580::now_us` reaches the deadline
+    Configure(Settings, u32),
+    Configured(Settings),
+    /// Update message passed on from the host
+    Update(heapless::Vec<u8, { usb::CONTROL_PACKET_SIZE }>),
+    UpdateState([u8; update::STATE_LEN]),
+    TimeRequest,
+    /// Answer to the request sent at `t1` and received at `t2`
+    TimeAnswer(u32, u32),
+    /// Loopback to switch to, or `None` to only ask
+    Loopback(Option<bool>),
+    LoopbackState(bool),
+    /// Modulations we receive and the modes flags
+    Modes(u8, u8),
+    Snr(u8),
+}
+
+/// Messages that can wait at once
+const OUTGOING_LEN: usize = 6;
+/// Frames that can wait to be sent back in loopback mode
+const LOOPBACK_LEN: usize = 8;
+
+#[derive(Debug, Default)]
+pub struct Remote {
+    last_rx: Indication,
+    outgoing: heapless::Deque<Outgoing, OUTGOING_LEN>,
+    /// Whether data from the peer is sent back to it instead of to the host
+    loopback: bool,
+    /// Data from the peer waiting to be sent back, with its channel
+    looped: heapless::Deque<(u8, mem::BufBox), LOOPBACK_LEN>,
+    /// Modulations the peer receives and its modes flags, until taken
+    peer_modes: Option<(u8, u8)>,
+    /// SNR the peer last reported for our frames, until taken
+    peer_snr: Option<u8>,
+}
+
+impl Remote {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps how a frame from the peer was received for the next report
+    pub(super) fn heard(&mut self, frame: &st7580::Frame) {
+        if let Some(ind) = Indication::parse(&frame.data) {
+            self.last_rx = ind;
+        }
+    }
+
+    /// Asks the peer for a report with the next frame to it
+    pub fn request(&mut self) {
+        self.queue(Outgoing::Request);
+    }
+
+    /// Offers the peer modem settings to switch to in `delay` milliseconds
+    pub fn configure(&mut self, settings: Settings, delay: u32) {
+        let delay_us = delay.saturating_mul(1000);
+        let deadline = st7580::now_us().wrapping_add(delay_us);
+        self.queue(Outgoing::Configure(settings, deadline));
+    }
+
+    /// Tells the follower we heard it under the settings on trial, once
+    /// however often it is asked before the message goes out
+    pub fn configured(&mut self, settings: Settings) {
+        let queued = self
+            .outgoing
+            .iter()
+            .any(|message| matches!(message, Outgoing::Configured(_)));
+        if !queued {
+            self.queue(Outgoing::Configured(settings));
+        }
+    }
+
+    /// Asks the leader for its time with the next frame to it
+    pub fn time_request(&mut self) {
+        self.queue(Outgoing::TimeRequest);
+    }
+
+    /// Turns the peer's loopback on or off, or only asks about it with
+    /// `None`, returning whether the message was queued
+    pub fn loopback(&mut self, on: Option<bool>) -> bool {
+        self.try_queue(Outgoing::Loopback(on))
+    }
+
+    /// Whether the leader turned loopback on
+    pub fn is_looping_back(&self) -> bool {
+        self.loopback
+    }
+
+    /// Turns loopback off and drops the data waiting to be sent back, once
+    /// the peer that turned it on is gone
+    pub(super) fn end_loopback(&mut self) {
+        if self.loopback {
+            crate::dbg::println!("loopback ended");
+        }
+        self.loopback = false;
+        self.looped.clear();
+    }
+
+    /// Queues data from the peer to be sent back to it unchanged
+    pub(super) fn loop_back(&mut self, channel: u8, data: mem::BufBox) {
+        if let Err(_looped) = self.looped.push_back((channel, data)) {
+            crate::dbg::println!("looped data dropped");
+            STATS.queue_drops.inc();
+        }
+    }
+
+    /// Takes the next data to send back, along with its channel
+    pub(super) fn take_looped(&mut self) -> Option<(u8, mem::BufBox)> {
+        self.looped.pop_front()
+    }
+
+    /// Room left for data to send back while in loopback
+    pub fn looped_room(&self) -> Option<u8> {
+        let free = LOOPBACK_LEN - self.looped.len();
+        self.loopback.then_some(free as u8)
+    }
+
+    /// Tells the peer the modulations we receive, whether we know its and
+    /// whether it should confirm, replacing any telling not yet sent
+    pub fn modes(&mut self, modes: u8, knows_peer: bool, ask: bool) {
+        let flags = if knows_peer { MODES_KNOWN } else { 0 }
+            | if ask { MODES_ASK } else { 0 };
+        let queued =
+            self.outgoing.iter_mut().find_map(|message| match message {
+                Outgoing::Modes(m, f) => Some((m, f)),
+                _ => None,
+            });
+        match queued {
+            Some((m, f)) => (*m, *f) = (modes, flags),
+            None => self.queue(Outgoing::Modes(modes, flags)),
+        }
+    }
+
+    /// Takes the modulations the peer last said it receives, along with its
+    /// modes flags
+    pub(super) fn take_peer_modes(&mut self) -> Option<(u8, u8)> {
+        self.peer_modes.take()
+    }
+
+    /// Reports the SNR of frames from the peer to it, replacing any report
+    /// not yet sent
+    pub fn snr(&mut self, snr: u8) {
+        let queued =
+            self.outgoing.iter_mut().find_map(|message| match message {
+                Outgoing::Snr(s) => Some(s),
+                _ => None,
+            });
+        match queued {
+            Some(s) => *s = snr,
+            None => self.queue(Outgoing::Snr(snr)),
+        }
+    }
+
+    /// Takes the SNR the peer last reported for our frames
+    pub(super) fn take_peer_snr(&mut self) -> Option<u8> {
+        self.peer_snr.take()
+    }
+
+    /// Passes an update message from the host on to the peer, returning
+    /// whether it was queued
+    pub fn update(&mut self, message: &[u8]) -> bool {
+        match heapless::Vec::from_slice(message) {
+            Ok(message) => self.try_queue(Outgoing::Update(message)),
+            Err(()) => {
+                crate::dbg::println!("update message too long");
+                false
+            }
+        }
+    }
+
+    fn queue(&mut self, message: Outgoing) {
+        self.try_queue(message);
+    }
+
+    /// Queues a message, returning whether there was room for it
+    fn try_queue(&mut self, message: Outgoing) -> bool {
+        if let Err(_message) = self.outgoing.push_back(message) {
+            crate::dbg::println!("control message dropped {:?}", _message);
+            STATS.queue_drops.inc();
+            return false;
+        }
+        true
+    }
+
+    /// Whether a message or data sent back waits to go to the peer
+    pub fn is_pending(&self) -> bool {
+        !self.outgoing.is_empty() || !self.looped.is_empty()
+    }
+
+    /// Takes the message waiting for the peer, to be sent on
+    /// `CONTROL_CHANNEL`
+    pub(super) fn take_message(&mut self) -> Option<mem::BufBox> {
+        if self.outgoing.is_empty() {
+            return None;
+        }
+        let mut buf = mem::alloc()?;
+        match self.outgoing.pop_front()? {
+            Outgoing::Request => buf.push(Message::Request.into()).unwrap(),
+            Outgoing::Report => {
+                buf.push(Message::Report.into()).unwrap();
+                Report::local(self.last_rx).encode(&mut buf);
+            }
+            Outgoing::Configure(settings, deadline) => {
+                let delay_us = deadline.wrapping_sub(st7580::now_us());
+                // Late past the deadline, the offer is already cancelled
+                let delay = if delay_us > i32::MAX as u32 {
+                    0
+                } else {
+                    delay_us / 1000
+                };
+                buf.push(Message::Configure.into()).unwrap();
+                buf.extend_from_slice(&delay.to_le_bytes()).unwrap();
+                buf.extend_from_slice(&settings.encode()).unwrap();
+            }
+            Outgoing::Configured(settings) => {
+                buf.push(Message::Configured.into()).unwrap();
+                buf.extend_from_slice(&settings.encode()).unwrap();
+            }
+            Outgoing::Update(message) => {
+                buf.push(Message::Update.into()).unwrap();
+                buf.extend_from_slice(&message).unwrap();
+            }
+            Outgoing::UpdateState(state) => {
+                buf.push(Message::UpdateState.into()).unwrap();
+                buf.extend_from_slice(&state).unwrap();
+            }
+            // Stamped as late as possible, right before the frame is sealed
+            Outgoing::TimeRequest => {
+                let t1 = st7580::now_us();
+                buf.push(Message::TimeRequest.into()).unwrap();
+                buf.extend_from_slice(&t1.to_le_bytes()).unwrap();
+                buf.resize(1 + time::MESSAGE_LEN, 0).unwrap();
+            }
+            Outgoing::TimeAnswer(t1, t2) => {
+                let t3 = time::at(st7580::now_us());
+                buf.push(Message::TimeAnswer.into()).unwrap();
+                for t in [t1, t2, t3] {
+                    buf.extend_from_slice(&t.to_le_bytes()).unwrap();
+                }
+            }
+            Outgoing::Loopback(on) => {
+                buf.push(Message::Loopback.into()).unwrap();
+                if let Some(on) = on {
+                    buf.push(on.into()).unwrap();
+                }
+            }
+            Outgoing::LoopbackState(on) => {
+                buf.push(Message::LoopbackState.into()).unwrap();
+                buf.push(on.into()).unwrap();
+            }
+            Outgoing::Modes(modes, flags) => {
+                buf.push(Message::Modes.into()).unwrap();
+                buf.extend_from_slice(&[modes, flags]).unwrap();
+            }
+            Outgoing::Snr(snr) => {
+                buf.push(Message::Snr.into()).unwrap();
+                buf.push(snr).unwrap();
+            }
+        }
+        Some(buf)
+    }
+
+    /// Handles a message from the peer, answering requests, handing reports
+    /// and update and loopback answers to the host, settings to the modem,
+    /// images to the free firmware slot and the leader's time to the clock
+    pub(super) fn received(
+        &mut self,
+        data: &[u8],
+        host: &mut Host,
+        modem: &mut Modem,
+        update: &mut Update,
+        clock: &mut Clock,
+        store: &mut config::Store,
+    ) {
+        let received_at = st7580::now_us();
+        let Some((&kind, payload)) = data.split_first() else { return };
+        match Message::try_from(kind) {
+            Ok(Message::Request) => self.queue(Outgoing::Report),
+            Ok(Message::Report) => match Report::decode(payload) {
+                Some(report) => host.send_remote(&report),
+                None => {
+                    crate::dbg::println!("malformed report");
+                }
+            },
+            Ok(Message::Configure) => {
+                let Some(&[d0, d1, d2, d3]) = payload.get(..4) else { return };
+                let delay = u32::from_le_bytes([d0, d1, d2, d3]);
+                match Settings::decode(&payload[4..]) {
+                    Some(settings) if modem.schedule(delay, settings) => {
+                        self.queue(Outgoing::Configured(settings))
+                    }
+                    _ => {
+                        crate::dbg::println!("modem settings declined");
+                    }
+                }
+            }
+            Ok(Message::Configured) => match Settings::decode(payload) {
+                Some(settings) => {
+                    if let Some(outcome) = modem.accepted(settings, store) {
+                        host.modem_changed(outcome, modem);
+                    }
+                }
+                None => {
+                    crate::dbg::println!("malformed modem settings");
+                }
+            },
+            Ok(Message::Update) => {
+                let state = update.received(payload, store);
+                self.queue(Outgoing::UpdateState(state));
+            }
+            Ok(Message::UpdateState) if payload.len() == update::STATE_LEN => {
+                host.send_update(payload)
+            }
+            Ok(Message::UpdateState) => {
+                crate::dbg::println!("malformed update state");
+            }
+            Ok(Message::TimeRequest) => match payload.get(..4) {
+                Some(&[a, b, c, d]) => {
+                    let t1 = u32::from_le_bytes([a, b, c, d]);
+                    let t2 = time::at(received_at);
+                    self.queue(Outgoing::TimeAnswer(t1, t2));
+                }
+                _ => {
+                    crate::dbg::println!("malformed time request");
+                }
+            },
+            Ok(Message::TimeAnswer) => {
+                let mut stamps = payload
+                    .chunks_exact(4)
+                    .map(|t| u32::from_le_bytes(t.try_into().unwrap()));
+                match (stamps.next(), stamps.next(), stamps.next()) {
+                    (Some(t1), Some(t2), Some(t3)) => {
+                        clock.answered(t1, t2, t3, received_at)
+                    }
+                    _ => {
+                        crate::dbg::println!("malformed time answer");
+                    }
+                }
+            }
+            Ok(Message::Loopback) => {
+                match payload {
+                    [] => {}
+                    [on @ (0 | 1)] => {
+                        self.loopback = *on != 0;
+                        crate::dbg::println!("loopback {}", self.loopback);
+                    }
+                    _ => {
+                        crate::dbg::println!("malformed loopback message");
+                    }
+                }
+                self.queue(Outgoing::LoopbackState(self.loopback));
+            }
+            Ok(Message::LoopbackState) => match payload {
+                &[on] => host.send_loopback(on != 0),
+                _ => {
+                    crate::dbg::println!("malformed loopback state");
+                }
+            },
+            Ok(Message::Modes) => match payload {
+                &[modes, flags] => self.peer_modes = Some((modes, flags)),
+                _ => {
+                    crate::dbg::println!("malformed modes");
+                }
+            },
+            Ok(Message::Snr) => match payload {
+                &[snr] => self.peer_snr = Some(snr),
+                _ => {
+                    crate::dbg::println!("malformed snr");
+                }
+            },
+            Err(_v) => {
+                crate::dbg::println!("unknown control message {}", _v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_codes_round_trip() {
+        let mut known = 0;
+        for code in 0..=u8::MAX {
+            if let Ok(message) = Message::try_from(code) {
+                assert_eq!(u8::from(message), code);
+                known += 1;
+            }
+        }
+        assert_eq!(known, 12);
+    }
+
+    #[test]
+    fn report_round_trip() {
+        let report = Report {
+            stats: core::array::from_fn(|i| i as u8),
+            last_rx: Indication {
+                opts: 0x24,
+                pga: 3,
+                snr: 17,
+                zc_delay: 9,
+            },
+            modem_status: 0x40,
+            uptime: 0x1234_5678,
+            version: [1, 2, 3],
+        };
+        let mut buf = mem::VecBuf::new();
+        report.encode(&mut buf);
+        assert_eq!(buf.len(), Report::ENCODED_LEN);
+
+        let decoded = Report::decode(&buf).unwrap();
+        assert_eq!(decoded.stats, report.stats);
+        assert_eq!(decoded.encode_health(), report.encode_health());
+        assert_eq!(decoded.last_rx.zc_delay, 0);
+        assert!(Report::decode(&buf[..Report::ENCODED_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn unsent_modes_and_snr_are_replaced() {
+        let mut remote = Remote::new();
+        remote.modes(0xB7, false, true);
+        remote.snr(5);
+        remote.modes(0x01, true, false);
+        remote.snr(9);
+
+        let mut outgoing = remote.outgoing.iter();
+        let modes = outgoing.next();
+        assert!(matches!(modes, Some(Outgoing::Modes(0x01, MODES_KNOWN))));
+        assert!(matches!(outgoing.next(), Some(Outgoing::Snr(9))));
+        assert!(outgoing.next().is_none());
+    }
+
+    #[test]
+    fn loopback_ends_with_its_data() {
+        mem::grow_for_tests();
+        let mut remote = Remote::new();
+        remote.loopback = true;
+        remote.loop_back(0, mem::alloc_from_slice(&[1, 2, 3]).unwrap());
+        assert_eq!(remote.looped_room(), Some(LOOPBACK_LEN as u8 - 1));
+
+        remote.end_loopback();
+        assert!(!remote.is_looping_back());
+        assert!(remote.take_looped().is_none());
+        assert_eq!(remote.looped_room(), None);
+    }
+}
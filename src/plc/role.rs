@@ -0,0 +1,210 @@
+//! Choice of the part a node plays in the tunnel
+//!
+//! One image holds every state machine. A role stored by the host wins, and
+//! without one a strapping pin tied low makes a follower. Otherwise the node
+//! is left to the election, starting as a follower and changing roles while
+//! running as leaders come and go. Peers for the contention mode are only
+//! ever picked by the host. A new role is saved to flash and taken up by
+//! rebooting once the host was answered.
+
+use super::{
+    election::{Election, Outcome},
+    host::{self, Host},
+    modem::Modem,
+    neighbor,
+    tunnel::Tunnel,
+    Follower, Leader, Peer, Role,
+};
+use crate::{config, st7580, usb};
+use stm32f4xx_hal::timer::{self, DelayUs};
+
+/// Payload of the Role command that hands the choice back to the pin and
+/// the election
+pub const ROLE_STRAPPED: u8 = 0xFF;
+
+/// Role the node boots into, `strap_low` being the level of the strapping
+/// pin, or `None` when the election decides
+pub fn select(store: &config::Store, strap_low: bool) -> Option<Role> {
+    let stored = store.load().role.and_then(|role| role.try_into().ok());
+    match stored {
+        Some(role) => Some(role),
+        None if strap_low => Some(Role::Follower),
+        None => None,
+    }
+}
+
+/// USB product string of a node booting into `role`
+pub fn product(role: Option<Role>) -> &'static str {
+    match role {
+        Some(Role::Leader) => "TUNNEL Leader",
+        Some(Role::Follower) => "TUNNEL Follower",
+        Some(Role::Peer) => "TUNNEL Peer",
+        None => "TUNNEL Node",
+    }
+}
+
+/// Answers the Role command, saving the new role and rebooting into it
+pub(super) fn command(
+    host: &mut Host,
+    store: &mut config::Store,
+    current: Role,
+    payload: &[u8],
+) {
+    let cmd = host::Command::Role as u8;
+    let role = match payload[..] {
+        [] => return host.send_role(current, store.load().role),
+        [ROLE_STRAPPED] => None,
+        [role] => match Role::try_from(role) {
+            Ok(role) => Some(role.into()),
+            Err(_) => return host.error(cmd),
+        },
+        _ => return host.error(cmd),
+    };
+
+    let mut config = store.load();
+    config.role = role;
+    if let Err(_e) = store.save(&config) {
+        crate::dbg::println!("role not saved {:?}", _e);
+        return host.error(cmd);
+    }
+    host.send_role(current, role);
+    host.schedule_reboot();
+}
+
+/// What a state machine is built from, handed over to the next one when an
+/// election changes the role
+pub struct Parts {
+    pub(super) source: Source,
+    /// Present when the role is left to the election
+    pub(super) election: Option<Election>,
+}
+
+// Moved only when the role changes, so the size of either does not matter
+#[allow(clippy::large_enum_variant)]
+pub(super) enum Source {
+    /// Hardware and queues at boot
+    Boot(Hardware),
+    /// The tunnel the previous role ran, which keeps its session, keys,
+    /// clock and counters across the switch
+    Switch(Tunnel),
+}
+
+pub(super) struct Hardware {
+    pub(super) driver: st7580::Driver,
+    pub(super) sender: st7580::DSender,
+    pub(super) store: config::Store,
+    pub(super) in_producers: [usb::UsbProducer; usb::CHANNELS],
+    pub(super) out_consumers: [usb::UsbConsumer; usb::CHANNELS],
+    pub(super) ctrl_in_producer: usb::ControlProducer,
+    pub(super) ctrl_out_consumer: usb::ControlConsumer,
+    pub(super) modem: Modem,
+}
+
+impl Parts {
+    pub fn new(
+        driver: st7580::Driver,
+        sender: st7580::DSender,
+        store: config::Store,
+        in_producers: [usb::UsbProducer; usb::CHANNELS],
+        out_consumers: [usb::UsbConsumer; usb::CHANNELS],
+        ctrl_in_producer: usb::ControlProducer,
+        ctrl_out_consumer: usb::ControlConsumer,
+    ) -> Self {
+        let hardware = Hardware {
+            driver,
+            sender,
+            modem: Modem::load(&store),
+            store,
+            in_producers,
+            out_consumers,
+            ctrl_in_producer,
+            ctrl_out_consumer,
+        };
+        Self {
+            source: Source::Boot(hardware),
+            election: None,
+        }
+    }
+}
+
+enum Machine<const TWO_WAY: bool> {
+    Leader(Leader<TWO_WAY>),
+    Follower(Follower<TWO_WAY>),
+    Peer(Peer),
+}
+
+/// Whichever state machine the node runs
+pub struct Node<const TWO_WAY: bool> {
+    /// Only ever `None` while the role changes
+    machine: Option<Machine<TWO_WAY>>,
+}
+
+impl<const TWO_WAY: bool> Node<TWO_WAY> {
+    /// Builds the state machine of `role`, or a follower that elects a
+    /// leader when there is no role
+    pub fn new(role: Option<Role>, mut parts: Parts) -> Self {
+        let machine = match role {
+            Some(Role::Leader) => Machine::Leader(Leader::new(parts)),
+            Some(Role::Follower) => Machine::Follower(Follower::new(parts)),
+            Some(Role::Peer) => Machine::Peer(Peer::new(parts)),
+            None => {
+                parts.election = Some(Election::new(neighbor::local_address()));
+                Machine::Follower(Follower::new(parts))
+            }
+        };
+        Self {
+            machine: Some(machine),
+        }
+    }
+
+    fn machine(&mut self) -> &mut Machine<TWO_WAY> {
+        self.machine.as_mut().unwrap()
+    }
+
+    pub fn role(&self) -> Role {
+        match self.machine.as_ref().unwrap() {
+            Machine::Leader(_) => Role::Leader,
+            Machine::Follower(_) => Role::Follower,
+            Machine::Peer(_) => Role::Peer,
+        }
+    }
+
+    pub fn init<TIM: timer::Instance>(&mut self, delay: &mut DelayUs<TIM>) {
+        match self.machine() {
+            Machine::Leader(leader) => leader.init(delay),
+            Machine::Follower(follower) => follower.init(delay),
+            Machine::Peer(peer) => peer.init(delay),
+        }
+    }
+
+    pub fn process(&mut self) {
+        let outcome = match self.machine() {
+            Machine::Leader(leader) => {
+                leader.process();
+                leader.election()
+            }
+            Machine::Follower(follower) => {
+                follower.process();
+                follower.election()
+            }
+            Machine::Peer(peer) => {
+                peer.process();
+                Outcome::Stay
+            }
+        };
+        if outcome == Outcome::Stay {
+            return;
+        }
+
+        let machine = match (self.machine.take().unwrap(), outcome) {
+            (Machine::Leader(leader), Outcome::StepDown) => {
+                Machine::Follower(Follower::new(leader.into_parts()))
+            }
+            (Machine::Follower(follower), Outcome::TakeOver) => {
+                Machine::Leader(Leader::new(follower.into_parts()))
+            }
+            (machine, _) => machine,
+        };
+        self.machine = Some(machine);
+    }
+}
@@ -0,0 +1,327 @@
+//! Sharing of the line between downstream data and upstream polls
+//!
+//! Only the leader starts exchanges in two-way mode, so it decides how much
+//! airtime each direction gets. While data waits for the follower, a poll
+//! still goes out after every `data_per_poll` data frames so that the follower
+//! is never starved. While nothing waits, polls are spaced out by an interval
+//! that doubles every time the follower has nothing to send, up to `max_poll`,
+//! and drops back to `min_poll` as soon as data moves either way.
+
+use super::{link, Header, LinkHeader};
+use crate::st7580;
+
+/// Longest `max_poll` taken, as the follower counts a miss when a
+/// keepalive period passes without a poll
+pub const MAX_POLL: u16 = link::KEEPALIVE_PERIOD as u16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Data frames sent in a row before the follower is polled
+    pub data_per_poll: u8,
+    /// Frames the follower may send back in answer to one poll
+    pub burst_grant: u8,
+    /// Shortest time between polls on a busy line in milliseconds
+    pub min_poll: u16,
+    /// Longest time between polls on a quiet line in milliseconds
+    pub max_poll: u16,
+    /// How long to wait for each frame answering a poll in milliseconds
+    pub reply_timeout: u16,
+    /// How long to leave the modem alone after it failed a request in
+    /// milliseconds
+    pub retry_delay: u16,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            data_per_poll: 4,
+            burst_grant: 4,
+            min_poll: 20,
+            max_poll: 1000,
+            reply_timeout: 500,
+            retry_delay: 100,
+        }
+    }
+}
+
+impl Settings {
+    pub const ENCODED_LEN: usize = 10;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let [n0, n1] = self.min_poll.to_le_bytes();
+        let [x0, x1] = self.max_poll.to_le_bytes();
+        let [r0, r1] = self.reply_timeout.to_le_bytes();
+        let [d0, d1] = self.retry_delay.to_le_bytes();
+        [
+            self.data_per_poll,
+            self.burst_grant,
+            n0,
+            n1,
+            x0,
+            x1,
+            r0,
+            r1,
+            d0,
+            d1,
+        ]
+    }
+
+    /// Reads settings sent by the host, rejecting ones that would stall the
+    /// line and bringing `max_poll` down to `MAX_POLL`
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::ENCODED_LEN)?;
+        let field = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let settings = Self {
+            data_per_poll: data[0],
+            burst_grant: data[1],
+            min_poll: field(2),
+            max_poll: field(4).min(MAX_POLL),
+            reply_timeout: field(6),
+            retry_delay: field(8),
+        };
+        let valid = settings.data_per_poll > 0
+            && settings.burst_grant > 0
+            && settings.min_poll > 0
+            && settings.min_poll <= settings.max_poll
+            && settings.reply_timeout > 0
+            && settings.retry_delay > 0;
+        valid.then_some(settings)
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    settings: Settings,
+    /// Data frames sent since the last poll
+    data_streak: u8,
+    /// Current spacing of polls on an idle line
+    poll_interval: u16,
+    poll_timeout: st7580::Timeout,
+    /// Whether a poll is waiting to be answered
+    polling: bool,
+    /// Frames of the follower's burst still allowed
+    burst_left: u8,
+    /// Whether the follower sent data during the current poll
+    burst_data: bool,
+    /// Frames of the follower's burst heard since the last poll started
+    burst_heard: u8,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let settings = Settings::default();
+        let mut poll_timeout = st7580::Timeout::default();
+        poll_timeout.set(1);
+        Self {
+            settings,
+            data_streak: 0,
+            poll_interval: settings.min_poll,
+            poll_timeout,
+            polling: false,
+            burst_left: 0,
+            burst_data: false,
+            burst_heard: 0,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn configure(&mut self, settings: Settings) {
+        self.settings = settings;
+        self.poll_interval = settings.min_poll;
+        self.poll_timeout.set(1);
+    }
+
+    pub fn poll_interval(&self) -> u16 {
+        self.poll_interval
+    }
+
+    pub fn reply_timeout(&self) -> u32 {
+        self.settings.reply_timeout.into()
+    }
+
+    pub fn retry_delay(&self) -> u32 {
+        self.settings.retry_delay.into()
+    }
+
+    /// Whether the follower should be polled instead of sending it data
+    pub fn poll_due(&self, data_waiting: bool) -> bool {
+        if data_waiting {
+            self.data_streak >= self.settings.data_per_poll
+        } else {
+            self.poll_timeout.is_expired()
+        }
+    }
+
+    pub fn sent_data(&mut self) {
+        self.data_streak = self.data_streak.saturating_add(1);
+        // An answer is likely on its way
+        self.poll_interval = self.settings.min_poll;
+        self.poll_timeout.set(self.poll_interval.into());
+    }
+
+    /// Starts a poll, returning the payload of the ping as `[grant, heard]`
+    /// with the burst granted to the follower and how many frames of its
+    /// last burst were heard
+    pub fn start_poll(&mut self) -> [u8; 2] {
+        self.data_streak = 0;
+        self.polling = true;
+        self.burst_left = self.settings.burst_grant;
+        self.burst_data = false;
+        let heard = core::mem::take(&mut self.burst_heard);
+        [self.settings.burst_grant, heard]
+    }
+
+    /// Records a frame heard from the follower during a poll, returning
+    /// whether more of its burst is on the way
+    pub(super) fn heard(&mut self, hdr: &LinkHeader) -> bool {
+        if !self.polling {
+            return false;
+        }
+        self.burst_data |= hdr.kind == Header::Data;
+        self.burst_heard = self.burst_heard.saturating_add(1);
+        if hdr.more && self.burst_left > 1 {
+            self.burst_left -= 1;
+            return true;
+        }
+        false
+    }
+
+    /// Ends the current poll, answered or not, and adapts the polling rate to
+    /// what came back
+    pub fn end_poll(&mut self) {
+        if !self.polling {
+            return;
+        }
+        self.polling = false;
+        self.burst_left = 0;
+        self.poll_interval = if self.burst_data {
+            self.settings.min_poll
+        } else {
+            self.poll_interval
+                .saturating_mul(2)
+                .min(self.settings.max_poll)
+        };
+        self.poll_timeout.set(self.poll_interval.into());
+    }
+
+    /// Encodes the settings and the current poll interval for the host
+    pub fn encode(&self) -> [u8; Settings::ENCODED_LEN + 2] {
+        let mut buf = [0; Settings::ENCODED_LEN + 2];
+        buf[..Settings::ENCODED_LEN].copy_from_slice(&self.settings.encode());
+        buf[Settings::ENCODED_LEN..]
+            .copy_from_slice(&self.poll_interval.to_le_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: Header, more: bool) -> LinkHeader {
+        LinkHeader {
+            kind,
+            compressed: false,
+            more,
+            seq: false,
+            network: 0,
+            src: 0,
+            dst: 0,
+            channel: 0,
+            credits: [0; crate::usb::CHANNELS],
+        }
+    }
+
+    #[test]
+    fn quiet_polls_back_off_to_max_poll() {
+        let mut scheduler = Scheduler::new();
+        let settings = *scheduler.settings();
+        let mut expected = settings.min_poll;
+        for _ in 0..16 {
+            scheduler.start_poll();
+            scheduler.heard(&frame(Header::Idle, false));
+            scheduler.end_poll();
+            expected = (expected * 2).min(settings.max_poll);
+            assert_eq!(scheduler.poll_interval(), expected);
+        }
+        assert_eq!(scheduler.poll_interval(), settings.max_poll);
+    }
+
+    #[test]
+    fn data_either_way_speeds_polls_back_up() {
+        let mut scheduler = Scheduler::new();
+        let min_poll = scheduler.settings().min_poll;
+        for _ in 0..4 {
+            scheduler.start_poll();
+            scheduler.end_poll();
+        }
+        assert!(scheduler.poll_interval() > min_poll);
+
+        scheduler.start_poll();
+        scheduler.heard(&frame(Header::Data, false));
+        scheduler.end_poll();
+        assert_eq!(scheduler.poll_interval(), min_poll);
+
+        scheduler.start_poll();
+        scheduler.end_poll();
+        scheduler.sent_data();
+        assert_eq!(scheduler.poll_interval(), min_poll);
+    }
+
+    #[test]
+    fn polls_come_after_data_per_poll_frames() {
+        let mut scheduler = Scheduler::new();
+        for _ in 0..scheduler.settings().data_per_poll {
+            assert!(!scheduler.poll_due(true));
+            scheduler.sent_data();
+        }
+        assert!(scheduler.poll_due(true));
+        scheduler.start_poll();
+        assert!(!scheduler.poll_due(true));
+    }
+
+    #[test]
+    fn bursts_end_at_the_grant() {
+        let mut scheduler = Scheduler::new();
+        let [grant, heard] = scheduler.start_poll();
+        assert_eq!(heard, 0);
+        for _ in 1..grant {
+            assert!(scheduler.heard(&frame(Header::Data, true)));
+        }
+        assert!(!scheduler.heard(&frame(Header::Data, true)));
+        scheduler.end_poll();
+        assert_eq!(scheduler.start_poll(), [grant, grant]);
+    }
+
+    #[test]
+    fn settings_round_trip_with_max_poll_capped() {
+        let settings = Settings {
+            max_poll: u16::MAX,
+            ..Settings::default()
+        };
+        let decoded = Settings::decode(&settings.encode()).unwrap();
+        assert_eq!(decoded.max_poll, MAX_POLL);
+        assert_eq!(
+            decoded,
+            Settings {
+                max_poll: MAX_POLL,
+                ..settings
+            }
+        );
+        let stalled = Settings {
+            burst_grant: 0,
+            ..Settings::default()
+        };
+        assert_eq!(Settings::decode(&stalled.encode()), None);
+        assert_eq!(Settings::decode(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! Pairing of a leader and a follower into a network
+//!
+//! A leader pairs with a follower by sending it a join carrying the network
+//! ID, which the follower answers with an accept. From then on both sides
+//! stamp every frame with the network ID and drop frames from other networks,
+//! so several tunnels can share the same wiring. The pairing is kept in flash
+//! and survives reboots until either side is unpaired from its host.
+//!
+//! An unpaired node only takes a join from the address its host allowed, or
+//! from anyone during `PAIRING_WINDOW` after boot or unpairing when its host
+//! allowed none, so a leader cannot pull in a follower of another bench. A
+//! node that unpairs tells its peer with a join offering `NETWORK_NONE`, sent
+//! with the network they shared.
+
+use super::{
+    neighbor::{self, Address},
+    Header, LinkHeader,
+};
+use crate::{config, st7580, usb};
+
+pub type NetworkId = u16;
+
+/// Network ID of a node that is not paired
+pub const NETWORK_NONE: NetworkId = 0x0000;
+
+/// Destination address of frames meant for every node
+pub const BROADCAST: Address = 0xFFFF;
+
+/// Time after boot or unpairing during which an unpaired node takes a join
+/// from anyone, in milliseconds
+pub const PAIRING_WINDOW: u32 = 60_000;
+
+/// Whether the payload of a join is the notice of a peer that unpaired
+pub(super) fn is_leave(payload: &[u8]) -> bool {
+    payload.get(..2) == Some(&NETWORK_NONE.to_le_bytes())
+}
+
+pub struct Session {
+    local: Address,
+    network: NetworkId,
+    peer: Address,
+    /// Only address a join is taken from while unpaired, set by the host
+    allowed: Option<Address>,
+    /// Network and peer left behind, until the peer is told
+    left: Option<(NetworkId, Address)>,
+    pairing_timeout: st7580::Timeout,
+    store: config::Store,
+}
+
+impl Session {
+    pub fn load(store: config::Store) -> Self {
+        let config = store.load();
+        let mut pairing_timeout = st7580::Timeout::default();
+        pairing_timeout.set(PAIRING_WINDOW);
+        Self {
+            local: neighbor::local_address(),
+            network: config.network,
+            peer: config.peer,
+            allowed: None,
+            left: None,
+            pairing_timeout,
+            store,
+        }
+    }
+
+    pub fn local(&self) -> Address {
+        self.local
+    }
+
+    pub fn network(&self) -> NetworkId {
+        self.network
+    }
+
+    pub fn peer(&self) -> Option<Address> {
+        self.is_paired().then_some(self.peer)
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.network != NETWORK_NONE
+    }
+
+    /// Network a leader hands out when pairing, derived from its address
+    pub fn own_network(&self) -> NetworkId {
+        match self.local {
+            NETWORK_NONE => !NETWORK_NONE,
+            local => local,
+        }
+    }
+
+    pub(super) fn header(&self, kind: Header, dst: Address) -> LinkHeader {
+        LinkHeader {
+            kind,
+            compressed: false,
+            more: false,
+            seq: false,
+            network: self.network,
+            src: self.local,
+            dst,
+            channel: 0,
+            credits: [0; usb::CHANNELS],
+        }
+    }
+
+    pub(super) fn to_peer(&self, kind: Header) -> LinkHeader {
+        self.header(kind, self.peer)
+    }
+
+    /// Whether a received frame is meant for this node at all. Nodes that are
+    /// not paired yet hear every network so that they can be paired, and
+    /// paired ones only hear unpaired nodes discover and announce.
+    pub(super) fn accepts(&self, hdr: &LinkHeader) -> bool {
+        let for_us = hdr.dst == self.local || hdr.dst == BROADCAST;
+        let same_network = !self.is_paired()
+            || hdr.network == self.network
+            || (hdr.network == NETWORK_NONE
+                && matches!(hdr.kind, Header::Discover | Header::Announce));
+        for_us && same_network
+    }
+
+    /// Whether a received frame comes from the paired peer
+    pub(super) fn is_from_peer(&self, hdr: &LinkHeader) -> bool {
+        self.is_paired() && hdr.network == self.network && hdr.src == self.peer
+    }
+
+    /// Only takes joins from `addr` while unpaired, or from anyone during the
+    /// pairing window when `None`
+    pub fn allow(&mut self, addr: Option<Address>) {
+        self.allowed = addr;
+    }
+
+    /// Whether a join from `src` into `network` may pair this node. A paired
+    /// node is only joined again into its own network.
+    pub(super) fn welcomes(&self, src: Address, network: NetworkId) -> bool {
+        if self.is_paired() {
+            return network == self.network;
+        }
+        match self.allowed {
+            Some(allowed) => src == allowed,
+            None => !self.pairing_timeout.is_expired(),
+        }
+    }
+
+    pub fn pair(&mut self, network: NetworkId, peer: Address) {
+        self.network = network;
+        self.peer = peer;
+        self.left = None;
+        self.persist();
+    }
+
+    pub fn unpair(&mut self) {
+        if self.is_paired() {
+            self.left = Some((self.network, self.peer));
+        }
+        self.network = NETWORK_NONE;
+        self.peer = 0;
+        self.pairing_timeout.set(PAIRING_WINDOW);
+        self.persist();
+    }
+
+    /// Whether a frame comes from the peer this node unpaired from
+    pub(super) fn is_from_left(&self, hdr: &LinkHeader) -> bool {
+        self.left == Some((hdr.network, hdr.src))
+    }
+
+    /// Whether the peer this node unpaired from is still to be told
+    pub(super) fn has_left(&self) -> bool {
+        self.left.is_some()
+    }
+
+    /// Header of the notice telling the peer this node unpaired from that it
+    /// did, until `forget_left`
+    pub(super) fn leave_header(&self) -> Option<LinkHeader> {
+        let (network, peer) = self.left?;
+        let mut hdr = self.header(Header::Join, peer);
+        hdr.network = network;
+        Some(hdr)
+    }
+
+    /// Stops telling the peer this node unpaired from that it did
+    pub(super) fn forget_left(&mut self) {
+        self.left = None;
+    }
+
+    /// Flash store shared with the rest of the settings
+    pub(super) fn store(&mut self) -> &mut config::Store {
+        &mut self.store
+    }
+
+    /// Encodes the session for the host as `[network, peer]`
+    pub fn encode(&self) -> [u8; 4] {
+        let [n0, n1] = self.network.to_le_bytes();
+        let [p0, p1] = self.peer.to_le_bytes();
+        [n0, n1, p0, p1]
+    }
+
+    /// Stages the session to be written between frames, as pairing happens
+    /// on the receive path
+    fn persist(&mut self) {
+        let mut config = self.store.load();
+        config.network = self.network;
+        config.peer = self.peer;
+        self.store.stage(config);
+    }
+}
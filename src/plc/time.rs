@@ -0,0 +1,212 @@
+//! Time shared between a leader and its follower
+//!
+//! Each node only has its own monotonic clock, so the leader's is taken as
+//! the time base of the tunnel. In two-way mode the follower asks its leader
+//! for the time every `SYNC_PERIOD`: it stamps the request as it goes out
+//! (t1), the leader stamps it on arrival (t2) and stamps its answer as it
+//! goes out (t3), and the follower stamps the answer on arrival (t4). The
+//! time both frames spend in the modems and on the wire drops out of the
+//! offset `((t2 - t1) + (t3 - t4)) / 2` as long as it is the same both ways,
+//! so the request is padded to the length of its answer. Successive offsets
+//! give the drift between the two clocks. `now` reads the leader's time on
+//! either side in microseconds, wrapping like the clocks themselves. When an
+//! election changes the leader, the node taking over carries on from its
+//! estimate and stamps its answers with it, so the time base of the tunnel
+//! runs on without a jump.
+
+use crate::st7580;
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+
+/// Time between requests for the leader's time in milliseconds
+pub const SYNC_PERIOD: u32 = 10_000;
+/// Time the estimate is trusted without a new answer in milliseconds
+pub const HOLDOVER: u32 = 10 * 60_000;
+/// Longest round trip an answer is used for in microseconds
+const MAX_ROUND_TRIP: u32 = 2_000_000;
+/// Share of the drift measured by each answer that is taken up
+const DRIFT_GAIN: f32 = 0.25;
+
+/// Length of both the request and its answer
+pub const MESSAGE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy)]
+struct Estimate {
+    /// Local time of the last answer
+    local: u32,
+    /// Leader's time less ours at `local`
+    offset: u32,
+    /// Leader's clock rate less ours, in seconds per second
+    drift: f32,
+}
+
+impl Estimate {
+    /// The leader's own clock
+    const LEADER: Self = Self {
+        local: 0,
+        offset: 0,
+        drift: 0.0,
+    };
+
+    fn at(&self, local: u32) -> u32 {
+        let elapsed = local.wrapping_sub(self.local) as i32 as f32;
+        let correction = (self.drift * elapsed) as i32 as u32;
+        local.wrapping_add(self.offset).wrapping_add(correction)
+    }
+}
+
+static ESTIMATE: Mutex<Cell<Option<Estimate>>> = Mutex::new(Cell::new(None));
+
+fn publish(estimate: Option<Estimate>) {
+    interrupt::free(|cs| ESTIMATE.borrow(cs).set(estimate));
+}
+
+/// The leader's time in microseconds, or `None` on a follower that has yet
+/// to hear it
+pub fn now() -> Option<u32> {
+    let estimate = interrupt::free(|cs| ESTIMATE.borrow(cs).get())?;
+    Some(estimate.at(st7580::now_us()))
+}
+
+/// The leader's time at the local time `local`, which a leader stamps its
+/// answers with
+pub(super) fn at(local: u32) -> u32 {
+    match interrupt::free(|cs| ESTIMATE.borrow(cs).get()) {
+        Some(estimate) => estimate.at(local),
+        None => local,
+    }
+}
+
+#[derive(Debug)]
+pub struct Clock {
+    estimate: Option<Estimate>,
+    /// Whether `estimate` came from an answer of the current leader, and so
+    /// whether the next answer measures the drift against it
+    measured: bool,
+    /// Round trip of the last answer used in microseconds
+    round_trip: u32,
+    sync_timeout: st7580::Timeout,
+    holdover_timeout: st7580::Timeout,
+}
+
+impl Clock {
+    /// Clock of a leader, whose own time is the time base
+    pub fn lead() -> Self {
+        publish(Some(Estimate::LEADER));
+        Self {
+            estimate: Some(Estimate::LEADER),
+            measured: false,
+            round_trip: 0,
+            sync_timeout: Default::default(),
+            holdover_timeout: Default::default(),
+        }
+    }
+
+    /// Clock of a follower, unsynchronized until the first answer
+    pub fn follow() -> Self {
+        publish(None);
+        let mut sync_timeout = st7580::Timeout::default();
+        sync_timeout.set(1);
+        Self {
+            estimate: None,
+            measured: false,
+            round_trip: 0,
+            sync_timeout,
+            holdover_timeout: Default::default(),
+        }
+    }
+
+    /// Makes the time base of a follower taking over the tunnel its own,
+    /// carrying on from its estimate of the leader it followed
+    pub fn take_lead(&mut self) {
+        let estimate = self.estimate.unwrap_or(Estimate::LEADER);
+        *self = Self {
+            estimate: Some(estimate),
+            ..Self::lead()
+        };
+        publish(Some(estimate));
+    }
+
+    /// Keeps the time base of a leader stepping down as the estimate until
+    /// the new leader answers
+    pub fn step_down(&mut self) {
+        let estimate = self.estimate;
+        *self = Self {
+            estimate,
+            ..Self::follow()
+        };
+        if let Some(estimate) = estimate {
+            self.holdover_timeout.set(HOLDOVER);
+            publish(Some(estimate));
+        }
+    }
+
+    /// Whether to ask the leader for its time, forgetting an estimate that
+    /// went without an answer for too long
+    pub fn request_due(&mut self) -> bool {
+        if self.holdover_timeout.is_expired() {
+            crate::dbg::println!("time sync lost");
+            self.holdover_timeout = Default::default();
+            self.estimate = None;
+            self.measured = false;
+            publish(None);
+        }
+        if !self.sync_timeout.is_expired() {
+            return false;
+        }
+        self.sync_timeout.set(SYNC_PERIOD);
+        true
+    }
+
+    /// Takes the leader's answer to a request, with `t4` when it arrived
+    pub(super) fn answered(&mut self, t1: u32, t2: u32, t3: u32, t4: u32) {
+        let round_trip = t4.wrapping_sub(t1).wrapping_sub(t3.wrapping_sub(t2));
+        if round_trip > MAX_ROUND_TRIP {
+            crate::dbg::println!(
+                "time answer dropped, round trip {}",
+                round_trip
+            );
+            return;
+        }
+        let there = t2.wrapping_sub(t1);
+        let back = t3.wrapping_sub(t4);
+        let offset =
+            there.wrapping_add((back.wrapping_sub(there) as i32 / 2) as u32);
+
+        let drift = match self.estimate {
+            Some(last) if self.measured => {
+                let elapsed = t4.wrapping_sub(last.local) as f32;
+                let measured =
+                    offset.wrapping_sub(last.offset) as i32 as f32 / elapsed;
+                last.drift + (measured - last.drift) * DRIFT_GAIN
+            }
+            _ => 0.0,
+        };
+        let estimate = Estimate {
+            local: t4,
+            offset,
+            drift,
+        };
+        self.estimate = Some(estimate);
+        self.measured = true;
+        self.round_trip = round_trip;
+        self.holdover_timeout.set(HOLDOVER);
+        publish(Some(estimate));
+    }
+
+    /// Encodes the state for the host as `[synced, now, drift, round trip]`
+    /// with the leader's time in microseconds, the drift in parts per
+    /// billion and the round trip of the last answer in microseconds
+    pub fn encode(&self) -> [u8; 13] {
+        let local = st7580::now_us();
+        let mut buf = [0; 13];
+        if let Some(estimate) = &self.estimate {
+            let drift = (estimate.drift * 1e9) as i32;
+            buf[0] = 1;
+            buf[1..5].copy_from_slice(&estimate.at(local).to_le_bytes());
+            buf[5..9].copy_from_slice(&drift.to_le_bytes());
+            buf[9..].copy_from_slice(&self.round_trip.to_le_bytes());
+        }
+        buf
+    }
+}
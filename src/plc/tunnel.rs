@@ -0,0 +1,555 @@
+//! One end of the tunnel, whatever part the node plays in it
+//!
+//! Leaders, followers and peers differ in when they send and what they
+//! expect back, but they drive the same modem, queues and layers the same
+//! way. `Tunnel` holds all of that together with what every role does alike:
+//! the host commands that do not depend on the role, the path of a frame to
+//! the modem, the checks each received frame goes through and the handling
+//! of a data frame from the peer. The state machines only add their own
+//! timing on top.
+
+use super::{
+    bench::{self, Bench},
+    election::Election,
+    fec::{self, Fec},
+    frame,
+    host::{self, Host},
+    link::{self, Link},
+    modem::Modem,
+    neighbor::{Address, NeighborTable, Role},
+    pipeline::Pipeline,
+    qos,
+    rate::Rate,
+    remote::{self, Remote},
+    role::{self, Hardware, Parts, Source},
+    session::{self, Session},
+    time::Clock,
+    update::Update,
+    Channels, Header, LinkHeader, DATA_START,
+};
+use crate::{mem, st7580, stats::STATS};
+use stm32f4xx_hal::timer::{self, DelayUs};
+
+pub(super) struct Tunnel {
+    pub(super) driver: st7580::Driver,
+    pub(super) sender: st7580::DSender,
+    pub(super) channels: Channels,
+    pub(super) host: Host,
+    pub(super) neighbors: NeighborTable,
+    pub(super) session: Session,
+    pub(super) pipeline: Pipeline,
+    pub(super) fec: Fec,
+    pub(super) modem: Modem,
+    pub(super) update: Update,
+    pub(super) clock: Clock,
+    pub(super) remote: Remote,
+    pub(super) bench: Bench,
+    pub(super) rate: Rate,
+    pub(super) link: Link,
+    role: Role,
+    /// Whether the peer answers, always so for contention peers
+    two_way: bool,
+}
+
+impl Tunnel {
+    /// Builds the end of the tunnel for `role`, or takes over the one the
+    /// previous role ran, handing back the election when there is one
+    pub(super) fn new(
+        parts: Parts,
+        role: Role,
+        two_way: bool,
+    ) -> (Self, Option<Election>) {
+        let Parts { source, election } = parts;
+        let hardware = match source {
+            Source::Boot(hardware) => hardware,
+            Source::Switch(mut tunnel) => {
+                tunnel.switch(role);
+                return (tunnel, election);
+            }
+        };
+        let Hardware {
+            driver,
+            sender,
+            mut store,
+            in_producers,
+            out_consumers,
+            ctrl_in_producer,
+            ctrl_out_consumer,
+            modem,
+        } = hardware;
+        let tunnel = Self {
+            driver,
+            sender,
+            channels: Channels::new(in_producers, out_consumers, two_way),
+            host: Host::new(ctrl_in_producer, ctrl_out_consumer),
+            neighbors: NeighborTable::new(),
+            update: Update::new(&mut store),
+            clock: match role {
+                Role::Leader => Clock::lead(),
+                Role::Follower | Role::Peer => Clock::follow(),
+            },
+            pipeline: Pipeline::load(&mut store),
+            session: Session::load(store),
+            fec: Fec::new(),
+            modem,
+            remote: Remote::new(),
+            bench: Bench::new(),
+            rate: Rate::new(),
+            link: Link::new(),
+            role,
+            two_way,
+        };
+        (tunnel, election)
+    }
+
+    /// Hands the tunnel over to the next role
+    pub(super) fn into_parts(self, election: Option<Election>) -> Parts {
+        Parts {
+            source: Source::Switch(self),
+            election,
+        }
+    }
+
+    /// Takes up the role an election gave the node, moving the time base
+    /// along with it
+    fn switch(&mut self, role: Role) {
+        match role {
+            Role::Leader => self.clock.take_lead(),
+            Role::Follower | Role::Peer => self.clock.step_down(),
+        }
+        self.role = role;
+    }
+
+    pub(super) fn init<TIM: timer::Instance>(
+        &mut self,
+        delay: &mut DelayUs<TIM>,
+    ) {
+        super::shared_init(
+            delay,
+            &mut self.driver,
+            &mut self.sender,
+            self.modem.current(),
+        )
+    }
+
+    /// Whether link control messages flow, which takes a leader and a
+    /// follower that hear each other
+    fn has_control(&self) -> bool {
+        self.two_way && self.role != Role::Peer
+    }
+
+    /// Takes the next command from the host and answers it when every role
+    /// does so alike, handing it back along with its payload otherwise
+    pub(super) fn poll_host(&mut self) -> Option<(host::Command, mem::BufBox)> {
+        let (cmd, payload) = self.host.next_command()?;
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(v) => {
+                self.host.error(v);
+                return None;
+            }
+        };
+        match cmd {
+            host::Command::Neighbors => {
+                self.host.send_neighbors(&self.neighbors)
+            }
+            host::Command::LinkState => {
+                self.host.send_link_state(self.link.state())
+            }
+            host::Command::Unpair => self.unpair(),
+            host::Command::Session => self.host.send_session(&self.session),
+            host::Command::Compression => {
+                self.host.send_compression(&self.pipeline.compression)
+            }
+            host::Command::Integrity => {
+                self.host.send_integrity(&self.pipeline.integrity)
+            }
+            host::Command::Frames => self.host.send_frame_errors(),
+            host::Command::Stats => self.host.send_stats(),
+            host::Command::Time if self.role != Role::Peer => {
+                self.host.send_time(&self.clock)
+            }
+            cmd @ host::Command::Remote
+                if self.has_control() && self.session.is_paired() =>
+            {
+                self.remote.request();
+                self.host.ack(cmd);
+            }
+            cmd @ host::Command::Rate if self.role != Role::Peer => {
+                match payload[..] {
+                    [] => {}
+                    // Told to the peer as the rate is polled
+                    [modes] if self.rate.set_modes(modes) => {}
+                    _ => return self.reject(cmd),
+                }
+                self.host.send_rate(&self.rate);
+            }
+            host::Command::Modem if payload.is_empty() => {
+                self.host.send_modem(&self.modem)
+            }
+            host::Command::Role => role::command(
+                &mut self.host,
+                self.session.store(),
+                self.role,
+                &payload,
+            ),
+            cmd @ host::Command::Qos => {
+                match qos::Settings::decode(&payload) {
+                    Some(settings) => self.channels.qos.configure(settings),
+                    None if payload.is_empty() => {}
+                    None => return self.reject(cmd),
+                }
+                self.host.send_qos(&self.channels.qos);
+            }
+            cmd @ host::Command::Fec => match payload[..] {
+                [] => self.host.send_fec(&self.fec),
+                [rate] => match fec::Rate::try_from(rate) {
+                    Ok(rate) => {
+                        self.fec.set_rate(rate);
+                        self.host.send_fec(&self.fec);
+                    }
+                    Err(_) => self.host.error(cmd as u8),
+                },
+                _ => self.host.error(cmd as u8),
+            },
+            cmd @ host::Command::SetKey => {
+                let key = match payload[..].try_into() {
+                    Ok(key) => Some(key),
+                    Err(_) if payload.is_empty() => None,
+                    Err(_) => return self.reject(cmd),
+                };
+                self.pipeline.crypto.set_key(key, self.session.store());
+                self.host.ack(cmd);
+            }
+            cmd => return Some((cmd, payload)),
+        }
+        None
+    }
+
+    /// Turns a command down, for the match arms of `poll_host`
+    fn reject(
+        &mut self,
+        cmd: host::Command,
+    ) -> Option<(host::Command, mem::BufBox)> {
+        self.host.error(cmd as u8);
+        None
+    }
+
+    pub(super) fn pair(&mut self, network: session::NetworkId, peer: Address) {
+        self.session.pair(network, peer);
+        self.forget_peer();
+    }
+
+    pub(super) fn unpair(&mut self) {
+        self.session.unpair();
+        self.forget_peer();
+    }
+
+    /// Weighs a join addressed to this node, returning the network to pair
+    /// into. A join from the peer offering no network tells us it unpaired.
+    pub(super) fn join_offer(
+        &mut self,
+        hdr: &LinkHeader,
+        payload: &[u8],
+        from_peer: bool,
+    ) -> Option<session::NetworkId> {
+        if session::is_leave(payload) {
+            if from_peer {
+                self.peer_left();
+            }
+            return None;
+        }
+        let &[n0, n1, ..] = payload else { return None };
+        let network = session::NetworkId::from_le_bytes([n0, n1]);
+        if hdr.dst != self.session.local()
+            || !self.session.welcomes(hdr.src, network)
+        {
+            crate::dbg::println!("join from {:04x} refused", hdr.src);
+            return None;
+        }
+        Some(network)
+    }
+
+    /// Unpairs on the peer's notice that it did, without telling it back
+    pub(super) fn peer_left(&mut self) {
+        self.unpair();
+        self.session.forget_left();
+    }
+
+    /// Notice telling the peer this node unpaired from that it did
+    pub(super) fn leave_frame(&self) -> Option<mem::BufBox> {
+        let hdr = self.session.leave_header()?;
+        super::link_frame(hdr, &session::NETWORK_NONE.to_le_bytes())
+    }
+
+    /// Starts over with whatever was learnt about the peer, as when the
+    /// pairing changes
+    fn forget_peer(&mut self) {
+        self.pipeline.crypto.forget_peer();
+        self.rate.forget_peer();
+        self.remote.end_loopback();
+        self.link = Link::new();
+        self.host.session_changed(&self.session);
+    }
+
+    /// Header of a frame to the peer, advertising our room to receive
+    pub(super) fn to_peer(&self, kind: Header) -> LinkHeader {
+        let mut hdr = self.session.to_peer(kind);
+        hdr.credits = self.channels.credits();
+        // In loopback the room is that left to send data back
+        if let Some(free) = self.remote.looped_room() {
+            for credit in &mut hdr.credits {
+                *credit = (*credit).min(free);
+            }
+        }
+        hdr
+    }
+
+    /// Whether anything waits to go to the peer as data that the peer has
+    /// room for
+    pub(super) fn sendable(&self) -> bool {
+        self.channels.sendable()
+            || self.remote.is_pending()
+            || self.bench.is_pending()
+    }
+
+    /// Takes the next message for the peer along with its channel, link
+    /// control first, then benchmark frames, which hold host data back
+    /// until the run is over, then data sent back in loopback and finally
+    /// host data
+    pub(super) fn next_data(&mut self) -> Option<(u8, mem::BufBox)> {
+        let room = self.pipeline.room(self.fec.frame_max());
+        if let Some(message) = self.remote.take_message() {
+            return Some((remote::CONTROL_CHANNEL, message));
+        }
+        if let Some(frame) = self.bench.take_frame(room) {
+            return Some((bench::CHANNEL, frame));
+        }
+        if let Some(looped) = self.remote.take_looped() {
+            return Some(looped);
+        }
+        self.channels.next_aggregate(room)
+    }
+
+    /// Hands a frame to the modem with the transmission options `opts`,
+    /// returning whether it took it, the frame being dropped and counted if
+    /// not
+    pub(super) fn transmit(
+        &mut self,
+        mut send_buf: mem::BufBox,
+        opts: u8,
+    ) -> bool {
+        if let Err(_e) = self.fec.encode(&mut send_buf) {
+            crate::dbg::println!("packet dropped {:?}", _e);
+            STATS.tx_errors.inc();
+            return false;
+        }
+        let len = send_buf.len();
+        let tag = if fec::ENABLED {
+            self.driver.phy_data(opts, send_buf)
+        } else {
+            self.driver.dl_data(opts, send_buf)
+        };
+        if let Err(_e) = tag.and_then(|tag| self.sender.enqueue(tag)) {
+            crate::dbg::println!("data error {:?}", _e);
+            STATS.tx_errors.inc();
+            return false;
+        }
+        STATS.tx.record(len);
+        true
+    }
+
+    /// Follows the frame the modem is sending, `None` while it still is and
+    /// then whether it went out
+    pub(super) fn poll_sent(&mut self) -> Option<bool> {
+        match self.sender.process() {
+            Ok(()) => Some(true),
+            Err(st7580::NbStErr::WouldBlock) => None,
+            Err(st7580::NbStErr::Other(st7580::StErr::TxErrNoStatus)) => {
+                crate::dbg::println!("plm did not return status");
+                Some(false)
+            }
+            Err(st7580::NbStErr::Other(st7580::StErr::TxErrAckTmo)) => {
+                crate::dbg::println!("plm ack timed out");
+                Some(false)
+            }
+            Err(st7580::NbStErr::Other(st7580::StErr::TxErrBusy)) => {
+                crate::dbg::println!("plm tx busy");
+                Some(false)
+            }
+            Err(st7580::NbStErr::Other(st7580::StErr::TxErrNak)) => {
+                crate::dbg::println!("plm tx NAK");
+                Some(false)
+            }
+            Err(st7580::NbStErr::Other(_e)) => {
+                crate::dbg::println!("plm tx error {:?}", _e);
+                STATS.tx_errors.inc();
+                Some(false)
+            }
+        }
+    }
+
+    /// Takes the next frame from the modem that is well formed and meant for
+    /// this node, along with its header and whether it comes from the peer
+    pub(super) fn receive(
+        &mut self,
+    ) -> Option<(st7580::Frame, LinkHeader, bool)> {
+        let mut f = self.driver.receive_frame()?;
+        STATS.rx.record(f.length.into());
+        debug_assert!(matches!(f.stx, st7580::STX_03 | st7580::STX_02));
+        if let Err(_e) = self.fec.decode(&mut f) {
+            crate::dbg::println!("frame dropped {:?}", _e);
+            return None;
+        }
+        let hdr = match frame::parse(&f) {
+            Ok(hdr) => hdr,
+            Err(e) => {
+                e.record();
+                return None;
+            }
+        };
+        // Announcements refresh the table as they are recorded
+        if hdr.kind != Header::Announce {
+            self.neighbors.heard(hdr.src, &f);
+        }
+        if !self.session.accepts(&hdr) {
+            return None;
+        }
+        let from_peer = self.session.is_from_peer(&hdr);
+        if from_peer {
+            self.link_heard();
+            self.channels.heard(&hdr);
+            self.remote.heard(&f);
+            if let Some(snr) = self.rate.heard(&f) {
+                if self.has_control() && self.session.is_paired() {
+                    self.remote.snr(snr);
+                }
+            }
+        }
+        Some((f, hdr, from_peer))
+    }
+
+    /// Passes the payload of a data frame from the peer back through the
+    /// pipeline and on to wherever its channel leads, returning whether the
+    /// pipeline took it
+    pub(super) fn data_received(
+        &mut self,
+        hdr: &LinkHeader,
+        f: st7580::Frame,
+    ) -> bool {
+        let len = f.length as usize;
+        let mut data = f.data;
+        data.copy_within(DATA_START..len, 0);
+        data.truncate(len - DATA_START);
+        if let Err(_e) = self.pipeline.incoming(hdr, &mut data) {
+            crate::dbg::println!("packet dropped {:?}", _e);
+            return false;
+        }
+        match hdr.channel {
+            remote::CONTROL_CHANNEL => self.remote.received(
+                &data,
+                &mut self.host,
+                &mut self.modem,
+                &mut self.update,
+                &mut self.clock,
+                self.session.store(),
+            ),
+            bench::CHANNEL => self.bench.received(&data),
+            channel if self.remote.is_looping_back() => {
+                self.remote.loop_back(channel, data)
+            }
+            channel => self.channels.deliver(channel, &data),
+        }
+        true
+    }
+
+    fn link_heard(&mut self) {
+        self.update.link_up(self.session.store());
+        // Followers only ever answer, and wait to be told in turn
+        if self.role == Role::Leader {
+            if let Some(outcome) = self.modem.answered(self.session.store()) {
+                self.host.modem_changed(outcome, &self.modem);
+            }
+            if self.modem.is_telling() {
+                self.remote.configured(*self.modem.current());
+            }
+        }
+        if let Some(state) = self.link.heard() {
+            // A peer coming back may have forgotten our modulations
+            if state == link::LinkState::Joining {
+                self.rate.retell();
+            }
+            self.host.link_changed(state);
+        }
+    }
+
+    /// Counts an exchange with the peer that never came
+    pub(super) fn link_missed(&mut self) {
+        if let Some(state) = self.link.missed() {
+            if state == link::LinkState::Down {
+                self.remote.end_loopback();
+            }
+            self.host.link_changed(state);
+        }
+    }
+
+    /// Keeps the parts of the tunnel that run on their own going: firmware
+    /// updates, benchmark runs and the modulations of the peer
+    pub(super) fn poll(&mut self) {
+        self.update.poll();
+        if self.bench.poll() {
+            self.host.send_bench(&self.bench);
+        }
+        self.poll_rate();
+    }
+
+    /// Does what has to wait for the line to be quiet: switching or falling
+    /// back modem settings and writing settings staged on the receive path
+    pub(super) fn poll_idle(&mut self) {
+        let outcome = self.modem.poll(&mut self.driver, &mut self.sender);
+        if let Some(outcome) = outcome {
+            self.host.modem_changed(outcome, &self.modem);
+        }
+        if let Err(_e) = self.session.store().flush() {
+            crate::dbg::println!("failed to write settings {:?}", _e);
+        }
+    }
+
+    /// Takes the SNR the peer reports and the modulations it receives,
+    /// telling it ours until it confirms them
+    fn poll_rate(&mut self) {
+        if let Some(snr) = self.remote.take_peer_snr() {
+            self.rate.reported(snr);
+        }
+        if let Some((modes, flags)) = self.remote.take_peer_modes() {
+            let knows_ours = flags & remote::MODES_KNOWN != 0;
+            self.rate.peer_modes(modes, knows_ours);
+            if !knows_ours || flags & remote::MODES_ASK != 0 {
+                self.tell_modes();
+            }
+        }
+        if self.has_control()
+            && self.session.is_paired()
+            && self.rate.modes_due()
+        {
+            self.tell_modes();
+        }
+    }
+
+    fn tell_modes(&mut self) {
+        let (modes, knows_peer) = (self.rate.modes(), self.rate.knows_peer());
+        self.remote.modes(modes, knows_peer, self.rate.is_untold());
+        self.rate.modes_told();
+    }
+
+    /// Takes the count of frames the peer heard of the last burst we sent
+    /// it, counting those it did not as lost
+    pub(super) fn burst_heard(&mut self, sent: u8, heard: u8) {
+        if heard > 0 {
+            self.rate.answered();
+        }
+        for _ in heard..sent {
+            self.rate.lost();
+        }
+    }
+}
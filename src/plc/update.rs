@@ -0,0 +1,247 @@
+//! Firmware updates of a follower streamed over the link
+//!
+//! The host hands the leader a new image piece by piece with the Update
+//! command and the leader passes each piece on to its follower as a link
+//! control message. The follower writes the pieces into the `boot` slot it
+//! is not running from and answers each with the offset it expects next, so
+//! a transfer that stalls picks up where it left off, even after starting it
+//! over with the same length and hash. Once the whole image is in, its
+//! SHA-256 is checked and the follower reboots into it on trial. An image
+//! that does not hear its peer within `TRIAL_TIMEOUT` reboots and the
+//! bootloader goes back to the old one.
+//!
+//! Messages from the leader are `[op, ..]`: `[0, length, hash]` begins or
+//! resumes a transfer, `[1, offset, data]` carries a piece, `[2]` ends the
+//! transfer and `[3]` only asks where it stands. Every message is answered
+//! with `[status, offset]`.
+
+use crate::{
+    boot::{BootRecord, Slot},
+    config, st7580, util,
+};
+
+pub const ENABLED: bool = cfg!(feature = "OTA");
+
+pub const HASH_LEN: usize = 32;
+/// Length of the answer to each message
+pub const STATE_LEN: usize = 5;
+/// Time a new image has to hear its peer in milliseconds
+pub const TRIAL_TIMEOUT: u32 = 60_000;
+/// Time left for the last answer to go out before rebooting in milliseconds
+const REBOOT_DELAY: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Begin = 0x00,
+    Chunk = 0x01,
+    End = 0x02,
+    Query = 0x03,
+}
+
+impl TryFrom<u8> for Op {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Op::*;
+        match v {
+            0x00 => Ok(Begin),
+            0x01 => Ok(Chunk),
+            0x02 => Ok(End),
+            0x03 => Ok(Query),
+            v => Err(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// No transfer going on
+    Idle = 0x00,
+    /// Receiving, the offset is the next byte expected
+    Receiving = 0x01,
+    /// The image checked out and the follower reboots into it
+    Installing = 0x02,
+    /// The received image does not match its hash and was dropped
+    BadHash = 0x03,
+    /// The message was malformed, the image too long or not linked for the
+    /// free slot, flash could not be written, or the follower was built
+    /// without `OTA`
+    Failed = 0x04,
+}
+
+impl From<Status> for u8 {
+    fn from(val: Status) -> Self {
+        val as u8
+    }
+}
+
+#[derive(Debug)]
+struct Transfer {
+    len: u32,
+    hash: [u8; HASH_LEN],
+    /// Next byte expected
+    offset: u32,
+}
+
+#[derive(Debug)]
+pub struct Update {
+    transfer: Option<Transfer>,
+    /// Whether the running image still has to hear its peer
+    trial: bool,
+    trial_timeout: st7580::Timeout,
+    /// Whether a checked image waits for the reboot
+    installing: bool,
+    reboot_timeout: st7580::Timeout,
+}
+
+impl Update {
+    pub fn new(store: &mut config::Store) -> Self {
+        let trial = ENABLED && BootRecord::load(store.flash()).is_trial();
+        let mut trial_timeout = st7580::Timeout::default();
+        if trial {
+            trial_timeout.set(TRIAL_TIMEOUT);
+        }
+        Self {
+            transfer: None,
+            trial,
+            trial_timeout,
+            installing: false,
+            reboot_timeout: Default::default(),
+        }
+    }
+
+    /// Handles a message from the leader, returning the answer
+    pub(super) fn received(
+        &mut self,
+        data: &[u8],
+        store: &mut config::Store,
+    ) -> [u8; STATE_LEN] {
+        let status = match data.split_first() {
+            _ if !ENABLED => Status::Failed,
+            Some((&op, payload)) => match Op::try_from(op) {
+                Ok(Op::Begin) => self.begin(payload, store),
+                Ok(Op::Chunk) => self.chunk(payload, store),
+                Ok(Op::End) => self.end(store),
+                Ok(Op::Query) => self.status(),
+                Err(_) => Status::Failed,
+            },
+            None => Status::Failed,
+        };
+        let offset = self.transfer.as_ref().map_or(0, |t| t.offset);
+        let mut buf = [0; STATE_LEN];
+        buf[0] = status.into();
+        buf[1..].copy_from_slice(&offset.to_le_bytes());
+        buf
+    }
+
+    fn status(&self) -> Status {
+        match self.transfer {
+            Some(_) if self.installing => Status::Installing,
+            Some(_) => Status::Receiving,
+            None => Status::Idle,
+        }
+    }
+
+    /// Starts receiving an image, erasing the free slot unless the same
+    /// image was already being received
+    fn begin(&mut self, payload: &[u8], store: &mut config::Store) -> Status {
+        let (Some(&[l0, l1, l2, l3]), Some(hash)) =
+            (payload.get(..4), payload.get(4..4 + HASH_LEN))
+        else {
+            return Status::Failed;
+        };
+        let len = u32::from_le_bytes([l0, l1, l2, l3]);
+        let hash: [u8; HASH_LEN] = hash.try_into().unwrap();
+        if let Some(transfer) = &self.transfer {
+            if transfer.len == len && transfer.hash == hash {
+                return Status::Receiving;
+            }
+        }
+        self.transfer = None;
+        if len == 0 || len as usize > Slot::SIZE {
+            return Status::Failed;
+        }
+        if let Err(_e) = Slot::RUNNING.other().erase(store.flash()) {
+            crate::dbg::println!("slot not erased {:?}", _e);
+            return Status::Failed;
+        }
+        self.transfer = Some(Transfer {
+            len,
+            hash,
+            offset: 0,
+        });
+        Status::Receiving
+    }
+
+    /// Writes the piece at the offset expected, any other piece only gets the
+    /// offset back
+    fn chunk(&mut self, payload: &[u8], store: &mut config::Store) -> Status {
+        let Some(transfer) = &mut self.transfer else { return Status::Idle };
+        let Some(&[o0, o1, o2, o3]) = payload.get(..4) else {
+            return Status::Failed;
+        };
+        let data = &payload[4..];
+        if u32::from_le_bytes([o0, o1, o2, o3]) != transfer.offset {
+            return Status::Receiving;
+        }
+        if transfer.offset as usize + data.len() > transfer.len as usize {
+            return Status::Failed;
+        }
+        let slot = Slot::RUNNING.other();
+        if let Err(_e) =
+            slot.program(store.flash(), transfer.offset as usize, data)
+        {
+            crate::dbg::println!("slot not written {:?}", _e);
+            return Status::Failed;
+        }
+        transfer.offset += data.len() as u32;
+        Status::Receiving
+    }
+
+    /// Checks the received image and has the bootloader try it
+    fn end(&mut self, store: &mut config::Store) -> Status {
+        let Some(transfer) = &self.transfer else { return Status::Idle };
+        if transfer.offset != transfer.len {
+            return Status::Receiving;
+        }
+        let slot = Slot::RUNNING.other();
+        let image = &slot.image(store.flash())[..transfer.len as usize];
+        if util::sha256(image) != transfer.hash {
+            self.transfer = None;
+            return Status::BadHash;
+        }
+        if !slot.is_bootable(store.flash()) {
+            return Status::Failed;
+        }
+        let mut record = BootRecord::load(store.flash());
+        record.install(slot);
+        if let Err(_e) = record.save(store.flash()) {
+            crate::dbg::println!("boot record not saved {:?}", _e);
+            return Status::Failed;
+        }
+        self.installing = true;
+        self.reboot_timeout.set(REBOOT_DELAY);
+        Status::Installing
+    }
+
+    /// Keeps an image on trial for good once the peer was heard
+    pub fn link_up(&mut self, store: &mut config::Store) {
+        if !self.trial {
+            return;
+        }
+        self.trial = false;
+        self.trial_timeout = Default::default();
+        let mut record = BootRecord::load(store.flash());
+        record.confirm();
+        if let Err(_e) = record.save(store.flash()) {
+            crate::dbg::println!("boot record not saved {:?}", _e);
+        }
+    }
+
+    /// Reboots into a new image, or out of one that never heard its peer
+    pub fn poll(&self) {
+        if self.reboot_timeout.is_expired() || self.trial_timeout.is_expired() {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
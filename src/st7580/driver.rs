@@ -5,16 +5,14 @@ use hal::{
 };
 use stm32f4xx_hal as hal;
 
-use crate::mem;
+use crate::{mem, stats::STATS};
 
 use super::{constants::*, frame::*, globals, types::*};
 
 pub struct Driver {
     resetn: PA8<Output<PushPull>>,
 
-    #[allow(unused)]
     tx_on: PC0<Input>,
-    #[allow(unused)]
     rx_on: PC1<Input>,
 
     ind_frame_queue: globals::FrameConsumer<{ globals::QUEUE_SIZE }>,
@@ -54,6 +52,11 @@ impl Driver {
         }
     }
 
+    /// Whether the modem is on the line, sending a frame or receiving one
+    pub fn line_busy(&self) -> bool {
+        self.tx_on.is_high() || self.rx_on.is_high()
+    }
+
     pub fn set_ready_to_receive(&mut self) {
         unsafe {
             globals::READY_TO_RECEIVE = true;
@@ -298,15 +301,18 @@ impl DSender {
                 unsafe { globals::T_REQ_PIN.as_mut() }.unwrap().set_high();
                 self.sf_state = TxStatus::TxreqLow;
                 globals::WAIT_STATUS.clear();
+                STATS.no_status.inc();
                 Err(StErr::TxErrNoStatus.into())
             }
             TxStatus::WaitStatusFrame => {
                 let status = globals::STATUS_VALUE.dequeue();
                 let Some(status) = status else { return Err(WouldBlock) };
+                STATS.set_modem_status(status);
 
                 if status & BUSY_MASK != 0 {
                     unsafe { globals::T_REQ_PIN.as_mut() }.unwrap().set_high();
                     self.sf_state = TxStatus::TxreqLow;
+                    STATS.busy.inc();
                     Err(StErr::TxErrBusy.into())
                 } else {
                     self.sf_state = TxStatus::WaitTxFrameDone;
@@ -329,6 +335,7 @@ impl DSender {
             TxStatus::WaitAck if self.ack_tmo.is_expired() => {
                 self.sf_state = TxStatus::TxreqLow;
                 globals::WAIT_ACK.clear();
+                STATS.ack_timeouts.inc();
                 Err(StErr::TxErrAckTmo.into())
             }
             TxStatus::WaitAck => {
@@ -342,6 +349,7 @@ impl DSender {
                     Err(WouldBlock)
                 } else {
                     self.sf_state = TxStatus::TxreqLow;
+                    STATS.naks.inc();
                     Err(StErr::TxErrNak.into())
                 }
             }
@@ -29,8 +29,20 @@ pub(super) fn set_now_fn(now: fn() -> Instant<u32, 1, 1000000>) {
     debug_assert!(unsafe { NOW.is_none() });
     unsafe { NOW.replace(now) };
 }
-pub(super) fn now() -> u32 {
-    unsafe { NOW.as_mut() }.unwrap()().ticks() / 1000
+/// Milliseconds elapsed since boot, wrapping along with `now_us` after about
+/// 71.6 minutes rather than at `u32::MAX`, so time between two readings is
+/// best measured with `now_us`
+pub fn now() -> u32 {
+    now_us() / 1000
+}
+/// Microseconds elapsed since boot, wrapping at `u32::MAX`
+pub fn now_us() -> u32 {
+    // Host tests run without a timer, standing still at boot
+    #[cfg(test)]
+    if unsafe { NOW.is_none() } {
+        return 0;
+    }
+    unsafe { NOW.as_mut() }.unwrap()().ticks()
 }
 
 pub(super) static STATUS_VALUE: Q2<u8> = Q2::new();
@@ -6,6 +6,7 @@ use hal::{
 use stm32f4xx_hal as hal;
 
 use super::{constants::*, frame::*, globals, types::*};
+use crate::stats::STATS;
 
 pub struct InterruptHandler {
     ic_timeout: Timeout,
@@ -125,18 +126,22 @@ impl InterruptHandler {
 
                 if !valid_cksum {
                     crate::dbg::println!("Invalid cksum {:?}", &self.rx_frame);
+                    STATS.checksum_errors.inc();
                 } else if self.rx_frame.command.is_indication() {
                     if matches!(self.rx_frame.command, CMD_RESET_IND)
                         || unsafe { globals::READY_TO_RECEIVE }
                     {
-                        self.ind_frame_queue
-                            .enqueue(self.rx_frame.clone())
-                            .unwrap();
+                        // Frames nobody has read yet are kept over new ones
+                        if let Err(_f) =
+                            self.ind_frame_queue.enqueue(self.rx_frame.clone())
+                        {
+                            STATS.queue_drops.inc();
+                        }
                     }
-                } else {
-                    self.cnf_frame_queue
-                        .enqueue(self.rx_frame.clone())
-                        .unwrap();
+                } else if let Err(_f) =
+                    self.cnf_frame_queue.enqueue(self.rx_frame.clone())
+                {
+                    STATS.queue_drops.inc();
                 }
 
                 self.ic_timeout.clear();
@@ -16,6 +16,7 @@ use stm32f4xx_hal as hal;
 pub use constants::*;
 pub use driver::*;
 pub use frame::*;
+pub use globals::{now, now_us};
 pub use isr::*;
 pub use types::{NbStErr, NbStResult, StErr, StResult, Timeout};
 
@@ -0,0 +1,246 @@
+//! Counters of how the tunnel is doing
+//!
+//! Every layer, from the modem's interrupt handler up to the USB side, bumps
+//! the counters of the global `STATS` as things happen. Each update is a
+//! single relaxed atomic operation, so the counters stay on in every build,
+//! `HALT` ones included, and may be touched from interrupts as well as tasks.
+//! Counts wrap around at `u32::MAX`. The last status byte of the modem is
+//! kept alongside them.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering::Relaxed};
+
+#[derive(Debug)]
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u32) {
+        self.0.fetch_add(n, Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frames and their bytes going one way over the wire
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub frames: Counter,
+    pub bytes: Counter,
+}
+
+impl Traffic {
+    pub const fn new() -> Self {
+        Self {
+            frames: Counter::new(),
+            bytes: Counter::new(),
+        }
+    }
+
+    pub fn record(&self, len: usize) {
+        self.frames.inc();
+        self.bytes.add(len as u32);
+    }
+}
+
+/// Round-trip times of pings in milliseconds
+#[derive(Debug)]
+pub struct RoundTrip {
+    samples: Counter,
+    total: Counter,
+    min: AtomicU32,
+    max: AtomicU32,
+}
+
+impl Default for RoundTrip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RoundTrip {
+    pub const fn new() -> Self {
+        Self {
+            samples: Counter::new(),
+            total: Counter::new(),
+            min: AtomicU32::new(u32::MAX),
+            max: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, rtt: u32) {
+        self.samples.inc();
+        self.total.add(rtt);
+        self.min.fetch_min(rtt, Relaxed);
+        self.max.fetch_max(rtt, Relaxed);
+    }
+
+    /// Shortest, average and longest round trip, all zero before the first
+    /// one. The average is taken over the samples since the total last
+    /// wrapped.
+    pub fn summary(&self) -> [u32; 3] {
+        let samples = self.samples.get();
+        if samples == 0 {
+            return [0; 3];
+        }
+        [
+            self.min.load(Relaxed),
+            self.total.get() / samples,
+            self.max.load(Relaxed),
+        ]
+    }
+}
+
+/// Received frames dropped, by reason
+#[derive(Debug, Default)]
+pub struct FrameErrors {
+    /// Frames too short to hold a link header
+    pub truncated: Counter,
+    /// Frames claiming more bytes than were received
+    pub bad_length: Counter,
+    /// Frames whose kind byte names no known header
+    pub unknown_kind: Counter,
+    /// Valid headers the node does not expect in its state
+    pub unexpected: Counter,
+}
+
+impl FrameErrors {
+    pub const fn new() -> Self {
+        Self {
+            truncated: Counter::new(),
+            bad_length: Counter::new(),
+            unknown_kind: Counter::new(),
+            unexpected: Counter::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Frames handed to the modem for the wire
+    pub tx: Traffic,
+    /// Frames the modem received from the wire
+    pub rx: Traffic,
+    /// Frames a contention peer sent again after a modem error or a missing
+    /// ack, and polls repeated after a missing answer
+    pub retries: Counter,
+    /// Requests the modem refused with a NAK
+    pub naks: Counter,
+    /// Requests the modem turned down as busy
+    pub busy: Counter,
+    /// Requests the modem never acknowledged
+    pub ack_timeouts: Counter,
+    /// Requests the modem never sent a status for
+    pub no_status: Counter,
+    /// Frames the modem did not take or failed to send for any other
+    /// reason, which are dropped, reported with the frame errors rather than
+    /// the other counters
+    pub tx_errors: Counter,
+    /// Received frames dropped before any state machine acted on them
+    pub frame_errors: FrameErrors,
+    /// Frames from the modem with a bad UART checksum
+    pub checksum_errors: Counter,
+    /// Frames and messages dropped because their queue was full
+    pub queue_drops: Counter,
+    /// Buffers asked of an empty pool
+    pub pool_exhausted: Counter,
+    /// Pings answered by the peer
+    pub ping_rtt: RoundTrip,
+    modem_status: AtomicU8,
+}
+
+pub static STATS: Stats = Stats::new();
+
+impl Stats {
+    pub const ENCODED_LEN: usize = 15 * 4;
+    pub const FRAMES_LEN: usize = 5 * 4;
+
+    pub const fn new() -> Self {
+        Self {
+            tx: Traffic::new(),
+            rx: Traffic::new(),
+            retries: Counter::new(),
+            naks: Counter::new(),
+            busy: Counter::new(),
+            ack_timeouts: Counter::new(),
+            no_status: Counter::new(),
+            tx_errors: Counter::new(),
+            frame_errors: FrameErrors::new(),
+            checksum_errors: Counter::new(),
+            queue_drops: Counter::new(),
+            pool_exhausted: Counter::new(),
+            ping_rtt: RoundTrip::new(),
+            modem_status: AtomicU8::new(0),
+        }
+    }
+
+    /// Keeps the status byte the modem sent ahead of a request
+    pub fn set_modem_status(&self, status: u8) {
+        self.modem_status.store(status, Relaxed);
+    }
+
+    pub fn modem_status(&self) -> u8 {
+        self.modem_status.load(Relaxed)
+    }
+
+    /// Encodes the counters as 32 bit values in the order `[tx frames,
+    /// tx bytes, rx frames, rx bytes, retries, naks, busy, ack timeouts,
+    /// no status, checksum errors, queue drops, pool exhausted, rtt min,
+    /// rtt avg, rtt max]`
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let [rtt_min, rtt_avg, rtt_max] = self.ping_rtt.summary();
+        let values = [
+            self.tx.frames.get(),
+            self.tx.bytes.get(),
+            self.rx.frames.get(),
+            self.rx.bytes.get(),
+            self.retries.get(),
+            self.naks.get(),
+            self.busy.get(),
+            self.ack_timeouts.get(),
+            self.no_status.get(),
+            self.checksum_errors.get(),
+            self.queue_drops.get(),
+            self.pool_exhausted.get(),
+            rtt_min,
+            rtt_avg,
+            rtt_max,
+        ];
+        let mut buf = [0; Self::ENCODED_LEN];
+        for (chunk, value) in buf.chunks_exact_mut(4).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        buf
+    }
+    /// Encodes the frame errors as 32 bit values in the order `[truncated,
+    /// bad length, unknown kind, unexpected, tx errors]`
+    pub fn encode_frames(&self) -> [u8; Self::FRAMES_LEN] {
+        let errors = &self.frame_errors;
+        let values = [
+            errors.truncated.get(),
+            errors.bad_length.get(),
+            errors.unknown_kind.get(),
+            errors.unexpected.get(),
+            self.tx_errors.get(),
+        ];
+        let mut buf = [0; Self::FRAMES_LEN];
+        for (chunk, value) in buf.chunks_exact_mut(4).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        buf
+    }
+}
@@ -0,0 +1,46 @@
+//! Vendor specific interface used for managing the tunnel from the host
+//!
+//! The interface is a plain bulk IN/OUT pair. Every packet is a single
+//! message whose meaning is defined by `plc::host`.
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+/// Vendor specific interface class
+const USB_CLASS_VENDOR: u8 = 0xFF;
+
+pub struct ControlClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+}
+
+impl<'a, B: UsbBus> ControlClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>, max_packet_size: u16) -> Self {
+        Self {
+            iface: alloc.interface(),
+            read_ep: alloc.bulk(max_packet_size),
+            write_ep: alloc.bulk(max_packet_size),
+        }
+    }
+
+    pub fn read_packet(&self, data: &mut [u8]) -> Result<usize> {
+        self.read_ep.read(data)
+    }
+
+    pub fn write_packet(&self, data: &[u8]) -> Result<usize> {
+        self.write_ep.write(data)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for ControlClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> Result<()> {
+        writer.interface(self.iface, USB_CLASS_VENDOR, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use crate::{mem, st7580, stats::STATS, util::Exchange};
+use core::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use heapless::spsc::{Consumer, Producer, Queue};
+use stm32f4xx_hal::otg_fs::UsbBusType;
+use usb_device::{bus::UsbBusAllocator, class::UsbClass};
+use usbd_serial::{CdcAcmClass, Result, UsbError};
+
+pub mod control;
+
+pub use control::ControlClass;
+
+/// Logical channels carried by the tunnel. Channel 0 is the CDC-ACM serial
+/// port, the others ride the control interface as `CHANNEL_DATA` packets.
+/// Each CDC-ACM interface takes two IN endpoints, so a port per channel would
+/// need eight along with EP0 and the control interface, while the OTG_FS
+/// peripheral has six on the F446 and four on the F411.
+pub const CHANNELS: usize = 3;
+/// Kind byte of a control interface packet carrying channel data, followed
+/// by the channel and the data itself in both directions
+pub const CHANNEL_DATA: u8 = 0x40;
+/// Most data a `CHANNEL_DATA` packet holds
+pub const CHANNEL_PAYLOAD_SIZE: usize = CONTROL_PACKET_SIZE - 2;
+
+pub const QUEUE_SIZE: usize = 32;
+pub type Elem = mem::BufBox;
+pub type UsbQueue = Queue<Elem, QUEUE_SIZE>;
+pub type UsbProducer = Producer<'static, Elem, QUEUE_SIZE>;
+pub type UsbConsumer = Consumer<'static, Elem, QUEUE_SIZE>;
+// Only ever copied into the arrays below
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_QUEUE: UsbQueue = Queue::new();
+static mut IN_QUEUES: [UsbQueue; CHANNELS] = [EMPTY_QUEUE; CHANNELS];
+static mut OUT_QUEUES: [UsbQueue; CHANNELS] = [EMPTY_QUEUE; CHANNELS];
+
+pub const CONTROL_QUEUE_SIZE: usize = 8;
+/// How long channel data with no room in its queue holds up the control
+/// interface before being dropped, in milliseconds, so that commands still
+/// get through to a tunnel that stopped moving data
+const HOLD_TIMEOUT: u32 = 1000;
+pub const CONTROL_PACKET_SIZE: usize = 64;
+pub type ControlQueue = Queue<Elem, CONTROL_QUEUE_SIZE>;
+pub type ControlProducer = Producer<'static, Elem, CONTROL_QUEUE_SIZE>;
+pub type ControlConsumer = Consumer<'static, Elem, CONTROL_QUEUE_SIZE>;
+static mut CTRL_IN_QUEUE: ControlQueue = Queue::new();
+static mut CTRL_OUT_QUEUE: ControlQueue = Queue::new();
+
+/// Whether the queues changed on the tunnel side since the USB side last
+/// looked. The USB side only runs on bus events, and none come while an
+/// endpoint NAKs the host or while the host waits for data, so it has to be
+/// woken for both.
+static WAKE: AtomicBool = AtomicBool::new(false);
+
+/// Marks the queues as changed, for after taking from or adding to them
+pub fn wake() {
+    WAKE.store(true, Relaxed);
+}
+
+/// Whether the queues changed since the last call
+pub fn take_wake() -> bool {
+    WAKE.swap(false, Relaxed)
+}
+
+pub struct UsbManager {
+    serial: CdcAcmClass<'static, UsbBusType>,
+    control: ControlClass<'static, UsbBusType>,
+    /// One queue per channel so that a stuck channel holds up no other
+    in_consumers: [UsbConsumer; CHANNELS],
+    out_producers: [UsbProducer; CHANNELS],
+    ctrl_in_consumer: ControlConsumer,
+    ctrl_out_producer: ControlProducer,
+    current_read: mem::BufBox,
+    /// Channel data read from the control interface that its queue had no
+    /// room for, holding the interface until it does
+    held: Option<(usize, mem::BufBox)>,
+    hold_timeout: st7580::Timeout,
+    /// Channel the control interface writes from next, taken in turns
+    next_channel: usize,
+    /// Whether data from the host is passed on, a one-way follower has
+    /// nowhere to send it
+    host_data: bool,
+}
+
+impl UsbManager {
+    pub fn classes(&mut self) -> [&mut dyn UsbClass<UsbBusType>; 2] {
+        [&mut self.serial, &mut self.control]
+    }
+
+    pub fn poll(&mut self) -> Result<()> {
+        self.poll_control()?;
+
+        // Packets left unread in the endpoint make it NAK the host, which
+        // holds on to its data until there is room for it here
+        if self.out_producers[0].ready() {
+            self.poll_read()?;
+        }
+
+        // Peek next write or return
+        let in_consumer = &mut self.in_consumers[0];
+        let Some(current_write) = in_consumer.peek() else { return Ok(()) };
+
+        // Write the data to host
+        match self.serial.write_packet(current_write) {
+            // Currently relying on everything being sent
+            Ok(len) => {
+                debug_assert_eq!(len, current_write.len());
+                in_consumer.dequeue();
+            }
+            // Try again once the host has read what is in the buffers
+            Err(UsbError::WouldBlock) => {}
+            // Return all other errors
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
+    fn poll_read(&mut self) -> Result<()> {
+        // Have the next buffer ready before taking a packet from the host
+        let Some(next_read) = mem::alloc() else { return Ok(()) };
+
+        // Reserve space for reading from host
+        let capacity = self.current_read.capacity();
+        if self.current_read.len() < capacity {
+            self.current_read.resize(capacity, 0).unwrap();
+        }
+        // Attempt read from host
+        match self.serial.read_packet(&mut self.current_read) {
+            // Assuming won't occurs since it would result in WouldBlock instead
+            Ok(0) => unreachable!(),
+            // Hand off the data to the queue
+            Ok(len) if self.host_data => {
+                let mut sending = self.current_read.exchange(next_read);
+                sending.truncate(len);
+
+                if let Err(_e) = self.out_producers[0].enqueue(sending) {
+                    crate::dbg::println!("The out going message queue is full");
+                    STATS.queue_drops.inc();
+                };
+            }
+            Ok(_) => {}
+            // No new data so continue
+            Err(UsbError::WouldBlock) => {}
+            // Return all other errors
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
+    fn poll_control(&mut self) -> Result<()> {
+        self.poll_held();
+
+        // Attempt read of a command from host, unless the interface is held
+        // back to NAK the host like the serial port is
+        let mut packet = [0; CONTROL_PACKET_SIZE];
+        let read = match self.held {
+            Some(_) => Err(UsbError::WouldBlock),
+            None => self.control.read_packet(&mut packet),
+        };
+        match read {
+            Ok(0) => {}
+            Ok(len) if packet[0] == CHANNEL_DATA => {
+                self.route_channel_data(&packet[1..len]);
+            }
+            Ok(len) => {
+                let Some(command) = mem::alloc_from_slice(&packet[..len])
+                else {
+                    crate::dbg::println!("no buffer for control command");
+                    return Ok(());
+                };
+                if let Err(_e) = self.ctrl_out_producer.enqueue(command) {
+                    crate::dbg::println!("The control command queue is full");
+                    STATS.queue_drops.inc();
+                }
+            }
+            Err(UsbError::WouldBlock) => {}
+            Err(e) => return Err(e),
+        }
+
+        // Messages stay queued until the host has room for them
+        if let Some(current_write) = self.ctrl_in_consumer.peek() {
+            match self.control.write_packet(current_write) {
+                Ok(len) => {
+                    debug_assert_eq!(len, current_write.len());
+                    self.ctrl_in_consumer.dequeue();
+                }
+                Err(UsbError::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+            return Ok(());
+        }
+
+        self.write_channel_data()
+    }
+
+    /// Queues channel data held back for room in its queue, or drops it once
+    /// it held up the control interface for too long
+    fn poll_held(&mut self) {
+        let Some((channel, _)) = self.held else { return };
+        if self.out_producers[channel].ready() {
+            let (_, buf) = self.held.take().unwrap();
+            self.out_producers[channel].enqueue(buf).ok().unwrap();
+        } else if self.hold_timeout.is_expired() {
+            crate::dbg::println!("channel {} queue is full, dropped", channel);
+            STATS.queue_drops.inc();
+            self.held = None;
+        }
+    }
+
+    /// Queues data the host sent on one of the channels of the control
+    /// interface. The endpoint is shared by every channel, so data for a full
+    /// channel holds all of them back until there is room, or `HOLD_TIMEOUT`.
+    fn route_channel_data(&mut self, packet: &[u8]) {
+        let Some((&channel, data)) = packet.split_first() else { return };
+        let channel = channel as usize;
+        if channel == 0 || channel >= CHANNELS {
+            crate::dbg::println!(
+                "no channel {} on the control interface",
+                channel
+            );
+            return;
+        }
+        if !self.host_data {
+            return;
+        }
+        let Some(buf) = mem::alloc_from_slice(data) else {
+            crate::dbg::println!("no buffer for channel {}, dropped", channel);
+            return;
+        };
+        if let Err(buf) = self.out_producers[channel].enqueue(buf) {
+            self.held = Some((channel, buf));
+            self.hold_timeout.set(HOLD_TIMEOUT);
+        }
+    }
+
+    /// Writes the next data waiting on a channel of the control interface,
+    /// taking the channels in turns
+    fn write_channel_data(&mut self) -> Result<()> {
+        let Some(channel) = (0..CHANNELS - 1)
+            .map(|n| (self.next_channel - 1 + n) % (CHANNELS - 1) + 1)
+            .find(|&channel| self.in_consumers[channel].ready())
+        else {
+            return Ok(());
+        };
+        let in_consumer = &mut self.in_consumers[channel];
+        let current_write = in_consumer.peek().unwrap();
+        if current_write.len() > CHANNEL_PAYLOAD_SIZE {
+            crate::dbg::println!("channel {} data too long, dropped", channel);
+            in_consumer.dequeue();
+            return Ok(());
+        }
+
+        let mut packet = [0; CONTROL_PACKET_SIZE];
+        packet[0] = CHANNEL_DATA;
+        packet[1] = channel as u8;
+        packet[2..2 + current_write.len()].copy_from_slice(current_write);
+        match self
+            .control
+            .write_packet(&packet[..2 + current_write.len()])
+        {
+            Ok(_) => {
+                in_consumer.dequeue();
+                self.next_channel = channel % (CHANNELS - 1) + 1;
+            }
+            Err(UsbError::WouldBlock) => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+}
+
+pub struct UsbSplit {
+    pub usb_manager: UsbManager,
+    pub in_producers: [UsbProducer; CHANNELS],
+    pub out_consumers: [UsbConsumer; CHANNELS],
+    pub ctrl_in_producer: ControlProducer,
+    pub ctrl_out_consumer: ControlConsumer,
+}
+
+pub fn split(
+    alloc: &'static UsbBusAllocator<UsbBusType>,
+    host_data: bool,
+) -> UsbSplit {
+    cortex_m::singleton!(:bool = false).expect("May only call split once");
+
+    let serial = CdcAcmClass::new(alloc, 64);
+    let control = ControlClass::new(alloc, CONTROL_PACKET_SIZE as u16);
+
+    let (in_producers, in_consumers) = split_queues(unsafe { &mut IN_QUEUES });
+    let (out_producers, out_consumers) =
+        split_queues(unsafe { &mut OUT_QUEUES });
+    let (ctrl_in_producer, ctrl_in_consumer) = unsafe { CTRL_IN_QUEUE.split() };
+    let (ctrl_out_producer, ctrl_out_consumer) =
+        unsafe { CTRL_OUT_QUEUE.split() };
+
+    let usb_manager = UsbManager {
+        serial,
+        control,
+        in_consumers,
+        out_producers,
+        ctrl_in_consumer,
+        ctrl_out_producer,
+        current_read: mem::alloc().unwrap(),
+        held: None,
+        hold_timeout: Default::default(),
+        next_channel: 1,
+        host_data,
+    };
+
+    UsbSplit {
+        usb_manager,
+        in_producers,
+        out_consumers,
+        ctrl_in_producer,
+        ctrl_out_consumer,
+    }
+}
+
+fn split_queues(
+    queues: &'static mut [UsbQueue; CHANNELS],
+) -> ([UsbProducer; CHANNELS], [UsbConsumer; CHANNELS]) {
+    let mut producers = heapless::Vec::<_, CHANNELS>::new();
+    let mut consumers = heapless::Vec::<_, CHANNELS>::new();
+    for queue in queues.iter_mut() {
+        let (producer, consumer) = queue.split();
+        producers.push(producer).ok().unwrap();
+        consumers.push(consumer).ok().unwrap();
+    }
+    (
+        producers.into_array().ok().unwrap(),
+        consumers.into_array().ok().unwrap(),
+    )
+}
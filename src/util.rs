@@ -38,6 +38,144 @@ pub const fn zeros<T: Zero + Copy, const N: usize>() -> [T; N] {
     [T::ZERO; N]
 }
 
+/// Parses a decimal string at compile time, used for package metadata
+pub const fn parse_u8(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut val = 0u8;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        val = val * 10 + (bytes[idx] - b'0');
+        idx += 1;
+    }
+    val
+}
+
+/// Base address of the 96-bit unique device ID on the STM32F4 family
+const UID_BASE: usize = 0x1FFF_7A10;
+
+/// Reads the factory programmed unique device ID of this chip
+pub fn unique_id() -> [u32; 3] {
+    let uid = UID_BASE as *const u32;
+    // SAFETY: the UID registers are always mapped and read only
+    unsafe { [0, 1, 2].map(|i| core::ptr::read_volatile(uid.add(i))) }
+}
+
+/// Lookup table for `crc32`, one entry per byte value
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+/// CRC-32 as used by Ethernet and zlib
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &val| {
+        CRC32_TABLE[((crc ^ val as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Round constants of SHA-256
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256_block(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7)
+            ^ w[i - 15].rotate_right(18)
+            ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17)
+            ^ w[i - 2].rotate_right(19)
+            ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (k, w) in SHA256_K.iter().zip(w) {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(*k)
+            .wrapping_add(w);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+/// SHA-256 digest of `data`, for checking firmware images
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+        0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+        sha256_block(&mut state, block);
+    }
+    // The rest, the end marker and the bit length fill one or two blocks
+    let rest = blocks.remainder();
+    let mut tail = [0; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    let bits = (data.len() as u64) * 8;
+    tail[tail_len - 8..tail_len].copy_from_slice(&bits.to_be_bytes());
+    for block in tail[..tail_len].chunks_exact(64) {
+        sha256_block(&mut state, block);
+    }
+    let mut digest = [0; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
 pub struct NullQueueConsumer<'a, T, const N: usize> {
     consumer: Consumer<'a, T, N>,
 }
@@ -51,3 +189,105 @@ impl<'a, T, const N: usize> NullQueueConsumer<'a, T, N> {
         self.consumer.dequeue();
     }
 }
+
+/// Small xorshift generator for spreading out retries, never for secrets
+#[derive(Debug, Clone)]
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift never leaves zero
+        Self(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Picks a value below `n`, which must not be zero
+    pub fn below(&mut self, n: u32) -> u32 {
+        self.next_u32() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(hex: &str) -> [u8; 32] {
+        core::array::from_fn(|i| {
+            u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap()
+        })
+    }
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                b"",
+                concat!(
+                    "e3b0c44298fc1c149afbf4c8996fb924",
+                    "27ae41e4649b934ca495991b7852b855"
+                ),
+            ),
+            (
+                b"abc",
+                concat!(
+                    "ba7816bf8f01cfea414140de5dae2223",
+                    "b00361a396177a9cb410ff61f20015ad"
+                ),
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                concat!(
+                    "248d6a61d20638b8e5c026930c3e6039",
+                    "a33ce45964ff2167f6ecedd419db06c1"
+                ),
+            ),
+        ];
+        for (data, hex) in cases {
+            assert_eq!(sha256(data), digest(hex));
+        }
+    }
+
+    #[test]
+    fn sha256_pads_around_block_ends() {
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let cases = [
+            (
+                55,
+                concat!(
+                    "463eb28e72f82e0a96c0a4cc53690c57",
+                    "1281131f672aa229e0d45ae59b598b59"
+                ),
+            ),
+            (
+                56,
+                concat!(
+                    "da2ae4d6b36748f2a318f23e7ab1dfdf",
+                    "45acdc9d049bd80e59de82a60895f562"
+                ),
+            ),
+            (
+                64,
+                concat!(
+                    "fdeab9acf3710362bd2658cdc9a29e8f",
+                    "9c757fcf9811603a8c447cd1d9151108"
+                ),
+            ),
+        ];
+        for (len, hex) in cases {
+            assert_eq!(sha256(&data[..len]), digest(hex));
+        }
+    }
+
+    #[test]
+    fn crc32_matches_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}